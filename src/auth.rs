@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, SaltString};
+use argon2::Argon2;
+use scrypt::Scrypt;
+use sha2::{Digest, Sha256};
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+
+use log::*;
+
+use subtle::ConstantTimeEq;
+
+use crate::metrics::METRICS;
+use crate::Config;
+
+/// Failures allowed before an address has to start waiting between attempts.
+const FREE_ATTEMPTS: u32 = 3;
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// An address that stays quiet this long starts from a clean slate.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// The admin password, or its hash, checked to be usable.
+#[derive(Debug, Clone)]
+pub enum Secret {
+    Plain(String),
+    // A PHC string, kept as a string because `PasswordHash` borrows from it
+    Hash(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Incorrect,
+    LockedOut(Duration),
+}
+
+impl AuthError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::Incorrect => StatusCode::UNAUTHORIZED,
+            AuthError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            AuthError::Incorrect => "Password incorrect.".to_string(),
+            AuthError::LockedOut(remaining) => format!(
+                "Too many failed attempts. Try again in {} seconds.",
+                remaining.as_secs() + 1
+            ),
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        (self.status(), self.message()).into_response()
+    }
+}
+
+/// Checks the admin password and throttles addresses that keep getting it wrong.
+#[derive(Debug)]
+pub struct Auth {
+    secret: RwLock<Secret>,
+    attempts: Mutex<HashMap<IpAddr, Attempts>>,
+}
+
+impl Secret {
+    pub fn new(config: &Config) -> Result<Self> {
+        match &config.password_hash {
+            Some(hash) => {
+                PasswordHash::new(hash).map_err(|e| anyhow!("Invalid password_hash: {}", e))?;
+                Ok(Secret::Hash(hash.clone()))
+            }
+            None if config.password.is_empty() => {
+                bail!("Either password or password_hash must be set")
+            }
+            None => {
+                warn!("The admin password is stored in plaintext. Consider replacing it with a password_hash, see --hash-password.");
+                Ok(Secret::Plain(config.password.clone()))
+            }
+        }
+    }
+}
+
+impl Auth {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            secret: RwLock::new(Secret::new(config)?),
+            attempts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Switch to the password of a reloaded configuration. Lockouts carry over.
+    pub fn reload(&self, secret: Secret) {
+        *self.secret.write().unwrap() = secret;
+    }
+
+    /// Fails if `ip` must wait before trying again. Otherwise the attempt counts as failed
+    /// until [`Auth::record_success`] says otherwise, so concurrent attempts can't all get past
+    /// the lockout before the first of them fails.
+    pub fn begin_attempt(&self, ip: IpAddr) -> Result<(), AuthError> {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, a| a.last_failure.elapsed() < FORGET_AFTER);
+        let now = Instant::now();
+        let entry = attempts.entry(ip).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if let Some(until) = entry.locked_until.filter(|&until| until > now) {
+            return Err(AuthError::LockedOut(until - now));
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+        if entry.failures > FREE_ATTEMPTS {
+            let exponent = (entry.failures - FREE_ATTEMPTS - 1).min(16);
+            let lockout = Duration::from_secs(1 << exponent).min(MAX_LOCKOUT);
+            entry.locked_until = Some(now + lockout);
+        }
+        Ok(())
+    }
+
+    /// Report that the attempt `ip` began failed. It was already counted.
+    pub fn record_failure(&self, ip: IpAddr, endpoint: &str) {
+        let failures = self
+            .attempts
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .map_or(1, |a| a.failures);
+        METRICS.auth_failures.fetch_add(1, Ordering::Relaxed);
+
+        // Keep this line stable, fail2ban filters match on it
+        warn!(
+            "Authentication failure from {} on {} (attempt {})",
+            ip, endpoint, failures
+        );
+    }
+
+    /// Report that the attempt `ip` began succeeded, which clears its history.
+    pub fn record_success(&self, ip: IpAddr) {
+        self.attempts.lock().unwrap().remove(&ip);
+    }
+
+    async fn verify_password(&self, password: &str) -> bool {
+        let secret = self.secret.read().unwrap().clone();
+        match secret {
+            Secret::Plain(expected) => {
+                // Digests have a fixed length, so comparing them doesn't give away the password's
+                Sha256::digest(password.as_bytes())
+                    .ct_eq(&Sha256::digest(expected.as_bytes()))
+                    .into()
+            }
+            Secret::Hash(hash) => {
+                let password = password.to_owned();
+                // Hash verification is deliberately slow, keep it off the runtime threads
+                tokio::task::spawn_blocking(move || {
+                    let Ok(hash) = PasswordHash::new(&hash) else {
+                        return false;
+                    };
+                    hash.verify_password(&[&Argon2::default(), &Scrypt], password)
+                        .is_ok()
+                })
+                .await
+                .unwrap_or(false)
+            }
+        }
+    }
+
+    /// Check `password` on behalf of `ip`, updating its attempt history.
+    pub async fn authenticate(
+        &self,
+        ip: IpAddr,
+        password: &str,
+        endpoint: &str,
+    ) -> Result<(), AuthError> {
+        self.begin_attempt(ip)?;
+        if self.verify_password(password).await {
+            self.record_success(ip);
+            Ok(())
+        } else {
+            self.record_failure(ip, endpoint);
+            Err(AuthError::Incorrect)
+        }
+    }
+}
+
+/// Produce an argon2id PHC string suitable for `password_hash`.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))
+}
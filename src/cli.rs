@@ -0,0 +1,310 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use clap::{Args, Parser, Subcommand};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+use serde_json::json;
+
+use crate::keys::{self, Permissions};
+use crate::sessions::SessionInfo;
+use crate::tls;
+use crate::Config;
+
+/// A remote desktop server for Telewindow clients.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+    /// The config file to use, instead of the one in the platform's config directory
+    #[arg(long, global = true, env = "TENEBRA_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: Overrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the server. This is the default when no command is given
+    #[cfg_attr(target_os = "windows", command(long_flag = "console"))]
+    Serve,
+    /// Write the default config file, to be filled in before the server is run
+    InitConfig {
+        /// Replace the config file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Create a single-use key on the running server and print it
+    CreateKey {
+        /// Only let the session started with the key watch and listen
+        #[arg(long)]
+        view_only: bool,
+    },
+    /// List the sessions of the running server
+    ListSessions,
+    /// Generate a self-signed certificate where the server looks for one, e.g. to rotate it
+    GenCert {
+        /// A host name or IP address the certificate is valid for. May be given more than once,
+        /// localhost and the addresses of this machine are used if it is not given
+        #[arg(long = "name", value_name = "NAME")]
+        names: Vec<String>,
+        /// Replace the certificate and key if they already exist
+        #[arg(long)]
+        force: bool,
+    },
+    /// Read a password from stdin and print a `password_hash` for it
+    #[command(long_flag = "hash-password")]
+    HashPassword,
+    /// Check the config file and print everything that is wrong with it
+    #[command(long_flag = "check-config")]
+    CheckConfig,
+}
+
+/// Settings from the command line or the environment, which take precedence over the config
+/// file. They stay in effect when the file is reloaded.
+#[derive(Args, Debug, Clone, Default)]
+pub struct Overrides {
+    /// The port to listen on
+    #[arg(long, global = true, env = "TENEBRA_PORT")]
+    pub port: Option<u16>,
+    /// The address to listen on, e.g. 127.0.0.1 to only accept local connections
+    #[arg(long, global = true, env = "TENEBRA_BIND", value_name = "ADDRESS")]
+    pub bind: Option<IpAddr>,
+    /// The TLS certificate file
+    #[arg(long, global = true, env = "TENEBRA_CERT", value_name = "PATH")]
+    pub cert: Option<PathBuf>,
+    /// The TLS private key file
+    #[arg(long, global = true, env = "TENEBRA_KEY", value_name = "PATH")]
+    pub key: Option<PathBuf>,
+    /// The admin password. Replaces both `password` and `password_hash`, and signs in the
+    /// commands that talk to a running server
+    #[arg(
+        long,
+        global = true,
+        env = "TENEBRA_PASSWORD",
+        hide_env_values = true,
+        value_name = "PASSWORD"
+    )]
+    pub password: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(bind) = self.bind {
+            config.bind = bind;
+        }
+        if let Some(cert) = &self.cert {
+            config.cert = Some(cert.clone());
+        }
+        if let Some(key) = &self.key {
+            config.key = Some(key.clone());
+        }
+        if let Some(password) = &self.password {
+            config.password = password.clone();
+            config.password_hash = None;
+        }
+    }
+}
+
+/// Read one line from stdin, without its line ending.
+pub fn read_password(prompt: &str) -> Result<String> {
+    eprintln!("{}", prompt);
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .context("Failed to read password")?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        bail!("The password must not be empty");
+    }
+    Ok(password.to_string())
+}
+
+/// Create a key on the server that runs with `config` and the certificate at `cert`, and print
+/// it.
+pub async fn create_key(
+    config: &Config,
+    cert: &Path,
+    password: &str,
+    view_only: bool,
+) -> Result<()> {
+    let body = json!({ "password": password, "view_only": view_only });
+    let key = request(config, cert, "/create_key", body)
+        .await?
+        .text()
+        .await?;
+    println!("{}", key);
+    Ok(())
+}
+
+/// Print the sessions of the server that runs with `config` and the certificate at `cert`, one
+/// per line.
+pub async fn list_sessions(config: &Config, cert: &Path, password: &str) -> Result<()> {
+    let body = json!({ "password": password });
+    let sessions: Vec<SessionInfo> = request(config, cert, "/sessions/list", body)
+        .await?
+        .json()
+        .await?;
+    if sessions.is_empty() {
+        println!("No sessions.");
+        return Ok(());
+    }
+    println!(
+        "{:<16}  {:<40}  {:<6}  {:>12}  {:>10}  PERMISSIONS",
+        "ID", "PEER", "CODEC", "BITRATE", "DURATION"
+    );
+    for session in sessions {
+        let bitrate = match session.bitrate {
+            Some(bitrate) => format!("{} Kbit/s", bitrate),
+            None => "-".to_string(),
+        };
+        let duration = Duration::from_secs(keys::unix_now().saturating_sub(session.started));
+        println!(
+            "{:<16}  {:<40}  {:<6}  {:>12}  {:>10}  {}",
+            session.id,
+            session.peer,
+            session.codec,
+            bitrate,
+            format_duration(duration),
+            format_permissions(session.permissions)
+        );
+    }
+    Ok(())
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn format_permissions(permissions: Permissions) -> String {
+    match permissions {
+        Permissions::FULL_CONTROL => "full_control".to_string(),
+        Permissions::VIEW_ONLY => "view_only".to_string(),
+        permissions => permissions
+            .iter()
+            .map(|capability| capability.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+/// Post `body` to an admin endpoint of the server that runs with `config`, on this machine.
+/// Only a server presenting the certificate at `cert` gets to see the password.
+async fn request(
+    config: &Config,
+    cert: &Path,
+    path: &str,
+    body: serde_json::Value,
+) -> Result<reqwest::Response> {
+    let ip = match config.bind {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let url = format!("https://{}{}", SocketAddr::new(ip, config.port), path);
+
+    // The server runs on this machine, usually with a self-signed certificate that no CA
+    // vouches for, so it is trusted by its fingerprint instead
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let verifier = PinnedCert {
+        fingerprint: tls::fingerprint(cert)?,
+        cert: cert.to_path_buf(),
+        provider: provider.clone(),
+    };
+    let tls_config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    let client = reqwest::Client::builder()
+        .use_preconfigured_tls(tls_config)
+        .build()?;
+    let response = client
+        .post(&url)
+        .json(&body)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach the server at {}", url))?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("The server answered {}: {}", status, response.text().await?);
+    }
+    Ok(response)
+}
+
+/// Accepts exactly one certificate, whatever names it is for or whoever signed it.
+#[derive(Debug)]
+struct PinnedCert {
+    fingerprint: String,
+    // Where the fingerprint came from, for the error message
+    cert: PathBuf,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = tls::fingerprint_der(end_entity);
+        if fingerprint != self.fingerprint {
+            return Err(rustls::Error::General(format!(
+                "the server presented a certificate with fingerprint {}, not the one in {}",
+                fingerprint,
+                self.cert.display()
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,550 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+
+use base64::prelude::*;
+
+use log::*;
+
+use tokio::sync::{broadcast, mpsc};
+
+use crate::protocol::ClipboardItem;
+
+/// The MIME types that are synchronized. Everything else on the host clipboard is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mime {
+    Text,
+    Html,
+    Png,
+}
+
+impl Mime {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mime::Text => "text/plain",
+            Mime::Html => "text/html",
+            Mime::Png => "image/png",
+        }
+    }
+
+    pub fn parse(mime: &str) -> Option<Self> {
+        // Browsers like to append parameters, e.g. "text/plain;charset=utf-8"
+        match mime.split(';').next().unwrap_or_default().trim() {
+            "text/plain" => Some(Mime::Text),
+            "text/html" => Some(Mime::Html),
+            "image/png" => Some(Mime::Png),
+            _ => None,
+        }
+    }
+
+    fn is_binary(&self) -> bool {
+        matches!(self, Mime::Png)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipboardEntry {
+    pub mime: Mime,
+    pub data: Vec<u8>,
+}
+
+impl ClipboardEntry {
+    /// Decode an item received from a client. Binary data travels as base64, text as-is.
+    pub fn from_item(item: &ClipboardItem) -> Result<Self> {
+        let Some(mime) = Mime::parse(&item.mime) else {
+            bail!("unsupported clipboard type `{}`", item.mime);
+        };
+        let data = if mime.is_binary() {
+            BASE64_STANDARD.decode(&item.data)?
+        } else {
+            item.data.clone().into_bytes()
+        };
+        Ok(Self { mime, data })
+    }
+
+    pub fn to_item(&self) -> ClipboardItem {
+        ClipboardItem {
+            mime: self.mime.as_str().to_string(),
+            data: if self.mime.is_binary() {
+                BASE64_STANDARD.encode(&self.data)
+            } else {
+                String::from_utf8_lossy(&self.data).into_owned()
+            },
+        }
+    }
+}
+
+/// Every representation of one clipboard selection, e.g. the same text as plain text and HTML.
+pub type ClipboardContents = Arc<Vec<ClipboardEntry>>;
+
+/// The session-facing half of the clipboard thread.
+#[derive(Debug, Clone)]
+pub struct Clipboard {
+    set_tx: mpsc::Sender<Vec<ClipboardEntry>>,
+    changes: broadcast::Sender<ClipboardContents>,
+}
+
+impl Clipboard {
+    pub fn new() -> (Self, mpsc::Receiver<Vec<ClipboardEntry>>) {
+        let (set_tx, set_rx) = mpsc::channel(8);
+        let (changes, _) = broadcast::channel(8);
+        (Self { set_tx, changes }, set_rx)
+    }
+
+    /// Replace the host clipboard with contents received from a client.
+    pub async fn set(&self, entries: Vec<ClipboardEntry>) {
+        if self.set_tx.send(entries).await.is_err() {
+            warn!("Dropped clipboard update: clipboard synchronization is not running.");
+        }
+    }
+
+    /// Receive every change made to the host clipboard from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClipboardContents> {
+        self.changes.subscribe()
+    }
+
+    pub fn changes(&self) -> broadcast::Sender<ClipboardContents> {
+        self.changes.clone()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use x11::do_clipboard;
+
+#[cfg(not(target_os = "linux"))]
+pub fn do_clipboard(
+    mut rx: mpsc::Receiver<Vec<ClipboardEntry>>,
+    _changes: broadcast::Sender<ClipboardContents>,
+    _max_size: usize,
+) {
+    warn!("Clipboard synchronization is not supported on this platform.");
+    while rx.blocking_recv().is_some() {}
+}
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use anyhow::{Context, Result};
+
+    use log::*;
+
+    use tokio::sync::{broadcast, mpsc};
+
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xfixes::{self, ConnectionExt as _};
+    use x11rb::protocol::xproto::{
+        Atom, AtomEnum, ClientMessageEvent, ConnectionExt as _, CreateWindowAux, EventMask,
+        PropMode, Property, SelectionNotifyEvent, SelectionRequestEvent, Window, WindowClass,
+        SELECTION_NOTIFY_EVENT,
+    };
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+    use x11rb::wrapper::ConnectionExt as _;
+    use x11rb::{COPY_DEPTH_FROM_PARENT, CURRENT_TIME, NONE};
+
+    use super::{ClipboardContents, ClipboardEntry, Mime};
+
+    const CONVERT_TIMEOUT: Duration = Duration::from_secs(1);
+    /// How long to wait before connecting to the X server again after losing it.
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    x11rb::atom_manager! {
+        Atoms: AtomsCookie {
+            CLIPBOARD,
+            TARGETS,
+            INCR,
+            STRING,
+            TEXT,
+            UTF8_STRING,
+            TEXT_PLAIN: b"text/plain",
+            TEXT_PLAIN_UTF8: b"text/plain;charset=utf-8",
+            TEXT_HTML: b"text/html",
+            IMAGE_PNG: b"image/png",
+            TENEBRA_SELECTION,
+            TENEBRA_WAKEUP,
+        }
+    }
+
+    /// Hands contents from sessions to the thread blocked on the X connection.
+    #[derive(Default)]
+    struct Mailbox {
+        // Only the latest contents matter
+        pending: Mutex<Option<Vec<ClipboardEntry>>>,
+        // The window to send a wakeup to, while connected
+        window: Mutex<Option<(Arc<RustConnection>, Window, Atom)>>,
+        closed: AtomicBool,
+    }
+
+    impl Mailbox {
+        fn wake(&self) {
+            if let Some((conn, window, wakeup)) = &*self.window.lock().unwrap() {
+                let event = ClientMessageEvent::new(32, *window, *wakeup, [0u32; 5]);
+                // A dead connection is noticed by the clipboard thread itself
+                conn.send_event(false, *window, EventMask::NO_EVENT, event)
+                    .ok();
+                conn.flush().ok();
+            }
+        }
+    }
+
+    struct ClipboardWindow {
+        conn: Arc<RustConnection>,
+        window: Window,
+        atoms: Atoms,
+        max_size: usize,
+        // Events that arrived while waiting for a selection conversion
+        deferred: VecDeque<Event>,
+    }
+
+    impl ClipboardWindow {
+        fn new(max_size: usize) -> Result<Self> {
+            let (conn, screen_num) =
+                x11rb::connect(None).context("Failed to connect to the X server")?;
+            let screen = &conn.setup().roots[screen_num];
+            let window = conn.generate_id()?;
+            conn.create_window(
+                COPY_DEPTH_FROM_PARENT,
+                window,
+                screen.root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                x11rb::COPY_FROM_PARENT,
+                &CreateWindowAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+            )?;
+            let atoms = Atoms::new(&conn)?.reply()?;
+
+            conn.xfixes_query_version(5, 0)?.reply()?;
+            conn.xfixes_select_selection_input(
+                window,
+                atoms.CLIPBOARD,
+                xfixes::SelectionEventMask::SET_SELECTION_OWNER,
+            )?;
+            conn.flush()?;
+
+            Ok(Self {
+                conn: Arc::new(conn),
+                window,
+                atoms,
+                max_size,
+                deferred: VecDeque::new(),
+            })
+        }
+
+        fn next_event(&mut self) -> Result<Event> {
+            if let Some(event) = self.deferred.pop_front() {
+                return Ok(event);
+            }
+            Ok(self.conn.wait_for_event()?)
+        }
+
+        /// Block until an event matching `filter` arrives, stashing everything else.
+        fn wait_for(&mut self, filter: impl Fn(&Event) -> bool) -> Result<Option<Event>> {
+            let deadline = Instant::now() + CONVERT_TIMEOUT;
+            while Instant::now() < deadline {
+                match self.conn.poll_for_event()? {
+                    Some(event) if filter(&event) => return Ok(Some(event)),
+                    Some(event) => self.deferred.push_back(event),
+                    None => std::thread::sleep(Duration::from_millis(5)),
+                }
+            }
+            Ok(None)
+        }
+
+        /// Ask the current owner to convert the clipboard to `target` and read the result.
+        fn convert(&mut self, target: Atom) -> Result<Option<(Atom, Vec<u8>)>> {
+            let property = self.atoms.TENEBRA_SELECTION;
+            self.conn.convert_selection(
+                self.window,
+                self.atoms.CLIPBOARD,
+                target,
+                property,
+                CURRENT_TIME,
+            )?;
+            self.conn.flush()?;
+
+            let window = self.window;
+            let notify = self.wait_for(|event| {
+                matches!(event, Event::SelectionNotify(ev) if ev.requestor == window)
+            })?;
+            match notify {
+                Some(Event::SelectionNotify(ev)) if ev.property != NONE => {}
+                _ => return Ok(None),
+            }
+
+            let reply = self
+                .conn
+                .get_property(true, self.window, property, AtomEnum::ANY, 0, u32::MAX / 4)?
+                .reply()?;
+            if reply.type_ != self.atoms.INCR {
+                return Ok(Some((reply.type_, reply.value)));
+            }
+
+            // The owner sends large selections in chunks, one property change at a time
+            let mut data = Vec::new();
+            loop {
+                let atom = property;
+                let new_value = self.wait_for(|event| {
+                    matches!(event, Event::PropertyNotify(ev) if ev.window == window && ev.atom == atom && ev.state == Property::NEW_VALUE)
+                })?;
+                if new_value.is_none() {
+                    warn!("Timed out during incremental clipboard transfer.");
+                    return Ok(None);
+                }
+                let chunk = self
+                    .conn
+                    .get_property(true, self.window, property, AtomEnum::ANY, 0, u32::MAX / 4)?
+                    .reply()?;
+                self.conn.flush()?;
+                if chunk.value.is_empty() {
+                    return Ok(Some((chunk.type_, data)));
+                }
+                data.extend_from_slice(&chunk.value);
+                if data.len() > self.max_size {
+                    warn!("Host clipboard exceeds the size limit, ignoring it.");
+                    return Ok(None);
+                }
+            }
+        }
+
+        fn read_clipboard(&mut self) -> Result<Vec<ClipboardEntry>> {
+            let targets = match self.convert(self.atoms.TARGETS)? {
+                Some((_, value)) => value
+                    .chunks_exact(4)
+                    .map(|atom| u32::from_ne_bytes(atom.try_into().unwrap()))
+                    .collect::<Vec<Atom>>(),
+                None => return Ok(vec![]),
+            };
+
+            let wanted = [
+                (Mime::Text, self.atoms.UTF8_STRING),
+                (Mime::Html, self.atoms.TEXT_HTML),
+                (Mime::Png, self.atoms.IMAGE_PNG),
+            ];
+
+            let mut entries = vec![];
+            for (mime, target) in wanted {
+                if !targets.contains(&target) {
+                    continue;
+                }
+                match self.convert(target)? {
+                    Some((_, data)) if data.len() <= self.max_size => {
+                        entries.push(ClipboardEntry { mime, data })
+                    }
+                    Some((_, data)) => warn!(
+                        "Not forwarding {} bytes of {} from the host clipboard: too large.",
+                        data.len(),
+                        mime.as_str()
+                    ),
+                    None => {}
+                }
+            }
+            Ok(entries)
+        }
+
+        fn serve_request(&self, owned: &[ClipboardEntry], req: &SelectionRequestEvent) -> Result<()> {
+            let atoms = &self.atoms;
+            let find = |mime: Mime| owned.iter().find(|entry| entry.mime == mime);
+
+            // Obsolete clients use the target as the property
+            let property = if req.property == NONE {
+                req.target
+            } else {
+                req.property
+            };
+
+            let served = if req.target == atoms.TARGETS {
+                let mut targets = vec![atoms.TARGETS];
+                for entry in owned {
+                    match entry.mime {
+                        Mime::Text => targets.extend([
+                            atoms.UTF8_STRING,
+                            atoms.STRING,
+                            atoms.TEXT,
+                            atoms.TEXT_PLAIN,
+                            atoms.TEXT_PLAIN_UTF8,
+                        ]),
+                        Mime::Html => targets.push(atoms.TEXT_HTML),
+                        Mime::Png => targets.push(atoms.IMAGE_PNG),
+                    }
+                }
+                self.conn.change_property32(
+                    PropMode::REPLACE,
+                    req.requestor,
+                    property,
+                    AtomEnum::ATOM,
+                    &targets,
+                )?;
+                true
+            } else {
+                let mime = if [
+                    atoms.UTF8_STRING,
+                    atoms.STRING,
+                    atoms.TEXT,
+                    atoms.TEXT_PLAIN,
+                    atoms.TEXT_PLAIN_UTF8,
+                ]
+                .contains(&req.target)
+                {
+                    Some(Mime::Text)
+                } else if req.target == atoms.TEXT_HTML {
+                    Some(Mime::Html)
+                } else if req.target == atoms.IMAGE_PNG {
+                    Some(Mime::Png)
+                } else {
+                    None
+                };
+
+                match mime.and_then(find) {
+                    Some(entry) => {
+                        self.conn.change_property8(
+                            PropMode::REPLACE,
+                            req.requestor,
+                            property,
+                            req.target,
+                            &entry.data,
+                        )?;
+                        true
+                    }
+                    None => false,
+                }
+            };
+
+            self.conn.send_event(
+                false,
+                req.requestor,
+                EventMask::NO_EVENT,
+                SelectionNotifyEvent {
+                    response_type: SELECTION_NOTIFY_EVENT,
+                    sequence: 0,
+                    time: req.time,
+                    requestor: req.requestor,
+                    selection: req.selection,
+                    target: req.target,
+                    property: if served { property } else { NONE },
+                },
+            )?;
+            self.conn.flush()?;
+            Ok(())
+        }
+    }
+
+    /// Keep the X clipboard and the sessions' clipboards in sync until `rx` closes, connecting
+    /// again whenever the X server goes away.
+    pub fn do_clipboard(
+        mut rx: mpsc::Receiver<Vec<ClipboardEntry>>,
+        changes: broadcast::Sender<ClipboardContents>,
+        max_size: usize,
+    ) {
+        let mailbox = Arc::new(Mailbox::default());
+        let forward_mailbox = mailbox.clone();
+        // Sessions' updates arrive here while the clipboard thread waits on the X connection
+        std::thread::spawn(move || {
+            while let Some(entries) = rx.blocking_recv() {
+                *forward_mailbox.pending.lock().unwrap() = Some(entries);
+                forward_mailbox.wake();
+            }
+            forward_mailbox.closed.store(true, Ordering::SeqCst);
+            forward_mailbox.wake();
+        });
+
+        // Only the first of a run of failures is worth a warning, e.g. on a host without X
+        let mut warned = false;
+        while !mailbox.closed.load(Ordering::SeqCst) {
+            let result = ClipboardWindow::new(max_size).and_then(|mut clipboard| {
+                info!("Watching the X11 clipboard.");
+                warned = false;
+                *mailbox.window.lock().unwrap() = Some((
+                    clipboard.conn.clone(),
+                    clipboard.window,
+                    clipboard.atoms.TENEBRA_WAKEUP,
+                ));
+                let result = watch(&mut clipboard, &mailbox, &changes);
+                *mailbox.window.lock().unwrap() = None;
+                result
+            });
+            match result {
+                Ok(()) => break,
+                Err(e) if !warned => {
+                    warn!("Clipboard synchronization interrupted, retrying: {:?}", e);
+                    warned = true;
+                }
+                Err(e) => debug!("Clipboard synchronization is still unavailable: {:?}", e),
+            }
+            std::thread::sleep(RECONNECT_DELAY);
+        }
+    }
+
+    /// Handle clipboard events until the connection fails, or `Ok` once sessions are gone.
+    fn watch(
+        clipboard: &mut ClipboardWindow,
+        mailbox: &Mailbox,
+        changes: &broadcast::Sender<ClipboardContents>,
+    ) -> Result<()> {
+        // What we serve while we own the selection
+        let mut owned: Vec<ClipboardEntry> = vec![];
+        // Last contents forwarded to clients, so that owners re-asserting the selection are ignored
+        let mut last_forwarded: Vec<ClipboardEntry> = vec![];
+
+        // Contents may have arrived while there was no connection to wake
+        let mut wakeup = true;
+        loop {
+            if wakeup {
+                if mailbox.closed.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                if let Some(entries) = mailbox.pending.lock().unwrap().take() {
+                    debug!(
+                        "Taking ownership of the clipboard for {} entries.",
+                        entries.len()
+                    );
+                    last_forwarded = entries.clone();
+                    owned = entries;
+                    clipboard.conn.set_selection_owner(
+                        clipboard.window,
+                        clipboard.atoms.CLIPBOARD,
+                        CURRENT_TIME,
+                    )?;
+                    clipboard.conn.flush()?;
+                }
+            }
+
+            // Only failing to get an event means the connection is gone. Anything else is one
+            // misbehaving client or conversion, and the next event may well work.
+            let event = clipboard.next_event()?;
+            wakeup = matches!(&event, Event::ClientMessage(ev)
+                if ev.window == clipboard.window && ev.type_ == clipboard.atoms.TENEBRA_WAKEUP);
+            let result = match event {
+                Event::XfixesSelectionNotify(ev) => {
+                    if ev.owner == clipboard.window || ev.owner == NONE {
+                        continue;
+                    }
+                    clipboard.read_clipboard().map(|entries| {
+                        if !entries.is_empty() && entries != last_forwarded {
+                            debug!("Host clipboard changed: {} entries.", entries.len());
+                            last_forwarded = entries.clone();
+                            // Fails only when no session is listening
+                            changes.send(Arc::new(entries)).ok();
+                        }
+                    })
+                }
+                Event::SelectionRequest(req) => clipboard.serve_request(&owned, &req),
+                Event::SelectionClear(_) => {
+                    owned.clear();
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!("Failed to handle a clipboard event: {:?}", e);
+            }
+        }
+    }
+}
@@ -1,15 +1,34 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use tokio::sync::mpsc::*;
 
 use anyhow::Result;
 
+use log::*;
+
+use serde::Deserialize;
+
 #[derive(Debug, Clone)]
 pub enum FileDialogKind {
     Save,
     Open,
+    Folder,
 }
 
+/// The host's answer to an incoming connection.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Approval {
+    Allow,
+    ViewOnly,
+    Deny,
+}
+
+const ALLOW_LABEL: &str = "Allow";
+const VIEW_ONLY_LABEL: &str = "View only";
+const DENY_LABEL: &str = "Deny";
+
 #[derive(Debug, Clone)]
 pub enum Dialog {
     MessageDialog {
@@ -18,6 +37,14 @@ pub enum Dialog {
         level: rfd::MessageLevel,
     },
     FileDialog(FileDialogKind, Sender<PathBuf>),
+    ApprovalDialog {
+        description: String,
+        // What the request falls back to if the host answers too late
+        default: Approval,
+        // Told when the dialog shows up, which starts the timeout
+        shown: Sender<()>,
+        tx: Sender<Approval>,
+    },
     StopLoop,
 }
 
@@ -44,11 +71,60 @@ pub fn do_dialogs(mut rx: Receiver<Dialog>) -> Result<()> {
                 let file = match kind {
                     FileDialogKind::Open => dialog.pick_file(),
                     FileDialogKind::Save => dialog.save_file(),
+                    FileDialogKind::Folder => dialog.pick_folder(),
                 };
                 if let Some(file) = file {
                     tx.blocking_send(file)?;
                 }
             }
+            Dialog::ApprovalDialog {
+                description,
+                default,
+                shown,
+                tx,
+            } => {
+                // Nobody is waiting for an answer anymore, e.g. the client went away
+                if tx.is_closed() {
+                    continue;
+                }
+                shown.blocking_send(()).ok();
+                let result = rfd::MessageDialog::new()
+                    .set_level(rfd::MessageLevel::Warning)
+                    .set_title("Tenebra Connection Request")
+                    .set_description(description)
+                    .set_buttons(rfd::MessageButtons::YesNoCancelCustom(
+                        ALLOW_LABEL.to_string(),
+                        VIEW_ONLY_LABEL.to_string(),
+                        DENY_LABEL.to_string(),
+                    ))
+                    .show();
+                let approval = match result {
+                    rfd::MessageDialogResult::Custom(label) if label == ALLOW_LABEL => {
+                        Approval::Allow
+                    }
+                    rfd::MessageDialogResult::Custom(label) if label == VIEW_ONLY_LABEL => {
+                        Approval::ViewOnly
+                    }
+                    _ => Approval::Deny,
+                };
+                // The request timed out while the dialog was open, and the dialog can't be
+                // closed for the host
+                if tx.blocking_send(approval).is_err() {
+                    let outcome = match default {
+                        Approval::Allow => "allowed",
+                        Approval::ViewOnly => "allowed to view only",
+                        Approval::Deny => "denied",
+                    };
+                    rfd::MessageDialog::new()
+                        .set_level(rfd::MessageLevel::Info)
+                        .set_title("Tenebra Connection Request")
+                        .set_description(format!(
+                            "The connection request timed out before it was answered, so it was {}.",
+                            outcome
+                        ))
+                        .show();
+                }
+            }
             Dialog::StopLoop => break,
         }
     }
@@ -60,6 +136,10 @@ pub async fn spawn_file_dialog(
     dialog_tx: &Sender<Dialog>,
     kind: FileDialogKind,
 ) -> Option<PathBuf> {
+    // Nobody could pick a file, treat it as dismissed
+    if is_headless() {
+        return None;
+    }
     let (file_tx, mut file_rx) = channel(1);
     // this should never fail
     dialog_tx
@@ -75,6 +155,10 @@ pub async fn spawn_message_dialog(
     description: impl Into<String>,
     level: rfd::MessageLevel,
 ) {
+    if is_headless() {
+        info!("{}: {}", title.into(), description.into());
+        return;
+    }
     dialog_tx
         .send(Dialog::MessageDialog {
             title: title.into(),
@@ -84,3 +168,57 @@ pub async fn spawn_message_dialog(
         .await
         .unwrap();
 }
+
+/// Whether there is nobody at the host to show a dialog to. Dialogs on a virtual display would
+/// only be seen, and answered, by remote viewers.
+pub fn is_headless() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            crate::display::is_virtual()
+                || (std::env::var_os("DISPLAY").is_none()
+                    && std::env::var_os("WAYLAND_DISPLAY").is_none())
+        } else {
+            false
+        }
+    }
+}
+
+/// Ask the local user whether to let a connection in. `default` is used if nobody answers within
+/// `timeout` of the dialog showing up, if other dialogs keep it from showing up for as long, or
+/// straight away if there is nobody to ask.
+pub async fn spawn_approval_dialog(
+    dialog_tx: &Sender<Dialog>,
+    description: impl Into<String>,
+    timeout: Duration,
+    default: Approval,
+) -> Approval {
+    if is_headless() {
+        return default;
+    }
+    let (approval_tx, mut approval_rx) = channel(1);
+    let (shown_tx, mut shown_rx) = channel(1);
+    // this should never fail
+    dialog_tx
+        .send(Dialog::ApprovalDialog {
+            description: description.into(),
+            default,
+            shown: shown_tx,
+            tx: approval_tx,
+        })
+        .await
+        .unwrap();
+    // Other dialogs may be open, this one waits for them without using up its time
+    match tokio::time::timeout(timeout, shown_rx.recv()).await {
+        Ok(Some(())) => {}
+        Ok(None) => return default,
+        Err(_) => {
+            // The dialog is skipped once it comes up, as nobody waits for its answer then
+            warn!("Another dialog kept the connection request from showing up.");
+            return default;
+        }
+    }
+    match tokio::time::timeout(timeout, approval_rx.recv()).await {
+        Ok(Some(approval)) => approval,
+        _ => default,
+    }
+}
@@ -0,0 +1,582 @@
+use std::fmt::Display;
+
+use anyhow::Result;
+
+use log::*;
+
+use serde::Deserialize;
+
+use crate::protocol::{Monitor, Window};
+
+/// The X server behind a virtual display.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayServer {
+    #[default]
+    Xvfb,
+    // Xorg with the dummy video driver, which supports RandR
+    Xorg,
+}
+
+impl Display for DisplayServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DisplayServer::Xvfb => write!(f, "Xvfb"),
+            DisplayServer::Xorg => write!(f, "Xorg (dummy)"),
+        }
+    }
+}
+
+/// An X server, and optionally a desktop session on it, that tenebra starts and keeps running.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct VirtualDisplayConfig {
+    #[serde(default)]
+    pub server: DisplayServer,
+    // The display number, the first free one is used when unset
+    pub display: Option<u32>,
+    #[serde(default = "default_width")]
+    pub width: u32,
+    #[serde(default = "default_height")]
+    pub height: u32,
+    #[serde(default = "default_depth")]
+    pub depth: u32,
+    // Run with `sh -c` once the display is up, e.g. "startxfce4"
+    pub session: Option<String>,
+}
+
+impl Display for VirtualDisplayConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} at {}x{}", self.server, self.width, self.height)?;
+        if let Some(display) = self.display {
+            write!(f, " on :{}", display)?;
+        }
+        if let Some(session) = &self.session {
+            write!(f, " running `{}`", session)?;
+        }
+        Ok(())
+    }
+}
+
+fn default_width() -> u32 {
+    1920
+}
+
+fn default_height() -> u32 {
+    1080
+}
+
+fn default_depth() -> u32 {
+    24
+}
+
+/// The smallest screen a client may ask for.
+const MIN_WIDTH: u32 = 640;
+const MIN_HEIGHT: u32 = 480;
+
+/// The size a client asked the screen to be, after rounding, and whether it had to change.
+#[derive(Debug, Clone, Copy)]
+pub struct Resized {
+    pub width: u32,
+    pub height: u32,
+    pub changed: bool,
+}
+
+/// Fits the screen to the viewport of the client that asked most recently, and puts the
+/// original mode back once every client that asked has left.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    state: tokio::sync::Mutex<ResolutionState>,
+}
+
+#[derive(Debug, Default)]
+struct ResolutionState {
+    // The latest size each session asked for, oldest first
+    requests: Vec<(String, (u16, u16))>,
+    // How the screen was before it was first resized
+    #[cfg(target_os = "linux")]
+    saved: Option<randr::Saved>,
+}
+
+impl Resolution {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resize the screen to fit a `width`x`height` viewport on behalf of `session`.
+    pub async fn request(&self, session: &str, width: u32, height: u32) -> Result<Resized> {
+        // CVT timings come in multiples of 8 pixels, and encoders want even sizes
+        let width = (width.clamp(MIN_WIDTH, u16::MAX as u32) / 8 * 8) as u16;
+        let height = (height.clamp(MIN_HEIGHT, u16::MAX as u32) / 2 * 2) as u16;
+
+        let mut state = self.state.lock().await;
+        // The screen already has this size, and asking RandR again would only take time
+        if state.requests.last() == Some(&(session.to_string(), (width, height))) {
+            return Ok(Resized {
+                width: width as u32,
+                height: height as u32,
+                changed: false,
+            });
+        }
+        let changed = state.apply(width, height).await?;
+        state.requests.retain(|(id, _)| id != session);
+        state.requests.push((session.to_string(), (width, height)));
+        Ok(Resized {
+            width: width as u32,
+            height: height as u32,
+            changed,
+        })
+    }
+
+    /// Forget what `session` asked for. If its size was the one in use, the screen goes back
+    /// to the previous request, or to its original mode. Returns whether the screen changed.
+    pub async fn release(&self, session: &str) -> bool {
+        let mut state = self.state.lock().await;
+        let active = state.requests.last().is_some_and(|(id, _)| id == session);
+        state.requests.retain(|(id, _)| id != session);
+        if !active {
+            return false;
+        }
+
+        let result = match state.requests.last() {
+            Some(&(_, (width, height))) => state.apply(width, height).await,
+            None => state.restore().await,
+        };
+        result
+            .inspect_err(|e| error!("Failed to restore the screen resolution: {:?}", e))
+            .unwrap_or(false)
+    }
+}
+
+impl ResolutionState {
+    #[cfg(target_os = "linux")]
+    async fn apply(&mut self, width: u16, height: u16) -> Result<bool> {
+        let previous =
+            tokio::task::spawn_blocking(move || randr::set_size(width, height)).await??;
+        // Only the first change knows the original mode
+        if let Some(previous) = previous {
+            self.saved.get_or_insert(previous);
+        }
+        Ok(previous.is_some())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn restore(&mut self) -> Result<bool> {
+        let Some(saved) = self.saved.take() else {
+            return Ok(false);
+        };
+        tokio::task::spawn_blocking(move || randr::restore(&saved)).await??;
+        Ok(true)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn apply(&mut self, _width: u16, _height: u16) -> Result<bool> {
+        anyhow::bail!("changing the resolution is only supported on Linux")
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn restore(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// The monitors of the X screen, as RandR reports them.
+#[cfg(target_os = "linux")]
+pub async fn monitors() -> Result<Vec<Monitor>> {
+    tokio::task::spawn_blocking(randr::monitors).await?
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn monitors() -> Result<Vec<Monitor>> {
+    anyhow::bail!("listing monitors is only supported on Linux")
+}
+
+/// The top-level windows of the X screen that can be captured on their own.
+#[cfg(target_os = "linux")]
+pub async fn windows() -> Result<Vec<Window>> {
+    tokio::task::spawn_blocking(window::windows).await?
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn windows() -> Result<Vec<Window>> {
+    anyhow::bail!("listing windows is only supported on Linux")
+}
+
+#[cfg(target_os = "linux")]
+pub use window::WindowWatcher;
+
+#[cfg(not(target_os = "linux"))]
+pub struct WindowWatcher;
+
+#[cfg(not(target_os = "linux"))]
+impl WindowWatcher {
+    pub async fn open(_id: u32) -> Result<Self> {
+        anyhow::bail!("capturing a window is only supported on Linux")
+    }
+
+    pub async fn geometry(&self) -> Result<Option<crate::rtc::hub::Region>> {
+        Ok(None)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use damage::{Area as DamageArea, DamageTracker};
+
+#[cfg(target_os = "linux")]
+mod damage;
+#[cfg(target_os = "linux")]
+mod randr;
+#[cfg(target_os = "linux")]
+mod window;
+
+#[cfg(target_os = "linux")]
+pub use linux::{claim, is_virtual, start};
+
+#[cfg(not(target_os = "linux"))]
+pub async fn start(
+    _config: &VirtualDisplayConfig,
+    _config_dir: &std::path::Path,
+) -> anyhow::Result<std::sync::Arc<tokio::sync::Notify>> {
+    log::warn!("Virtual displays are only supported on Linux, ignoring `virtual_display`.");
+    Ok(Default::default())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_virtual() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::path::{Path, PathBuf};
+    use std::process::Stdio;
+    use std::sync::{Arc, OnceLock};
+    use std::time::Duration;
+
+    use anyhow::{bail, Context, Result};
+
+    use log::*;
+
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::process::{Child, Command};
+    use tokio::sync::Notify;
+    use tokio::time::{sleep, timeout, Instant};
+
+    use super::{DisplayServer, VirtualDisplayConfig};
+
+    /// How long the X server may take to accept connections.
+    const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+    /// Restarts back off up to this long while the display keeps dying.
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+    /// A display that stayed up this long is considered healthy again.
+    const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+    /// The display number picked by [`claim`].
+    static CLAIMED: OnceLock<u32> = OnceLock::new();
+
+    struct Supervisor {
+        config: VirtualDisplayConfig,
+        // Generated for Xorg, which can't take the resolution on its command line
+        xorg_conf: PathBuf,
+        display: Option<u32>,
+        restarted: Arc<Notify>,
+    }
+
+    /// Pick the display number of the virtual display and point `DISPLAY` at it, so that
+    /// capture, input and the clipboard all use it. Changing the environment races with other
+    /// threads reading it, so this has to happen before the runtime starts any.
+    pub fn claim(config: &VirtualDisplayConfig) -> Result<()> {
+        let display = match config.display {
+            Some(display) => display,
+            None => (1..1000)
+                .find(|n| {
+                    !Path::new(&format!("/tmp/.X{}-lock", n)).exists()
+                        && !Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists()
+                })
+                .context("There is no free display number")?,
+        };
+        std::env::set_var("DISPLAY", format!(":{}", display));
+        CLAIMED.get_or_init(|| display);
+        Ok(())
+    }
+
+    /// Whether `DISPLAY` is a virtual display of ours, which only remote viewers can see.
+    pub fn is_virtual() -> bool {
+        CLAIMED.get().is_some()
+    }
+
+    /// Start the virtual display on the display [`claim`] picked. The display and its session
+    /// are restarted whenever they exit, and the returned `Notify` is notified when the display
+    /// comes back, since everything connected to it has to reconnect.
+    pub async fn start(config: &VirtualDisplayConfig, config_dir: &Path) -> Result<Arc<Notify>> {
+        let display = *CLAIMED
+            .get()
+            .context("The display number was not claimed at startup")?;
+        let restarted = Arc::new(Notify::new());
+        let mut supervisor = Supervisor {
+            config: config.clone(),
+            xorg_conf: config_dir.join("xorg-dummy.conf"),
+            display: Some(display),
+            restarted: restarted.clone(),
+        };
+        if supervisor.config.server == DisplayServer::Xorg {
+            std::fs::write(&supervisor.xorg_conf, xorg_conf(config))
+                .context("Failed to write the Xorg configuration")?;
+        }
+
+        let server = supervisor.start_server().await?;
+        info!("Virtual display :{} is up.", display);
+        let session = supervisor.start_session();
+
+        tokio::spawn(supervisor.run(server, session));
+        Ok(restarted)
+    }
+
+    impl Supervisor {
+        async fn run(mut self, mut server: Child, mut session: Option<Child>) {
+            let mut backoff = Duration::from_secs(1);
+            let mut started = Instant::now();
+            loop {
+                tokio::select! {
+                    status = server.wait() => {
+                        error!("The virtual display exited ({}), restarting it.", describe_exit(status));
+                        if let Some(mut session) = session.take() {
+                            session.kill().await.ok();
+                        }
+                        loop {
+                            backoff = self.next_backoff(backoff, started);
+                            sleep(backoff).await;
+                            started = Instant::now();
+                            match self.start_server().await {
+                                Ok(restarted) => {
+                                    server = restarted;
+                                    self.restarted.notify_one();
+                                    break;
+                                }
+                                Err(e) => error!("Failed to restart the virtual display: {:?}", e),
+                            }
+                        }
+                        session = self.start_session();
+                    }
+                    Some(status) = wait_session(&mut session) => {
+                        warn!("The desktop session exited ({}), restarting it.", describe_exit(status));
+                        backoff = self.next_backoff(backoff, started);
+                        sleep(backoff).await;
+                        started = Instant::now();
+                        session = self.start_session();
+                    }
+                }
+            }
+        }
+
+        fn next_backoff(&self, backoff: Duration, started: Instant) -> Duration {
+            if started.elapsed() >= HEALTHY_AFTER {
+                Duration::from_secs(1)
+            } else {
+                (backoff * 2).min(MAX_BACKOFF)
+            }
+        }
+
+        /// Launch the X server and wait until it accepts connections.
+        async fn start_server(&mut self) -> Result<Child> {
+            let mut command = Command::new(match self.config.server {
+                DisplayServer::Xvfb => "Xvfb",
+                DisplayServer::Xorg => "Xorg",
+            });
+            if let Some(display) = self.display {
+                command.arg(format!(":{}", display));
+            }
+            match self.config.server {
+                DisplayServer::Xvfb => command.arg("-screen").arg("0").arg(format!(
+                    "{}x{}x{}",
+                    self.config.width, self.config.height, self.config.depth
+                )),
+                DisplayServer::Xorg => command.arg("-config").arg(&self.xorg_conf),
+            };
+            // The server writes its display number to stdout once it is ready for clients
+            command
+                .args(["-displayfd", "1", "-nolisten", "tcp", "-noreset"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true);
+            let mut server = command
+                .spawn()
+                .with_context(|| format!("Failed to run {:?}", command.as_std().get_program()))?;
+
+            let stdout = server.stdout.take().unwrap();
+            let mut line = String::new();
+            let read = timeout(STARTUP_TIMEOUT, BufReader::new(stdout).read_line(&mut line)).await;
+            match read {
+                Ok(Ok(n)) if n > 0 => {}
+                Ok(_) => {
+                    let status = server.wait().await;
+                    bail!(
+                        "the X server exited during startup ({})",
+                        describe_exit(status)
+                    );
+                }
+                Err(_) => {
+                    server.kill().await.ok();
+                    bail!(
+                        "the X server did not start within {} seconds",
+                        STARTUP_TIMEOUT.as_secs()
+                    );
+                }
+            }
+            let display = line
+                .trim()
+                .parse()
+                .context("the X server reported an invalid display number")?;
+            // Restarts keep the display everything else is already pointed at
+            self.display = Some(display);
+            Ok(server)
+        }
+
+        fn start_session(&self) -> Option<Child> {
+            let session = self.config.session.as_ref()?;
+            match Command::new("sh")
+                .arg("-c")
+                .arg(session)
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()
+            {
+                Ok(child) => {
+                    info!("Started desktop session `{}`.", session);
+                    Some(child)
+                }
+                Err(e) => {
+                    error!("Failed to start desktop session `{}`: {}", session, e);
+                    None
+                }
+            }
+        }
+    }
+
+    /// Wait for the session to exit. Never resolves if there is none.
+    async fn wait_session(
+        session: &mut Option<Child>,
+    ) -> Option<std::io::Result<std::process::ExitStatus>> {
+        match session {
+            Some(session) => Some(session.wait().await),
+            None => std::future::pending().await,
+        }
+    }
+
+    fn describe_exit(status: std::io::Result<std::process::ExitStatus>) -> String {
+        match status {
+            Ok(status) => status.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    /// An Xorg configuration for the dummy driver with a single mode at the configured
+    /// resolution.
+    fn xorg_conf(config: &VirtualDisplayConfig) -> String {
+        let (width, height) = (config.width, config.height);
+        // Enough for the framebuffer with some room to spare, in KiB
+        let video_ram = (width as u64 * height as u64 * 4 / 1024 * 2).max(256000);
+        format!(
+            r#"Section "Device"
+    Identifier  "Dummy Device"
+    Driver      "dummy"
+    VideoRam    {video_ram}
+EndSection
+
+Section "Monitor"
+    Identifier  "Dummy Monitor"
+    HorizSync   5.0-1000.0
+    VertRefresh 5.0-200.0
+    Modeline    {modeline}
+EndSection
+
+Section "Screen"
+    Identifier "Dummy Screen"
+    Device     "Dummy Device"
+    Monitor    "Dummy Monitor"
+    DefaultDepth {depth}
+    SubSection "Display"
+        Depth     {depth}
+        Modes     "{width}x{height}"
+    EndSubSection
+EndSection
+"#,
+            modeline = modeline(width, height, 60),
+            depth = config.depth,
+        )
+    }
+
+    /// VESA CVT reduced blanking timings, like `cvt --reduced` computes them.
+    pub(super) struct Timings {
+        // In MHz
+        pub clock: f64,
+        pub h_sync_start: u32,
+        pub h_sync_end: u32,
+        pub h_total: u32,
+        pub v_sync_start: u32,
+        pub v_sync_end: u32,
+        pub v_total: u32,
+    }
+
+    pub(super) fn cvt(width: u32, height: u32, refresh: u32) -> Timings {
+        const H_BLANK: u32 = 160;
+        const H_FRONT_PORCH: u32 = 48;
+        const H_SYNC: u32 = 32;
+        const V_FRONT_PORCH: u32 = 3;
+        const V_BACK_PORCH_MIN: u32 = 6;
+        const MIN_V_BLANK_US: f64 = 460.0;
+        const CLOCK_STEP_MHZ: f64 = 0.25;
+
+        // The vertical sync width encodes the aspect ratio
+        let v_sync = match (
+            width * 3 == height * 4,
+            width * 9 == height * 16,
+            width * 10 == height * 16,
+        ) {
+            (true, _, _) => 4,
+            (_, true, _) => 5,
+            (_, _, true) => 6,
+            _ if width * 4 == height * 5 || width * 9 == height * 15 => 7,
+            _ => 10,
+        };
+        let h_period_us = (1_000_000.0 / refresh as f64 - MIN_V_BLANK_US) / height as f64;
+        let v_blank = ((MIN_V_BLANK_US / h_period_us) as u32 + 1)
+            .max(V_FRONT_PORCH + v_sync + V_BACK_PORCH_MIN);
+        let v_total = height + v_blank;
+        let h_total = width + H_BLANK;
+        let clock =
+            (refresh as f64 * v_total as f64 * h_total as f64 / 1_000_000.0 / CLOCK_STEP_MHZ)
+                .floor()
+                * CLOCK_STEP_MHZ;
+
+        let h_sync_start = width + H_FRONT_PORCH;
+        let v_sync_start = height + V_FRONT_PORCH;
+        Timings {
+            clock,
+            h_sync_start,
+            h_sync_end: h_sync_start + H_SYNC,
+            h_total,
+            v_sync_start,
+            v_sync_end: v_sync_start + v_sync,
+            v_total,
+        }
+    }
+
+    /// A modeline for the CVT timings, like `cvt --reduced` prints.
+    fn modeline(width: u32, height: u32, refresh: u32) -> String {
+        let timings = cvt(width, height, refresh);
+        format!(
+            "\"{}x{}\" {:.2} {} {} {} {} {} {} {} {} +hsync -vsync",
+            width,
+            height,
+            timings.clock,
+            width,
+            timings.h_sync_start,
+            timings.h_sync_end,
+            timings.h_total,
+            height,
+            timings.v_sync_start,
+            timings.v_sync_end,
+            timings.v_total
+        )
+    }
+}
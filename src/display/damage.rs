@@ -0,0 +1,246 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+
+use log::*;
+
+use x11rb::connection::Connection as _;
+use x11rb::protocol::damage::{self, ConnectionExt as _, ReportLevel};
+use x11rb::protocol::xfixes::{self, ConnectionExt as _, CursorNotifyMask};
+use x11rb::protocol::xinput::{self, ConnectionExt as _, XIEventMask};
+use x11rb::protocol::xproto::{
+    ConnectionExt as _, CreateWindowAux, EventMask, Rectangle, Window, WindowClass,
+};
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+use x11rb::COPY_FROM_PARENT;
+use x11rb::NONE;
+
+/// How far past the captured area the pointer can be and still have part of it drawn there.
+const CURSOR_MARGIN: i32 = 64;
+
+/// The part of the screen that is captured, in root window coordinates. The ends are inclusive,
+/// `None` extends to the edge of the screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Area {
+    pub startx: i32,
+    pub starty: i32,
+    pub endx: Option<i32>,
+    pub endy: Option<i32>,
+}
+
+impl Area {
+    fn overlaps(&self, rect: &Rectangle) -> bool {
+        let (x, y) = (rect.x as i32, rect.y as i32);
+        x + rect.width as i32 > self.startx
+            && y + rect.height as i32 > self.starty
+            && self.endx.is_none_or(|endx| x <= endx)
+            && self.endy.is_none_or(|endy| y <= endy)
+    }
+
+    fn near(&self, (x, y): (i16, i16)) -> bool {
+        self.overlaps(&Rectangle {
+            x: (x as i32 - CURSOR_MARGIN) as i16,
+            y: (y as i32 - CURSOR_MARGIN) as i16,
+            width: 2 * CURSOR_MARGIN as u16,
+            height: 2 * CURSOR_MARGIN as u16,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Flags {
+    changed: AtomicBool,
+    stopped: AtomicBool,
+    // `None` while the whole screen counts, e.g. when a window is captured
+    area: Mutex<Option<Area>>,
+}
+
+/// Watches the X screen for damage, so that capture can skip frames while nothing changes.
+#[derive(Debug)]
+pub struct DamageTracker {
+    flags: Arc<Flags>,
+    conn: Arc<RustConnection>,
+    // Destroyed to wake the watching thread up when the tracker is dropped
+    wakeup: Window,
+}
+
+impl DamageTracker {
+    /// Start watching `area` of the screen, and the pointer if it is captured too.
+    pub fn start(area: Area, track_pointer: bool) -> Result<Self> {
+        let (conn, screen_num) =
+            x11rb::connect(None).context("Failed to connect to the X server")?;
+        let root = conn.setup().roots[screen_num].root;
+        conn.damage_query_version(1, 1)?
+            .reply()
+            .context("The X server does not support DAMAGE")?;
+        conn.xfixes_query_version(2, 0)?
+            .reply()
+            .context("The X server does not support XFIXES")?;
+        let damage = conn.generate_id()?;
+        conn.damage_create(damage, root, ReportLevel::NON_EMPTY)?
+            .check()
+            .context("Failed to watch the screen for damage")?;
+        let parts = conn.generate_id()?;
+        conn.xfixes_create_region(parts, &[])?;
+
+        if track_pointer {
+            conn.xinput_xi_query_version(2, 2)?
+                .reply()
+                .context("The X server does not support XInput 2.2")?;
+            // Raw events reach the root window no matter which window the pointer is over
+            conn.xinput_xi_select_events(
+                root,
+                &[xinput::EventMask {
+                    deviceid: xinput::Device::ALL_MASTER.into(),
+                    mask: vec![XIEventMask::RAW_MOTION],
+                }],
+            )?
+            .check()
+            .context("Failed to watch the pointer")?;
+            // The pointer can change shape without moving
+            conn.xfixes_select_cursor_input(root, CursorNotifyMask::DISPLAY_CURSOR)?;
+        }
+
+        let wakeup = conn.generate_id()?;
+        conn.create_window(
+            COPY_FROM_PARENT as u8,
+            wakeup,
+            root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            COPY_FROM_PARENT,
+            &CreateWindowAux::new().event_mask(EventMask::STRUCTURE_NOTIFY),
+        )?
+        .check()
+        .context("Failed to create a window")?;
+
+        let flags = Arc::new(Flags {
+            // The first frame always goes out
+            changed: AtomicBool::new(true),
+            stopped: AtomicBool::new(false),
+            area: Mutex::new(Some(area)),
+        });
+        let conn = Arc::new(conn);
+        let thread_flags = flags.clone();
+        let thread_conn = conn.clone();
+        std::thread::spawn(move || {
+            let watcher = Watcher {
+                conn: &thread_conn,
+                root,
+                damage,
+                parts,
+                wakeup,
+                track_pointer,
+                flags: &thread_flags,
+            };
+            if let Err(e) = watcher.watch() {
+                if !thread_flags.stopped.load(Ordering::SeqCst) {
+                    error!("Stopped tracking damage, capturing every frame: {:?}", e);
+                }
+                // Without damage reports, every frame has to be assumed to have changed
+                thread_flags.stopped.store(true, Ordering::SeqCst);
+            }
+        });
+        Ok(Self {
+            flags,
+            conn,
+            wakeup,
+        })
+    }
+
+    /// Whether anything changed since the last call.
+    pub fn take(&self) -> bool {
+        self.flags.changed.swap(false, Ordering::SeqCst)
+            || self.flags.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Let the next frame through even if nothing changed, e.g. for a keyframe.
+    pub fn mark(&self) {
+        self.flags.changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Only count changes in `area`, or anywhere on the screen for `None`.
+    pub fn set_area(&self, area: Option<Area>) {
+        *self.flags.area.lock().unwrap() = area;
+        self.mark();
+    }
+}
+
+impl Drop for DamageTracker {
+    fn drop(&mut self) {
+        self.flags.stopped.store(true, Ordering::SeqCst);
+        // The thread is blocked waiting for an event, the DestroyNotify is one
+        let _ = self.conn.destroy_window(self.wakeup);
+        let _ = self.conn.flush();
+    }
+}
+
+struct Watcher<'a> {
+    conn: &'a RustConnection,
+    root: Window,
+    damage: damage::Damage,
+    parts: xfixes::Region,
+    wakeup: Window,
+    track_pointer: bool,
+    flags: &'a Flags,
+}
+
+impl Watcher<'_> {
+    fn watch(&self) -> Result<()> {
+        let mut pointer = self.pointer()?;
+        loop {
+            let event = self.conn.wait_for_event()?;
+            if self.flags.stopped.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            let area = *self.flags.area.lock().unwrap();
+            let changed = match event {
+                Event::DamageNotify(_) => {
+                    // Take the damage so far and start collecting again, so the next change is
+                    // reported too
+                    self.conn.damage_subtract(self.damage, NONE, self.parts)?;
+                    let rectangles = self
+                        .conn
+                        .xfixes_fetch_region(self.parts)?
+                        .reply()?
+                        .rectangles;
+                    match area {
+                        Some(area) => rectangles.iter().any(|rect| area.overlaps(rect)),
+                        None => !rectangles.is_empty(),
+                    }
+                }
+                Event::XinputRawMotion(_) | Event::XfixesCursorNotify(_) => {
+                    let last = pointer;
+                    pointer = self.pointer()?;
+                    // A new cursor shape shows up in the frame even if the pointer stays put
+                    let moved = pointer != last || matches!(event, Event::XfixesCursorNotify(_));
+                    moved
+                        && area.is_none_or(|area| {
+                            pointer.is_some_and(|p| area.near(p))
+                                || last.is_some_and(|p| area.near(p))
+                        })
+                }
+                Event::DestroyNotify(event) if event.window == self.wakeup => return Ok(()),
+                _ => false,
+            };
+            if changed {
+                self.flags.changed.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Where the pointer is, if it is tracked.
+    fn pointer(&self) -> Result<Option<(i16, i16)>> {
+        if !self.track_pointer {
+            return Ok(None);
+        }
+        let reply = self.conn.query_pointer(self.root)?.reply()?;
+        Ok(Some((reply.root_x, reply.root_y)))
+    }
+}
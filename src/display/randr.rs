@@ -0,0 +1,316 @@
+use anyhow::{bail, Context, Result};
+
+use log::*;
+
+use x11rb::connection::Connection as _;
+use x11rb::protocol::randr::{self, ConnectionExt as _, ModeFlag, ModeInfo};
+use x11rb::protocol::xproto::{ConnectionExt as _, Window};
+use x11rb::rust_connection::RustConnection;
+use x11rb::{CURRENT_TIME, NONE};
+
+use super::linux::cvt;
+use crate::protocol::Monitor;
+
+/// Modes we create are named with this prefix, so they can be found and removed again.
+const MODE_PREFIX: &str = "tenebra-";
+
+/// The size of the whole screen, which has to cover every CRTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ScreenSize {
+    width: u16,
+    height: u16,
+    mm_width: u32,
+    mm_height: u32,
+}
+
+/// How the screen was before it was resized.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Saved {
+    mode: randr::Mode,
+    size: ScreenSize,
+}
+
+/// The screen and the CRTC that drives the output being resized.
+struct Screen {
+    conn: RustConnection,
+    root: Window,
+    size: ScreenSize,
+    resources: randr::GetScreenResourcesCurrentReply,
+    output: randr::Output,
+    output_modes: Vec<randr::Mode>,
+    crtc: randr::Crtc,
+    crtc_info: randr::GetCrtcInfoReply,
+}
+
+/// Switch the output to a `width`x`height` mode, creating one if it has none. Returns how the
+/// screen was before, or `None` if the output already had that size.
+pub(super) fn set_size(width: u16, height: u16) -> Result<Option<Saved>> {
+    let screen = Screen::open()?;
+    if screen.crtc_info.width == width && screen.crtc_info.height == height {
+        return Ok(None);
+    }
+    let saved = Saved {
+        mode: screen.crtc_info.mode,
+        size: screen.size,
+    };
+
+    // Grow or shrink the screen along with the CRTC, keeping the other CRTCs on it
+    let (mut screen_width, mut screen_height) = (
+        (screen.crtc_info.x as i32 + width as i32) as u16,
+        (screen.crtc_info.y as i32 + height as i32) as u16,
+    );
+    for &crtc in screen.resources.crtcs.iter().filter(|&&c| c != screen.crtc) {
+        let info = screen
+            .conn
+            .randr_get_crtc_info(crtc, screen.resources.config_timestamp)?
+            .reply()?;
+        if info.mode != NONE {
+            screen_width = screen_width.max((info.x as i32 + info.width as i32) as u16);
+            screen_height = screen_height.max((info.y as i32 + info.height as i32) as u16);
+        }
+    }
+    let range = screen
+        .conn
+        .randr_get_screen_size_range(screen.root)?
+        .reply()?;
+    if screen_width > range.max_width || screen_height > range.max_height {
+        bail!(
+            "{}x{} is larger than the X server allows ({}x{})",
+            screen_width,
+            screen_height,
+            range.max_width,
+            range.max_height
+        );
+    }
+
+    let mode = screen.find_or_create_mode(width, height)?;
+    screen.apply(
+        mode,
+        ScreenSize {
+            width: screen_width,
+            height: screen_height,
+            // Keep the DPI the screen had
+            mm_width: screen.size.mm_width * screen_width as u32 / screen.size.width as u32,
+            mm_height: screen.size.mm_height * screen_height as u32 / screen.size.height as u32,
+        },
+    )?;
+    info!("Resized the screen to {}x{}.", width, height);
+    Ok(Some(saved))
+}
+
+/// Put the screen back the way it was, and remove the modes we created.
+pub(super) fn restore(saved: &Saved) -> Result<()> {
+    let screen = Screen::open()?;
+    screen.apply(saved.mode, saved.size)?;
+    screen.remove_created_modes();
+    info!(
+        "Restored the screen to {}x{}.",
+        saved.size.width, saved.size.height
+    );
+    Ok(())
+}
+
+/// Every active monitor, in the order the X server lists them.
+pub(super) fn monitors() -> Result<Vec<Monitor>> {
+    let (conn, screen_num) = x11rb::connect(None).context("Failed to connect to the X server")?;
+    let root = conn.setup().roots[screen_num].root;
+    conn.randr_query_version(1, 5)?
+        .reply()
+        .context("The X server does not support RandR")?;
+
+    let reply = conn
+        .randr_get_monitors(root, true)?
+        .reply()
+        .context("Failed to list monitors")?;
+    reply
+        .monitors
+        .iter()
+        .map(|monitor| {
+            let name = conn.get_atom_name(monitor.name)?.reply()?.name;
+            Ok(Monitor {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                primary: monitor.primary,
+                x: monitor.x as i32,
+                y: monitor.y as i32,
+                width: monitor.width as u32,
+                height: monitor.height as u32,
+            })
+        })
+        .collect()
+}
+
+impl Screen {
+    fn open() -> Result<Self> {
+        let (conn, screen_num) =
+            x11rb::connect(None).context("Failed to connect to the X server")?;
+        let (root, size) = {
+            let screen = &conn.setup().roots[screen_num];
+            let size = ScreenSize {
+                width: screen.width_in_pixels,
+                height: screen.height_in_pixels,
+                mm_width: screen.width_in_millimeters as u32,
+                mm_height: screen.height_in_millimeters as u32,
+            };
+            (screen.root, size)
+        };
+        conn.randr_query_version(1, 3)?
+            .reply()
+            .context("The X server does not support RandR")?;
+
+        let resources = conn.randr_get_screen_resources_current(root)?.reply()?;
+        // Prefer the primary output, or else the first one that shows anything
+        let primary = conn.randr_get_output_primary(root)?.reply()?.output;
+        let mut found = None;
+        for output in std::iter::once(primary).chain(resources.outputs.iter().copied()) {
+            if output == NONE {
+                continue;
+            }
+            let info = conn
+                .randr_get_output_info(output, resources.config_timestamp)?
+                .reply()?;
+            if info.connection == randr::Connection::CONNECTED && info.crtc != NONE {
+                found = Some((output, info.modes, info.crtc));
+                break;
+            }
+        }
+        let Some((output, output_modes, crtc)) = found else {
+            bail!("no output of the X server is enabled");
+        };
+        let crtc_info = conn
+            .randr_get_crtc_info(crtc, resources.config_timestamp)?
+            .reply()?;
+
+        Ok(Self {
+            conn,
+            root,
+            size,
+            resources,
+            output,
+            output_modes,
+            crtc,
+            crtc_info,
+        })
+    }
+
+    /// Every mode the server knows, with its name.
+    fn modes(&self) -> impl Iterator<Item = (&ModeInfo, &[u8])> {
+        let mut offset = 0;
+        self.resources.modes.iter().map(move |mode| {
+            let name = &self.resources.names[offset..offset + mode.name_len as usize];
+            offset += mode.name_len as usize;
+            (mode, name)
+        })
+    }
+
+    fn find_or_create_mode(&self, width: u16, height: u16) -> Result<randr::Mode> {
+        let name = format!("{}{}x{}", MODE_PREFIX, width, height);
+        let mut ours = None;
+        for (mode, mode_name) in self.modes() {
+            if mode.width != width || mode.height != height {
+                continue;
+            }
+            if self.output_modes.contains(&mode.id) {
+                return Ok(mode.id);
+            }
+            if mode_name == name.as_bytes() {
+                ours = Some(mode.id);
+            }
+        }
+
+        let mode = match ours {
+            Some(mode) => mode,
+            None => {
+                let timings = cvt(width as u32, height as u32, 60);
+                let info = ModeInfo {
+                    id: 0,
+                    width,
+                    height,
+                    dot_clock: (timings.clock * 1_000_000.0) as u32,
+                    hsync_start: timings.h_sync_start as u16,
+                    hsync_end: timings.h_sync_end as u16,
+                    htotal: timings.h_total as u16,
+                    hskew: 0,
+                    vsync_start: timings.v_sync_start as u16,
+                    vsync_end: timings.v_sync_end as u16,
+                    vtotal: timings.v_total as u16,
+                    name_len: name.len() as u16,
+                    mode_flags: ModeFlag::HSYNC_POSITIVE | ModeFlag::VSYNC_NEGATIVE,
+                };
+                self.conn
+                    .randr_create_mode(self.root, info, name.as_bytes())?
+                    .reply()
+                    .with_context(|| format!("Failed to create mode {}", name))?
+                    .mode
+            }
+        };
+        self.conn
+            .randr_add_output_mode(self.output, mode)?
+            .check()
+            .with_context(|| format!("Failed to add mode {} to the output", name))?;
+        Ok(mode)
+    }
+
+    fn apply(&self, mode: randr::Mode, size: ScreenSize) -> Result<()> {
+        let info = &self.crtc_info;
+        let timestamp = self.resources.config_timestamp;
+        // The screen can't shrink while the CRTC still covers the area being cut off
+        if (info.x as i32 + info.width as i32) > size.width as i32
+            || (info.y as i32 + info.height as i32) > size.height as i32
+        {
+            self.set_crtc(timestamp, NONE, &[])?;
+        }
+        self.conn
+            .randr_set_screen_size(
+                self.root,
+                size.width,
+                size.height,
+                size.mm_width,
+                size.mm_height,
+            )?
+            .check()
+            .context("Failed to set the screen size")?;
+        self.set_crtc(timestamp, mode, &info.outputs)
+    }
+
+    fn set_crtc(&self, timestamp: u32, mode: randr::Mode, outputs: &[randr::Output]) -> Result<()> {
+        let info = &self.crtc_info;
+        let reply = self
+            .conn
+            .randr_set_crtc_config(
+                self.crtc,
+                CURRENT_TIME,
+                timestamp,
+                info.x,
+                info.y,
+                mode,
+                info.rotation,
+                outputs,
+            )?
+            .reply()
+            .context("Failed to configure the CRTC")?;
+        if reply.status != randr::SetConfig::SUCCESS {
+            bail!("the X server refused the CRTC configuration");
+        }
+        Ok(())
+    }
+
+    fn remove_created_modes(&self) {
+        for (mode, name) in self.modes() {
+            if !name.starts_with(MODE_PREFIX.as_bytes()) {
+                continue;
+            }
+            let remove = || -> Result<()> {
+                if self.output_modes.contains(&mode.id) {
+                    self.conn
+                        .randr_delete_output_mode(self.output, mode.id)?
+                        .check()?;
+                }
+                self.conn.randr_destroy_mode(mode.id)?.check()?;
+                Ok(())
+            };
+            if let Err(e) = remove() {
+                debug!("Failed to remove mode {}: {:?}", mode.id, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use x11rb::connection::Connection as _;
+use x11rb::errors::ReplyError;
+use x11rb::protocol::xproto::{self, AtomEnum, ConnectionExt as _, MapState};
+use x11rb::rust_connection::RustConnection;
+
+use crate::protocol::Window;
+use crate::rtc::hub::Region;
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        _NET_CLIENT_LIST,
+        _NET_WM_NAME,
+        UTF8_STRING,
+    }
+}
+
+/// Looks up where a captured window is, over a connection of its own.
+pub struct WindowWatcher {
+    conn: Arc<RustConnection>,
+    root: xproto::Window,
+    id: xproto::Window,
+}
+
+impl WindowWatcher {
+    pub async fn open(id: u32) -> Result<Self> {
+        tokio::task::spawn_blocking(move || {
+            let (conn, screen_num) =
+                x11rb::connect(None).context("Failed to connect to the X server")?;
+            let root = conn.setup().roots[screen_num].root;
+            Ok(Self {
+                conn: Arc::new(conn),
+                root,
+                id,
+            })
+        })
+        .await?
+    }
+
+    /// Where the window is on the desktop now, or `None` once it is closed or hidden.
+    pub async fn geometry(&self) -> Result<Option<Region>> {
+        let (conn, root, id) = (self.conn.clone(), self.root, self.id);
+        tokio::task::spawn_blocking(move || geometry(&conn, root, id)).await?
+    }
+}
+
+/// The top-level windows that are showing, as the window manager lists them.
+pub(super) fn windows() -> Result<Vec<Window>> {
+    let (conn, screen_num) = x11rb::connect(None).context("Failed to connect to the X server")?;
+    let root = conn.setup().roots[screen_num].root;
+    let atoms = Atoms::new(&conn)?.reply()?;
+
+    let clients = conn
+        .get_property(
+            false,
+            root,
+            atoms._NET_CLIENT_LIST,
+            AtomEnum::WINDOW,
+            0,
+            u32::MAX,
+        )?
+        .reply()?;
+    // Without a window manager, the children of the root window are the top-level windows
+    let ids: Vec<xproto::Window> = match clients.value32() {
+        Some(ids) if clients.value_len > 0 => ids.collect(),
+        _ => conn.query_tree(root)?.reply()?.children,
+    };
+
+    let mut windows = vec![];
+    for id in ids {
+        let Some(region) = geometry(&conn, root, id)? else {
+            continue;
+        };
+        // Untitled windows are mostly docks, menus and the like
+        let title = title(&conn, &atoms, id).unwrap_or_default();
+        if title.is_empty() {
+            continue;
+        }
+        windows.push(Window {
+            id,
+            title,
+            x: region.x,
+            y: region.y,
+            width: region.width,
+            height: region.height,
+        });
+    }
+    Ok(windows)
+}
+
+fn geometry(
+    conn: &RustConnection,
+    root: xproto::Window,
+    id: xproto::Window,
+) -> Result<Option<Region>> {
+    match query_geometry(conn, root, id) {
+        // The window can go away between any two requests
+        Err(ReplyError::X11Error(_)) => Ok(None),
+        result => Ok(result?),
+    }
+}
+
+fn query_geometry(
+    conn: &RustConnection,
+    root: xproto::Window,
+    id: xproto::Window,
+) -> Result<Option<Region>, ReplyError> {
+    let attributes = conn.get_window_attributes(id)?.reply()?;
+    if attributes.map_state != MapState::VIEWABLE {
+        return Ok(None);
+    }
+    let size = conn.get_geometry(id)?.reply()?;
+    let position = conn.translate_coordinates(id, root, 0, 0)?.reply()?;
+    Ok(Some(Region {
+        x: position.dst_x as i32,
+        y: position.dst_y as i32,
+        width: size.width as u32,
+        height: size.height as u32,
+    }))
+}
+
+/// The window's EWMH title, or its legacy `WM_NAME`.
+fn title(conn: &RustConnection, atoms: &Atoms, id: xproto::Window) -> Result<String> {
+    let name = conn
+        .get_property(false, id, atoms._NET_WM_NAME, atoms.UTF8_STRING, 0, 1024)?
+        .reply()?;
+    if !name.value.is_empty() {
+        return Ok(String::from_utf8_lossy(&name.value).into_owned());
+    }
+    let name = conn
+        .get_property(false, id, AtomEnum::WM_NAME, AtomEnum::STRING, 0, 1024)?
+        .reply()?;
+    // Latin-1 maps one to one onto the first Unicode code points
+    Ok(name.value.iter().map(|&b| b as char).collect())
+}
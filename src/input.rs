@@ -61,11 +61,12 @@ use log::*;
 
 use input_device::{InputSimulator, Key};
 
-use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::*;
 
 use strum::IntoEnumIterator;
 
+use crate::protocol::ClientMessage;
+
 pub fn browser_code_to_key(code: &str) -> Option<Key> {
     match code {
         // --- Top Row (Function Keys) ---
@@ -254,29 +255,14 @@ pub fn browser_code_to_key(code: &str) -> Option<Key> {
 
 #[derive(Debug, Clone)]
 pub enum InputCommand {
-    ClientCommand(ClientCommand),
-    #[allow(unused)]
+    // Absolute coordinates are relative to the given point of the desktop
+    Input(ClientMessage, (i32, i32)),
     ReleaseAll,
+    // The display was restarted, so input has to be simulated on the new one
+    Reconnect,
 }
 
-#[allow(non_snake_case)]
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
-pub struct ClientCommand {
-    pub r#type: String,
-    pub x: Option<i32>,
-    pub y: Option<i32>,
-    pub button: Option<u8>,
-    pub key: Option<String>,
-    pub id: Option<i32>,
-    pub pressure: Option<f64>,
-    pub tiltX: Option<i32>,
-    pub tiltY: Option<i32>,
-
-    // File transfers
-    pub size: Option<u64>,
-}
-
-pub fn do_input(mut rx: Receiver<InputCommand>, startx: i32, starty: i32) -> anyhow::Result<()> {
+pub fn do_input(mut rx: Receiver<InputCommand>) -> anyhow::Result<()> {
     #[cfg(target_os = "windows")]
     let _ = crate::windows_service::sync_thread_desktop();
 
@@ -288,102 +274,86 @@ pub fn do_input(mut rx: Receiver<InputCommand>, startx: i32, starty: i32) -> any
         #[cfg(target_os = "windows")]
         let _ = crate::windows_service::sync_thread_desktop();
 
-        match msg {
-            InputCommand::ClientCommand(ClientCommand {
-                r#type,
-                x: Some(x),
-                y: Some(y),
-                pressure: Some(pressure),
-                tiltX: Some(tilt_x),
-                tiltY: Some(tilt_y),
-                ..
-            }) => {
-                if r#type == "pen" {
-                    sim.pen(
-                        x + startx as i32,
-                        y + starty as i32,
-                        pressure,
-                        tilt_x,
-                        tilt_y,
-                    )
-                    .ok();
+        let (msg, (startx, starty)) = match msg {
+            InputCommand::Input(msg, origin) => (msg, origin),
+            InputCommand::ReleaseAll => {
+                let keys = Key::iter();
+                // Unpress all possible keys
+                for key in keys {
+                    sim.key_up(key).ok();
                 }
+                continue;
             }
-            InputCommand::ClientCommand(ClientCommand {
-                r#type,
-                x: Some(x),
-                y: Some(y),
-                id: Some(id),
-                ..
-            }) => match r#type.as_str() {
-                "touchstart" => {
-                    sim.touch_down(id, x + startx as i32, y + starty as i32)
-                        .ok();
-                }
-                "touchmove" => {
-                    sim.touch_move(id, x + startx as i32, y + starty as i32)
-                        .ok();
+            InputCommand::Reconnect => {
+                match InputSimulator::new() {
+                    Ok(new_sim) => {
+                        sim = new_sim;
+                        // Nothing is held on the new display
+                        held.clear();
+                    }
+                    Err(e) => error!("Failed to reconnect input: {:?}", e),
                 }
-                _ => {}
-            },
-            InputCommand::ClientCommand(ClientCommand {
-                r#type,
-                x: Some(x),
-                y: Some(y),
+                continue;
+            }
+        };
+
+        match msg {
+            ClientMessage::Pen {
+                x,
+                y,
+                pressure,
+                tilt_x,
+                tilt_y,
                 ..
-            }) => match r#type.as_str() {
-                "mousemove" => {
-                    sim.move_mouse_rel(x, y).ok();
+            } => {
+                sim.pen(x + startx, y + starty, pressure, tilt_x, tilt_y)
+                    .ok();
+            }
+            ClientMessage::TouchStart { id, x, y, .. } => {
+                sim.touch_down(id, x + startx, y + starty).ok();
+            }
+            ClientMessage::TouchMove { id, x, y, .. } => {
+                sim.touch_move(id, x + startx, y + starty).ok();
+            }
+            ClientMessage::TouchEnd { id } => {
+                sim.touch_up(id).ok();
+            }
+            ClientMessage::MouseMove { x, y } => {
+                sim.move_mouse_rel(x, y).ok();
+            }
+            ClientMessage::MouseMoveAbs { x, y, .. } => {
+                sim.move_mouse_abs(x + startx, y + starty).ok();
+            }
+            ClientMessage::Wheel { x, y } => {
+                sim.wheel(x, -y).ok();
+            }
+            ClientMessage::MouseDown { button } => match button {
+                0 => {
+                    sim.left_mouse_down().ok();
                 }
-                "mousemoveabs" => {
-                    sim.move_mouse_abs(x + startx as i32, y + starty as i32)
-                        .ok();
+                1 => {
+                    sim.middle_mouse_down().ok();
                 }
-                "wheel" => {
-                    sim.wheel(x, -y).ok();
+                2 => {
+                    sim.right_mouse_down().ok();
                 }
-                _ => {}
+                _ => error!("Received bad mouse button: {}", button),
             },
-            InputCommand::ClientCommand(ClientCommand {
-                r#type,
-                id: Some(id),
-                ..
-            }) => {
-                if r#type.as_str() == "touchend" {
-                    sim.touch_up(id).ok();
-                }
-            }
-            InputCommand::ClientCommand(ClientCommand {
-                r#type,
-                button: Some(button),
-                ..
-            }) => match (button, r#type.as_str()) {
-                (0, "mousedown") => {
-                    sim.left_mouse_down().ok();
-                }
-                (0, "mouseup") => {
+            ClientMessage::MouseUp { button } => match button {
+                0 => {
                     sim.left_mouse_up().ok();
                 }
-                (1, "mousedown") => {
-                    sim.middle_mouse_down().ok();
-                }
-                (1, "mouseup") => {
+                1 => {
                     sim.middle_mouse_up().ok();
                 }
-                (2, "mousedown") => {
-                    sim.right_mouse_down().ok();
-                }
-                (2, "mouseup") => {
+                2 => {
                     sim.right_mouse_up().ok();
                 }
                 _ => error!("Received bad mouse button: {}", button),
             },
-            InputCommand::ClientCommand(ClientCommand {
-                r#type,
-                key: Some(key),
-                ..
-            }) => {
-                let parsed_key = browser_code_to_key(&key);
+            ClientMessage::KeyDown { ref key } | ClientMessage::KeyUp { ref key } => {
+                let is_keyup = matches!(msg, ClientMessage::KeyUp { .. });
+                let parsed_key = browser_code_to_key(key);
                 if let Some(key) = parsed_key {
                     // fix capslock on iPad client
                     if key == Key::CapsLock && last_capslock.elapsed() > Duration::from_millis(250)
@@ -394,21 +364,17 @@ pub fn do_input(mut rx: Receiver<InputCommand>, startx: i32, starty: i32) -> any
                         last_capslock = Instant::now();
                         continue;
                     }
-                    match r#type.as_str() {
-                        "keydown" => {
-                            sim.key_down(key).ok();
-                            held.insert(key);
-                        }
-                        "keyup" => {
-                            sim.key_up(key).ok();
-                            held.remove(&key);
-                        }
-                        _ => error!("Received bad packet type: {}", r#type),
+                    if is_keyup {
+                        sim.key_up(key).ok();
+                        held.remove(&key);
+                    } else {
+                        sim.key_down(key).ok();
+                        held.insert(key);
                     }
 
                     // A SEVERE BUG in Safari means that that any keys that are pressed while Meta is held are never released.
                     // We work around this by specifically ensuring that all numbers, etc. are released when Meta is released.
-                    if (key == Key::LeftMeta || key == Key::RightMeta) && r#type == "keyup" {
+                    if (key == Key::LeftMeta || key == Key::RightMeta) && is_keyup {
                         for key in held.iter() {
                             sim.key_up(*key).ok();
                         }
@@ -418,16 +384,6 @@ pub fn do_input(mut rx: Receiver<InputCommand>, startx: i32, starty: i32) -> any
                     error!("Received unknown key: {}", key);
                 }
             }
-            InputCommand::ClientCommand(ClientCommand {
-                r#type,
-                ..
-            }) if r#type == "releaseall" => {
-                let keys = Key::iter();
-                // Unpress all possible keys
-                for key in keys {
-                    sim.key_up(key).ok();
-                }
-            }
             _ => {}
         }
     }
@@ -1,47 +1,355 @@
+use anyhow::{Context, Result};
+use base64::prelude::*;
+use log::*;
 use rand::{distr::Alphanumeric, Rng};
-use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 
-#[derive(Debug, Clone, Copy, PartialEq, Hash)]
-pub enum Permissions {
+/// One thing a session may do. Sessions hold a set of these, see [`Permissions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    ViewVideo,
+    HearAudio,
+    Keyboard,
+    Mouse,
+    TouchPen,
+    ClipboardRead,
+    ClipboardWrite,
+    FileUpload,
+    FileDownload,
+    // Resize the host's screen to fit the client, which every other session sees too
+    ResizeDisplay,
+    // List the host's windows, including hidden ones, and capture them on their own
+    CaptureWindows,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 11] = [
+        Capability::ViewVideo,
+        Capability::HearAudio,
+        Capability::Keyboard,
+        Capability::Mouse,
+        Capability::TouchPen,
+        Capability::ClipboardRead,
+        Capability::ClipboardWrite,
+        Capability::FileUpload,
+        Capability::FileDownload,
+        Capability::ResizeDisplay,
+        Capability::CaptureWindows,
+    ];
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::ViewVideo => "view_video",
+            Capability::HearAudio => "hear_audio",
+            Capability::Keyboard => "keyboard",
+            Capability::Mouse => "mouse",
+            Capability::TouchPen => "touch_pen",
+            Capability::ClipboardRead => "clipboard_read",
+            Capability::ClipboardWrite => "clipboard_write",
+            Capability::FileUpload => "file_upload",
+            Capability::FileDownload => "file_download",
+            Capability::ResizeDisplay => "resize_display",
+            Capability::CaptureWindows => "capture_windows",
+        }
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The set of capabilities a session was granted.
+///
+/// Serialized as a list of capabilities. The presets `"view_only"` and `"full_control"` are
+/// accepted too, which is also how tokens stored before capabilities existed are read.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(from = "PermissionsRepr", into = "Vec<Capability>")]
+pub struct Permissions(u16);
+
+impl Permissions {
+    pub const VIEW_ONLY: Permissions =
+        Permissions((1 << Capability::ViewVideo as u16) | (1 << Capability::HearAudio as u16));
+    pub const FULL_CONTROL: Permissions = Permissions((1 << Capability::ALL.len()) - 1);
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    /// Whether any of `capabilities` is granted. An empty list needs no capability at all.
+    pub fn has_any(&self, capabilities: &[Capability]) -> bool {
+        capabilities.is_empty() || capabilities.iter().any(|c| self.has(*c))
+    }
+
+    pub fn intersection(&self, other: Permissions) -> Permissions {
+        Permissions(self.0 & other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Capability> + '_ {
+        Capability::ALL.into_iter().filter(|c| self.has(*c))
+    }
+}
+
+impl FromIterator<Capability> for Permissions {
+    fn from_iter<I: IntoIterator<Item = Capability>>(iter: I) -> Self {
+        Permissions(iter.into_iter().fold(0, |bits, c| bits | c.bit()))
+    }
+}
+
+impl std::fmt::Debug for Permissions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl From<Permissions> for Vec<Capability> {
+    fn from(permissions: Permissions) -> Self {
+        permissions.iter().collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PermissionsRepr {
+    Preset(Preset),
+    Capabilities(Vec<Capability>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Preset {
     ViewOnly,
     FullControl,
 }
 
+impl From<PermissionsRepr> for Permissions {
+    fn from(repr: PermissionsRepr) -> Self {
+        match repr {
+            PermissionsRepr::Preset(Preset::ViewOnly) => Permissions::VIEW_ONLY,
+            PermissionsRepr::Preset(Preset::FullControl) => Permissions::FULL_CONTROL,
+            PermissionsRepr::Capabilities(capabilities) => capabilities.into_iter().collect(),
+        }
+    }
+}
+
+/// Everything about a token except its secret.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub id: String,
+    pub label: String,
+    pub permissions: Permissions,
+    // Unix timestamps, in seconds
+    pub created: u64,
+    pub expires: Option<u64>,
+    // None means the token can be used any number of times
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+impl Token {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| now >= expires)
+    }
+
+    fn is_exhausted(&self) -> bool {
+        self.max_uses.is_some_and(|max_uses| self.uses >= max_uses)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredToken {
+    #[serde(flatten)]
+    token: Token,
+    secret_hash: String,
+}
+
+/// Access tokens, persisted to a JSON file.
+///
+/// Clients present tokens as `<id>.<secret>`. Only a SHA-256 hash of the secret is stored: the
+/// secrets are long random strings, so a slow password hash would add nothing.
 #[derive(Debug)]
 pub struct Keys {
-    map: HashMap<String, (Permissions, Instant)>,
+    path: PathBuf,
+    tokens: Vec<StoredToken>,
+    // Bumped on every save, so a slow write can't replace a newer one
+    generation: u64,
+    // The generation on disk, locked while writing
+    saved: Arc<Mutex<u64>>,
 }
 
 impl Keys {
-    pub fn new() -> Self {
-        Self {
-            map: HashMap::new(),
-        }
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let tokens = if path.exists() {
+            serde_json::from_str(
+                &std::fs::read_to_string(&path).context("Failed to read token file")?,
+            )
+            .context("Failed to parse token file")?
+        } else {
+            vec![]
+        };
+        let mut keys = Self {
+            path,
+            tokens,
+            generation: 0,
+            saved: Arc::new(Mutex::new(0)),
+        };
+        keys.remove_old_keys();
+        Ok(keys)
+    }
+
+    /// Writes the tokens on a blocking thread, so callers can hold the lock around `Keys`.
+    fn save(&mut self) {
+        let data = match serde_json::to_vec_pretty(&self.tokens) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to save tokens to {}: {:?}", self.path.display(), e);
+                return;
+            }
+        };
+        self.generation += 1;
+        let generation = self.generation;
+        let path = self.path.clone();
+        let saved = self.saved.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut saved = saved.lock().unwrap();
+            if *saved > generation {
+                return;
+            }
+            match write_private(&path, &data) {
+                Ok(()) => *saved = generation,
+                Err(e) => error!("Failed to save tokens to {}: {:?}", path.display(), e),
+            }
+        });
     }
 
     pub fn remove_old_keys(&mut self) {
-        // Keep keys younger than 1 hr
-        self.map.retain(|_, &mut (_, creation_date)| {
-            creation_date.elapsed() < Duration::from_secs(3600)
+        let now = unix_now();
+        let before = self.tokens.len();
+        self.tokens
+            .retain(|stored| !stored.token.is_expired(now) && !stored.token.is_exhausted());
+        if self.tokens.len() != before {
+            self.save();
+        }
+    }
+
+    /// Create a token, returning it along with the string clients present it as.
+    pub fn create_token(
+        &mut self,
+        label: String,
+        permissions: Permissions,
+        expires_in: Option<u64>,
+        max_uses: Option<u32>,
+    ) -> (Token, String) {
+        self.remove_old_keys();
+        let now = unix_now();
+        let secret = random_string(32);
+        let token = Token {
+            id: random_string(8),
+            label,
+            permissions,
+            created: now,
+            expires: expires_in.map(|secs| now + secs),
+            max_uses,
+            uses: 0,
+        };
+        self.tokens.push(StoredToken {
+            token: token.clone(),
+            secret_hash: hash_secret(&secret),
         });
+        self.save();
+        let presented = format!("{}.{}", token.id, secret);
+        (token, presented)
     }
 
+    /// The original keys: usable once, within an hour of creation.
     pub fn create_key(&mut self, permissions: Permissions) -> String {
-        let key: String = rand::rng()
-            .sample_iter(&Alphanumeric)
-            .take(32)
-            .map(char::from)
-            .collect();
-        self.map.insert(key.clone(), (permissions, Instant::now()));
+        self.create_token("one-time key".to_string(), permissions, Some(3600), Some(1))
+            .1
+    }
+
+    pub fn list(&mut self) -> Vec<Token> {
         self.remove_old_keys();
-        key
+        self.tokens
+            .iter()
+            .map(|stored| stored.token.clone())
+            .collect()
+    }
+
+    pub fn revoke(&mut self, id: &str) -> bool {
+        let before = self.tokens.len();
+        self.tokens.retain(|stored| stored.token.id != id);
+        let revoked = self.tokens.len() != before;
+        if revoked {
+            self.save();
+        }
+        revoked
     }
 
     pub fn use_key(&mut self, key: &str) -> Option<Permissions> {
         self.remove_old_keys();
-        self.map
-            .remove(&String::from(key))
-            .map(|(permissions, _)| permissions)
+        let (id, secret) = key.split_once('.')?;
+        let stored = self
+            .tokens
+            .iter_mut()
+            .find(|stored| stored.token.id == id)?;
+        if !bool::from(
+            hash_secret(secret)
+                .as_bytes()
+                .ct_eq(stored.secret_hash.as_bytes()),
+        ) {
+            return None;
+        }
+        stored.token.uses += 1;
+        let permissions = stored.token.permissions;
+        // Persist the new use count, dropping the token if that was its last use
+        self.save();
+        self.remove_old_keys();
+        Some(permissions)
     }
 }
+
+fn hash_secret(secret: &str) -> String {
+    BASE64_STANDARD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Write-then-rename so a crash can't leave a truncated file behind.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    // Left behind by a crash, maybe with looser permissions
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Tokens grant access, nobody else has any business reading them
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp)?.write_all(data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn random_string(len: usize) -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
@@ -55,10 +55,11 @@
  */
 
 use std::{
+    collections::BTreeMap,
     fmt::Display,
-    net::SocketAddr,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
     time::{Instant, Duration},
 };
 
@@ -67,10 +68,10 @@ use log::*;
 use network_interface::{NetworkInterface, NetworkInterfaceConfig};
 use tokio::net::{TcpListener, UdpSocket};
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Context, Result};
 
 use axum::{
-    extract::{ConnectInfo, State},
+    extract::{ConnectInfo, FromRef, State},
     http::StatusCode,
     response::{IntoResponse, Response},
     routing::{get, post},
@@ -89,20 +90,40 @@ use str0m::{
 
 use base64::prelude::*;
 
+use clap::Parser;
+
 use tokio::{
     spawn,
     sync::mpsc::*,
 };
 
+use auth::{Auth, AuthError};
+use cli::{Cli, Command, Overrides};
+use clipboard::{do_clipboard, Clipboard};
 use dialogs::*;
-use input::{do_input, ClientCommand, InputCommand};
+use display::{Resolution, VirtualDisplayConfig};
+use input::{do_input, InputCommand};
 use keys::{Keys, Permissions};
-
+use rtc::adaptation::AdaptationConfig;
+use rtc::codec::VideoCodec;
+use rtc::transfer::ResumeStore;
+use sessions::{SessionInfo, Sessions};
+use tls::CertPaths;
+
+mod auth;
+mod cli;
+mod clipboard;
 mod dialogs;
+mod display;
 mod input;
 pub mod keys;
+mod metrics;
+mod protocol;
 mod rtc;
+mod sessions;
+mod settings;
 mod stun;
+mod tls;
 
 // This module contains all code related to Windows service functionality
 #[cfg(target_os = "windows")]
@@ -137,6 +158,9 @@ struct CreateOffer {
     show_mouse: bool,
     #[serde(default)]
     low_power_mode: bool,
+    // Ask the server to record this session even if `record_sessions` is off
+    #[serde(default)]
+    record: bool,
 }
 
 #[derive(Serialize)]
@@ -206,6 +230,10 @@ fn fix_tcp_candidates(sdp: &str) -> String {
     result
 }
 
+fn offer_auth_error(e: AuthError) -> (StatusCode, Json<ResponseOffer>) {
+    (e.status(), Json(ResponseOffer::Error(e.message())))
+}
+
 async fn offer(
     State(state): State<AppState>,
     ConnectInfo(req_addr): ConnectInfo<SocketAddr>,
@@ -213,22 +241,29 @@ async fn offer(
 ) -> Result<(StatusCode, Json<ResponseOffer>), AppError> {
     info!("Received offer from {}", req_addr);
     let permissions = if let Some(ref password) = payload.password {
-        if *password != state.config.password {
-            return Ok((
-                StatusCode::UNAUTHORIZED,
-                Json(ResponseOffer::Error("Password incorrect.".to_string())),
-            ));
-        } else {
-            Permissions::FullControl
+        match state
+            .auth
+            .authenticate(req_addr.ip(), password, "/offer")
+            .await
+        {
+            Ok(()) => state.config.password_permissions,
+            Err(e) => return Ok(offer_auth_error(e)),
         }
     } else if let Some(ref key) = payload.key {
-        if let Some(key_permissions) = state.keys.lock().unwrap().use_key(key.as_str()) {
+        if let Err(e) = state.auth.begin_attempt(req_addr.ip()) {
+            return Ok(offer_auth_error(e));
+        }
+        let key_permissions = state.keys.lock().unwrap().use_key(key.as_str());
+        if let Some(key_permissions) = key_permissions {
+            state.auth.record_success(req_addr.ip());
             info!(
                 "Authenticated key {} with permissions: {:?}",
-                key, key_permissions
+                key.split('.').next().unwrap_or_default(),
+                key_permissions
             );
             key_permissions
         } else {
+            state.auth.record_failure(req_addr.ip(), "/offer");
             return Ok((
                 StatusCode::UNAUTHORIZED,
                 Json(ResponseOffer::Error("Bad session.".to_string())),
@@ -243,6 +278,56 @@ async fn offer(
         ));
     };
 
+    let permissions = if state.config.ask_approval {
+        let description = format!(
+            "{} wants to connect with these permissions:\n{}",
+            req_addr.ip(),
+            permissions
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let approval = spawn_approval_dialog(
+            &state.dialog_tx,
+            description,
+            Duration::from_secs(state.config.approval_timeout),
+            state.config.approval_default,
+        )
+        .await;
+        info!("Connection from {} was answered with {:?}", req_addr, approval);
+        match approval {
+            Approval::Allow => permissions,
+            Approval::ViewOnly => permissions.intersection(Permissions::VIEW_ONLY),
+            Approval::Deny => {
+                return Ok((
+                    StatusCode::FORBIDDEN,
+                    Json(ResponseOffer::Error(
+                        "The host denied the connection.".to_string(),
+                    )),
+                ));
+            }
+        }
+    } else {
+        permissions
+    };
+
+    let desc_data = BASE64_STANDARD.decode(payload.offer.clone())?;
+    let desc_data = std::str::from_utf8(&desc_data)?;
+    let their_offer = serde_json::from_str::<SdpOffer>(desc_data)?;
+
+    let Some(codec) =
+        VideoCodec::negotiate(&state.config.video_codecs, &their_offer.to_sdp_string())
+    else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            Json(ResponseOffer::Error(
+                "None of the configured video codecs were offered.".to_string(),
+            )),
+        ));
+    };
+    info!("Negotiated video codec: {}", codec);
+
     let mut exts = ExtensionMap::empty();
     exts.set(1, Extension::AudioLevel);
     exts.set(2, Extension::AbsoluteSendTime);
@@ -253,9 +338,8 @@ async fn offer(
     exts.set(11, Extension::RepairedRtpStreamId);
     exts.set(13, Extension::VideoOrientation);
 
-    let rtc = Rtc::builder()
-        .clear_codecs()
-        .enable_h264(true)
+    let rtc = codec
+        .enable(Rtc::builder().clear_codecs())
         .enable_opus(true)
         // needed for zero-latency streaming
         .set_extension_map(exts)
@@ -354,9 +438,6 @@ async fn offer(
 
     // Accept an incoming offer from the remote peer
     // and get the corresponding answer.
-    let desc_data = BASE64_STANDARD.decode(payload.offer.clone())?;
-    let desc_data = std::str::from_utf8(&desc_data)?;
-    let their_offer = serde_json::from_str::<SdpOffer>(desc_data)?;
     let answer = rtc.sdp_api().accept_offer(their_offer)?;
 
     // Munge
@@ -369,6 +450,15 @@ async fn offer(
     let json_str = answer.to_string();
     let b64 = BASE64_STANDARD.encode(&json_str);
 
+    let session = state.sessions.register(
+        keys::random_string(16),
+        req_addr,
+        permissions,
+        codec,
+    );
+    info!("Starting session {} for {}", session.id(), req_addr);
+
+    let session_id = session.id().to_string();
     let state_cloned = state.clone();
     spawn(async move {
         //spawn_message_dialog(&state_cloned.dialog_tx, "Tenebra Alert", "New connection received!", rfd::MessageLevel::Info).await;
@@ -381,6 +471,8 @@ async fn offer(
             state_cloned,
             payload,
             permissions,
+            session,
+            codec,
         )
         .await
         {
@@ -389,6 +481,11 @@ async fn offer(
             info!("Run task exited gracefully.");
         }
 
+        // Undo the resize if this session was the one that set the current resolution
+        if state.resolution.release(&session_id).await {
+            state.hub.restart_video().await;
+        }
+
         if let Some((gateway, port)) = gateway_and_port {
             info!("Removing port mapping {}.", port);
             gateway
@@ -417,24 +514,172 @@ struct CreateKeyRequest {
 
 async fn create_key(
     State(state): State<AppState>,
+    ConnectInfo(req_addr): ConnectInfo<SocketAddr>,
     Json(payload): Json<CreateKeyRequest>,
-) -> Result<(StatusCode, String), AppError> {
-    if payload.password == state.config.password {
-        let key = state.keys.lock().unwrap().create_key(if payload.view_only {
-            Permissions::ViewOnly
-        } else {
-            Permissions::FullControl
-        });
-        info!("Registering new key: {}", key);
-        Ok((StatusCode::OK, key))
+) -> Result<(StatusCode, String), AuthError> {
+    state
+        .auth
+        .authenticate(req_addr.ip(), &payload.password, "/create_key")
+        .await?;
+    let key = state.keys.lock().unwrap().create_key(if payload.view_only {
+        Permissions::VIEW_ONLY
+    } else {
+        Permissions::FULL_CONTROL
+    });
+    // Only the ID, the secret part must not end up in logs
+    info!(
+        "Registering new key: {}",
+        key.split('.').next().unwrap_or_default()
+    );
+    Ok((StatusCode::OK, key))
+}
+
+#[derive(Deserialize, Clone)]
+struct CreateTokenRequest {
+    password: String,
+    label: String,
+    permissions: Permissions,
+    // Seconds until the token expires, never if absent
+    expires_in: Option<u64>,
+    // Unlimited if absent
+    max_uses: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    info: keys::Token,
+    token: String,
+}
+
+async fn create_token(
+    State(state): State<AppState>,
+    ConnectInfo(req_addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<CreateTokenRequest>,
+) -> Result<Json<CreatedToken>, AuthError> {
+    state
+        .auth
+        .authenticate(req_addr.ip(), &payload.password, "/tokens")
+        .await?;
+    let (info, token) = state.keys.lock().unwrap().create_token(
+        payload.label,
+        payload.permissions,
+        payload.expires_in,
+        payload.max_uses,
+    );
+    info!("Created token {} ({})", info.id, info.label);
+    Ok(Json(CreatedToken { info, token }))
+}
+
+#[derive(Deserialize, Clone)]
+struct ListTokensRequest {
+    password: String,
+}
+
+async fn list_tokens(
+    State(state): State<AppState>,
+    ConnectInfo(req_addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ListTokensRequest>,
+) -> Result<Json<Vec<keys::Token>>, AuthError> {
+    state
+        .auth
+        .authenticate(req_addr.ip(), &payload.password, "/tokens/list")
+        .await?;
+    Ok(Json(state.keys.lock().unwrap().list()))
+}
+
+#[derive(Deserialize, Clone)]
+struct RevokeTokenRequest {
+    password: String,
+    id: String,
+}
+
+async fn revoke_token(
+    State(state): State<AppState>,
+    ConnectInfo(req_addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<RevokeTokenRequest>,
+) -> Result<StatusCode, AuthError> {
+    state
+        .auth
+        .authenticate(req_addr.ip(), &payload.password, "/tokens/revoke")
+        .await?;
+    if state.keys.lock().unwrap().revoke(&payload.id) {
+        info!("Revoked token {}", payload.id);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct ListSessionsRequest {
+    password: String,
+}
+
+async fn list_sessions(
+    State(state): State<AppState>,
+    ConnectInfo(req_addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<ListSessionsRequest>,
+) -> Result<Json<Vec<SessionInfo>>, AuthError> {
+    state
+        .auth
+        .authenticate(req_addr.ip(), &payload.password, "/sessions/list")
+        .await?;
+    Ok(Json(state.sessions.list()))
+}
+
+#[derive(Deserialize, Clone)]
+struct SessionRequest {
+    password: String,
+    id: String,
+}
+
+async fn inspect_session(
+    State(state): State<AppState>,
+    ConnectInfo(req_addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<SessionRequest>,
+) -> Result<(StatusCode, Json<Option<SessionInfo>>), AuthError> {
+    state
+        .auth
+        .authenticate(req_addr.ip(), &payload.password, "/sessions/inspect")
+        .await?;
+    match state.sessions.get(&payload.id) {
+        Some(info) => Ok((StatusCode::OK, Json(Some(info)))),
+        None => Ok((StatusCode::NOT_FOUND, Json(None))),
+    }
+}
+
+async fn kick_session(
+    State(state): State<AppState>,
+    ConnectInfo(req_addr): ConnectInfo<SocketAddr>,
+    Json(payload): Json<SessionRequest>,
+) -> Result<StatusCode, AuthError> {
+    state
+        .auth
+        .authenticate(req_addr.ip(), &payload.password, "/sessions/kick")
+        .await?;
+    if state.sessions.kick(&payload.id) {
+        info!("Terminating session {} at {}'s request", payload.id, req_addr);
+        Ok(StatusCode::NO_CONTENT)
     } else {
-        Err(AppError(anyhow!("Password incorrect.")))
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn metrics(State(state): State<AppState>) -> (StatusCode, String) {
+    if !state.config.metrics {
+        return (StatusCode::NOT_FOUND, String::new());
     }
+    (StatusCode::OK, metrics::render(&state.sessions.list()))
 }
 
 async fn home(State(state): State<AppState>) -> String {
     let mut out = String::new();
     out.push_str("This is a Telewindow server powered by the Tenebra project. https://github.com/UE2020/tenebra/\n\n");
+    out.push_str(&format!(
+        "Certificate fingerprint (SHA-256): {}\n\n",
+        state.fingerprint.read().unwrap()
+    ));
     out.push_str(&format!("{}\n", state.config));
     out.push_str(include_str!("notice.txt"));
     out
@@ -446,9 +691,34 @@ pub struct AppState {
     dialog_tx: Sender<Dialog>,
     ports: Arc<Mutex<Vec<u16>>>,
     keys: Arc<Mutex<Keys>>,
+    auth: Arc<Auth>,
+    sessions: Arc<Sessions>,
+    transfers: Arc<Mutex<ResumeStore>>,
+    hub: Arc<rtc::hub::MediaHub>,
+    resolution: Arc<Resolution>,
+    clipboard: Clipboard,
+    // Of the certificate being served, which changes when it is reloaded
+    fingerprint: Arc<RwLock<String>>,
     config: Config,
 }
 
+/// What the router holds. Each request gets an `AppState` with the configuration as it is at
+/// that moment, and the session it starts keeps that even when the file is reloaded.
+#[derive(Debug, Clone)]
+struct LiveState {
+    app: AppState,
+    config: Arc<RwLock<Config>>,
+}
+
+impl FromRef<LiveState> for AppState {
+    fn from_ref(live: &LiveState) -> Self {
+        AppState {
+            config: live.config.read().unwrap().clone(),
+            ..live.app.clone()
+        }
+    }
+}
+
 #[allow(unused)]
 #[derive(Deserialize, Clone, Debug)]
 struct Config {
@@ -465,7 +735,24 @@ struct Config {
     windows_quality_vs_speed: Option<u32>,
 
     port: u16,
+    // The address to listen on, all of them by default
+    #[serde(default = "default_bind")]
+    bind: IpAddr,
+    #[serde(default)]
     password: String,
+    // A PHC string (argon2 or scrypt), takes precedence over `password`
+    password_hash: Option<String>,
+    // What sessions authenticated with the password may do
+    #[serde(default = "default_password_permissions")]
+    password_permissions: Permissions,
+    // Ask the local user before letting anyone in
+    #[serde(default)]
+    ask_approval: bool,
+    #[serde(default = "default_approval_timeout")]
+    approval_timeout: u64,
+    // Used when nobody answers in time, or there is no desktop to ask on
+    #[serde(default = "default_approval_default")]
+    approval_default: Approval,
     sound_forwarding: bool,
     #[serde(alias = "hwencode")]
     vaapi: bool,
@@ -475,8 +762,39 @@ struct Config {
     tcp_upnp: bool,
     #[serde(default = "default_vbv_buf_capacity")]
     vbv_buf_capacity: u32,
-    cert: PathBuf,
-    key: PathBuf,
+    #[serde(default = "default_clipboard_max_size")]
+    clipboard_max_size: usize,
+    // Preference order of video codecs, the first one the client also supports is used
+    #[serde(default = "default_video_codecs")]
+    video_codecs: Vec<VideoCodec>,
+    // Expose Prometheus metrics at /metrics, without authentication
+    #[serde(default)]
+    metrics: bool,
+    #[serde(default)]
+    record_sessions: bool,
+    #[serde(default = "default_recording_dir")]
+    recording_dir: PathBuf,
+    // Uploads are saved here instead of asking the host where to put them
+    drop_dir: Option<PathBuf>,
+    // Directories clients may browse and download from, by the name they are shown as
+    #[serde(default)]
+    shared_dirs: BTreeMap<String, PathBuf>,
+    // How many file transfers of one session move data at once, the rest are queued
+    #[serde(default = "default_transfer_parallelism")]
+    transfer_parallelism: usize,
+    // Linux-only: run our own X server instead of capturing an existing one
+    virtual_display: Option<VirtualDisplayConfig>,
+    // Linux-only: let clients resize the screen to fit their viewport
+    #[serde(default)]
+    dynamic_resolution: bool,
+    // Linux-only: skip encoding frames while nothing on the screen changes
+    #[serde(default)]
+    damage_capture: bool,
+    // Lower the framerate, then the resolution, when bandwidth runs short
+    adaptation: Option<AdaptationConfig>,
+    // Both or neither, a self-signed certificate in the config directory is used without them
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
 }
 
 impl Display for Config {
@@ -489,6 +807,7 @@ impl Display for Config {
         writeln!(f, "\tEnd x-coordinate:                  {:?}", self.endx)?;
         writeln!(f, "\tEnd y-coordinate:                  {:?}", self.endy)?;
         writeln!(f, "\tPort:                              {}", self.port)?;
+        writeln!(f, "\tListen address:                    {}", self.bind)?;
         writeln!(f, "\tSound forwarding:                  {}", bool_to_str(self.sound_forwarding))?;
         writeln!(f, "\tHardware accelerated encoding:     {}", bool_to_str(self.vaapi))?;
         writeln!(f, "\tVA-API format conversion:          {}", bool_to_str(self.vapostproc))?;
@@ -496,11 +815,28 @@ impl Display for Config {
         writeln!(f, "\tFull color encoding:               {}", bool_to_str(self.full_chroma))?;
         writeln!(f, "\tAutomatic ICE-TCP UPnP forwarding: {}", bool_to_str(self.tcp_upnp))?;
         writeln!(f, "\tVBV Buffer capacity:               {} ms", self.vbv_buf_capacity)?;
+        writeln!(f, "\tClipboard size limit:              {} bytes", self.clipboard_max_size)?;
+        writeln!(f, "\tAsk before accepting connections:  {}", bool_to_str(self.ask_approval))?;
+        writeln!(f, "\tVideo codecs:                      {}", self.video_codecs.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(", "))?;
+        writeln!(f, "\tRecord all sessions:               {}", bool_to_str(self.record_sessions))?;
+        writeln!(f, "\tUpload drop directory:             {}", self.drop_dir.as_ref().map(|dir| dir.display().to_string()).unwrap_or_else(|| "off".to_string()))?;
+        writeln!(f, "\tShared directories:                {}", self.shared_dirs.len())?;
+        writeln!(f, "\tParallel file transfers:           {}", self.transfer_parallelism)?;
+        writeln!(f, "\tVirtual display:                   {}", self.virtual_display.as_ref().map(|display| display.to_string()).unwrap_or_else(|| "off".to_string()))?;
+        writeln!(f, "\tDynamic resolution:                {}", bool_to_str(self.dynamic_resolution))?;
+        writeln!(f, "\tDamage-driven capture:             {}", bool_to_str(self.damage_capture))?;
+        writeln!(f, "\tBandwidth adaptation:              {}", self.adaptation.as_ref().map(|adaptation| adaptation.to_string()).unwrap_or_else(|| "off".to_string()))?;
 
         Ok(())
     }
 }
 
+impl Config {
+    fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
 fn bool_to_str(b: bool) -> &'static str {
     match b {
         true => "on",
@@ -508,47 +844,209 @@ fn bool_to_str(b: bool) -> &'static str {
     }
 }
 
+fn default_bind() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
 fn default_vbv_buf_capacity() -> u32 {
     120
 }
 
+fn default_clipboard_max_size() -> usize {
+    1024 * 1024
+}
+
+fn default_transfer_parallelism() -> usize {
+    2
+}
+
+fn default_approval_timeout() -> u64 {
+    30
+}
+
+fn default_approval_default() -> Approval {
+    Approval::Deny
+}
+
+fn default_password_permissions() -> Permissions {
+    Permissions::FULL_CONTROL
+}
+
+fn default_video_codecs() -> Vec<VideoCodec> {
+    vec![VideoCodec::H264]
+}
+
+fn default_recording_dir() -> PathBuf {
+    PathBuf::from("recordings")
+}
+
 #[cfg(target_os = "windows")]
 fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    let option: Option<&str> = args.get(1).map(|s| s.as_str());
-    match option {
-        Some("--console") => {
-            tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(crate::entrypoint())?;
+    let cli = Cli::parse();
+    // The service manager starts us without a command, the service then runs `--console`
+    if cli.command.is_none() {
+        return windows_service::run();
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(cli))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    // `DISPLAY` can only be changed safely while this is the only thread
+    #[cfg(target_os = "linux")]
+    if matches!(cli.command, None | Some(Command::Serve)) {
+        if let Some(virtual_display) = load_config(&cli).ok().and_then(|c| c.virtual_display) {
+            display::claim(&virtual_display)?;
         }
-        Some("--version") => {
-            println!("v{}", env!("CARGO_PKG_VERSION"));
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    match &cli.command {
+        None | Some(Command::Serve) => entrypoint(&cli).await,
+        Some(Command::InitConfig { force }) => init_config(&config_path(&cli)?, *force),
+        Some(Command::CreateKey { view_only }) => {
+            let config = load_config(&cli)?;
+            let cert = CertPaths::new(&config, &state_dir(&config_path(&cli)?)).cert;
+            cli::create_key(&config, &cert, &admin_password(&cli)?, *view_only).await
         }
-        _ => {
-            windows_service::run()?;
+        Some(Command::ListSessions) => {
+            let config = load_config(&cli)?;
+            let cert = CertPaths::new(&config, &state_dir(&config_path(&cli)?)).cert;
+            cli::list_sessions(&config, &cert, &admin_password(&cli)?).await
         }
+        Some(Command::GenCert { names, force }) => gen_cert(&cli, names, *force),
+        Some(Command::HashPassword) => print_password_hash(),
+        Some(Command::CheckConfig) => check_config(&cli),
     }
+}
+
+/// Read a password from stdin and print a `password_hash` for it.
+fn print_password_hash() -> Result<()> {
+    let password = cli::read_password("Enter the new password:")?;
+    println!("password_hash = \"{}\"", auth::hash_password(&password)?);
     Ok(())
 }
 
-#[cfg(not(target_os = "windows"))]
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    let option: Option<&str> = args.get(1).map(|s| s.as_str());
-    match option {
-        Some("--version") => {
-            println!("v{}", env!("CARGO_PKG_VERSION"));
-            Ok(())
+/// The password to sign in to the running server with.
+fn admin_password(cli: &Cli) -> Result<String> {
+    match &cli.overrides.password {
+        Some(password) => Ok(password.clone()),
+        None => cli::read_password("Enter the admin password:"),
+    }
+}
+
+/// Validate the config file and print everything that is wrong with it.
+fn check_config(cli: &Cli) -> Result<()> {
+    let path = config_path(cli)?;
+    let config = load_config(cli)?;
+    let diagnostics = settings::check(&config);
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    if diagnostics.iter().any(|d| d.is_error()) {
+        bail!("{} is invalid", path.display());
+    }
+    println!("{} is valid.", path.display());
+    Ok(())
+}
+
+/// Write the default config file to `path`.
+fn init_config(path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        bail!("{} exists, use --force to replace it", path.display());
+    }
+    write_default_config(path)?;
+    println!("Wrote the default configuration to {}.", path.display());
+    Ok(())
+}
+
+fn write_default_config(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).context("Failed to create config directory")?;
+    }
+    std::fs::write(path, include_bytes!("default.toml")).context("Failed to write default config")
+}
+
+/// Generate a self-signed certificate where the server looks for one.
+fn gen_cert(cli: &Cli, names: &[String], force: bool) -> Result<()> {
+    let (cert, key) = match &cli.overrides {
+        Overrides {
+            cert: Some(cert),
+            key: Some(key),
+            ..
+        } => (cert.clone(), key.clone()),
+        _ => {
+            let config_path = config_path(cli)?;
+            let paths = CertPaths::new(&load_config(cli)?, &state_dir(&config_path));
+            (paths.cert, paths.key)
+        }
+    };
+    for path in [&cert, &key] {
+        if path.exists() && !force {
+            bail!("{} exists, use --force to replace it", path.display());
         }
-        _ => entrypoint().await,
     }
+    let names = match names {
+        [] => tls::default_names(),
+        names => names.to_vec(),
+    };
+    tls::generate(names, &cert, &key)?;
+    println!("Wrote {} and {}.", cert.display(), key.display());
+    println!(
+        "Certificate fingerprint (SHA-256): {}",
+        tls::fingerprint(&cert)?
+    );
+    Ok(())
+}
+
+/// The config file, as given on the command line or in the config directory.
+fn config_path(cli: &Cli) -> Result<PathBuf> {
+    match &cli.config {
+        Some(path) => Ok(path.clone()),
+        None => Ok(config_dir()?.join("config.toml")),
+    }
+}
+
+/// Where tokens, transfers and the generated certificate are kept: next to the config file.
+fn state_dir(config_path: &Path) -> PathBuf {
+    match config_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// The config file with the command line and environment overrides applied, but not validated.
+fn load_config(cli: &Cli) -> Result<Config> {
+    let mut config = settings::load(&config_path(cli)?)?;
+    cli.overrides.apply(&mut config);
+    Ok(config)
+}
+
+fn config_dir() -> Result<PathBuf> {
+    #[cfg(not(target_os = "windows"))]
+    let dir = dirs::config_dir()
+        .context("Failed to find config directory")?
+        .join("tenebra");
+
+    #[cfg(target_os = "windows")]
+    let dir = PathBuf::from("C:\\tenebra");
+
+    std::fs::create_dir_all(&dir).context("Failed to create config directory")?;
+    Ok(dir)
 }
 
-async fn entrypoint() -> Result<()> {
+async fn entrypoint(cli: &Cli) -> Result<()> {
     pretty_env_logger::init_timed();
 
     // WinCrypto simplifies build significantly on Windows
@@ -583,68 +1081,120 @@ async fn entrypoint() -> Result<()> {
     gstreamer::init().unwrap();
 
     // get the config path
-    #[cfg(not(target_os = "windows"))]
-    let mut config_path = dirs::config_dir()
-        .context("Failed to find config directory")?
-        .join("tenebra");
-    #[cfg(not(target_os = "windows"))]
-    std::fs::create_dir_all(&config_path).context("Failed to create config directory")?;
-
-    #[cfg(not(target_os = "windows"))]
-    config_path.push("config.toml");
-
-    #[cfg(target_os = "windows")]
-    let config_path = std::path::Path::new("C:\\tenebra\\config.toml");
+    let config_path = config_path(cli)?;
+    let config_dir = state_dir(&config_path);
 
     if !config_path.exists() {
-        std::fs::write(&config_path, include_bytes!("default.toml"))
-            .context("Failed to write default config")?;
+        write_default_config(&config_path)?;
         bail!("No config file found. The default configuration file has been copied to {}. Before running Tenebra again, populate the config file.", config_path.display());
     }
 
     // read the config
-    let config: Config = toml::from_str(
-        &std::fs::read_to_string(config_path).context("Failed to read config file")?,
-    )
-    .context("Failed to parse config file")?;
+    let config = load_config(cli)?;
+    settings::ensure_valid(&config)?;
 
     println!("{}", config);
 
+    let cert_paths = CertPaths::new(&config, &config_dir);
+    cert_paths.ensure()?;
+    let fingerprint = Arc::new(RwLock::new(tls::fingerprint(&cert_paths.cert)?));
+    println!(
+        "Certificate fingerprint (SHA-256): {}",
+        fingerprint.read().unwrap()
+    );
+
+    let display_restarted = match &config.virtual_display {
+        Some(virtual_display) => Some(
+            display::start(virtual_display, &config_dir)
+                .await
+                .context("Failed to start the virtual display")?,
+        ),
+        None => None,
+    };
+
     let (tx, rx) = channel::<InputCommand>(100);
+    let hub = Arc::new(rtc::hub::MediaHub::new());
+
+    if let Some(display_restarted) = display_restarted {
+        // Connections to the old X server are gone, the capture and input ones come back here
+        let hub = hub.clone();
+        let input_tx = tx.clone();
+        spawn(async move {
+            loop {
+                display_restarted.notified().await;
+                input_tx.send(InputCommand::Reconnect).await.ok();
+                hub.restart_video().await;
+            }
+        });
+    }
     let (dialog_tx, dialog_rx) = channel::<Dialog>(1);
 
+    let (clipboard, clipboard_rx) = Clipboard::new();
+    let clipboard_changes = clipboard.changes();
+    let clipboard_max_size = config.clipboard_max_size;
+    std::thread::spawn(move || do_clipboard(clipboard_rx, clipboard_changes, clipboard_max_size));
+
     let ports = Arc::new(Mutex::new(Vec::new()));
+    let auth = Arc::new(Auth::new(&config)?);
+    let live_config = Arc::new(RwLock::new(config.clone()));
     let app = Router::new()
         .route("/", get(home))
         .route("/create_key", post(create_key))
+        .route("/tokens", post(create_token))
+        .route("/tokens/list", post(list_tokens))
+        .route("/tokens/revoke", post(revoke_token))
+        .route("/sessions/list", post(list_sessions))
+        .route("/sessions/inspect", post(inspect_session))
+        .route("/sessions/kick", post(kick_session))
+        .route("/metrics", get(metrics))
         .route("/offer", post(offer))
         .layer(tower_http::cors::CorsLayer::very_permissive())
-        .with_state(AppState {
-            input_tx: tx,
-            config: config.clone(),
-            keys: Arc::new(Mutex::new(Keys::new())),
-            ports: ports.clone(),
-            dialog_tx: dialog_tx.clone(),
+        .with_state(LiveState {
+            app: AppState {
+                input_tx: tx,
+                config: config.clone(),
+                keys: Arc::new(Mutex::new(Keys::load(config_dir.join("tokens.json"))?)),
+                auth: auth.clone(),
+                sessions: Arc::new(Sessions::new()),
+                transfers: Arc::new(Mutex::new(ResumeStore::load(
+                    config_dir.join("transfers.json"),
+                )?)),
+                ports: ports.clone(),
+                hub,
+                resolution: Arc::new(Resolution::new()),
+                clipboard,
+                dialog_tx: dialog_tx.clone(),
+                fingerprint: fingerprint.clone(),
+            },
+            config: live_config.clone(),
         });
 
     let tls_config = RustlsConfig::from_pem(
-        tokio::fs::read(&config.cert)
+        tokio::fs::read(&cert_paths.cert)
             .await
             .context("Failed to read certificate file")?,
-        tokio::fs::read(&config.key)
+        tokio::fs::read(&cert_paths.key)
             .await
             .context("Failed to read private key file")?,
     )
     .await?;
 
-    spawn(async move {
-        axum_server::bind_rustls(SocketAddr::from(([0, 0, 0, 0], config.port)), tls_config)
-            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-            .await
-            .unwrap();
-    });
-
-    println!("Tenebra is listening on port {}.", config.port);
+    let handle = listen(app.clone(), tls_config.clone(), config.address()).await?;
+    println!("Tenebra is listening on {}.", config.address());
+
+    #[cfg(target_family = "unix")]
+    spawn(reload_on_sighup(
+        config_path,
+        cli.overrides.clone(),
+        live_config,
+        fingerprint,
+        auth,
+        app,
+        tls_config,
+        handle,
+    ));
+    #[cfg(not(target_family = "unix"))]
+    let _ = (live_config, fingerprint, handle);
 
     if config.tcp_upnp {
         match igd_next::aio::tokio::search_gateway(Default::default()).await {
@@ -656,16 +1206,10 @@ async fn entrypoint() -> Result<()> {
                         tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
                             .unwrap();
 
-                    #[cfg(target_family = "unix")]
-                    let mut sighup_stream =
-                        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
-                            .unwrap();
-
                     #[cfg(target_family = "unix")]
                     tokio::select! {
                         _ = ctrl_c() => {},
                         _ = sigterm_stream.recv() => {},
-                        _ = sighup_stream.recv() => {},
                     }
 
                     #[cfg(not(target_family = "unix"))]
@@ -689,9 +1233,153 @@ async fn entrypoint() -> Result<()> {
         }
     }
 
-    std::thread::spawn(move || do_input(rx, config.startx, config.starty));
+    std::thread::spawn(move || do_input(rx));
 
     tokio::task::block_in_place(move || do_dialogs(dialog_rx))?;
 
     Ok(())
 }
+
+/// Serve `app` over HTTPS on `address`, until the returned handle shuts it down.
+async fn listen(
+    app: Router,
+    tls_config: RustlsConfig,
+    address: SocketAddr,
+) -> Result<axum_server::Handle> {
+    let handle = axum_server::Handle::new();
+    let server = axum_server::bind_rustls(address, tls_config).handle(handle.clone());
+    spawn(async move {
+        if let Err(e) = server
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+        {
+            error!("The server on {} stopped: {:?}", address, e);
+        }
+    });
+    handle
+        .listening()
+        .await
+        .with_context(|| format!("Failed to listen on {}", address))?;
+    Ok(handle)
+}
+
+/// Listen on `address` as soon as a server that is shutting down lets go of it.
+#[cfg(target_family = "unix")]
+async fn listen_when_free(
+    app: &Router,
+    tls_config: &RustlsConfig,
+    address: SocketAddr,
+) -> Result<axum_server::Handle> {
+    let mut retries = 20;
+    loop {
+        match listen(app.clone(), tls_config.clone(), address).await {
+            Err(_) if retries > 0 => {
+                retries -= 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Reload the config file on every SIGHUP. Sessions that are already running keep the settings
+/// they started with.
+#[cfg(target_family = "unix")]
+async fn reload_on_sighup(
+    path: PathBuf,
+    overrides: Overrides,
+    live_config: Arc<RwLock<Config>>,
+    fingerprint: Arc<RwLock<String>>,
+    auth: Arc<Auth>,
+    app: Router,
+    tls_config: RustlsConfig,
+    mut handle: axum_server::Handle,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+    while sighup.recv().await.is_some() {
+        info!("Reloading {}.", path.display());
+        let reloaded = reload(
+            &path,
+            &overrides,
+            &live_config,
+            &fingerprint,
+            &auth,
+            &app,
+            &tls_config,
+            &mut handle,
+        )
+        .await;
+        if let Err(e) = reloaded {
+            error!("Failed to reload the configuration: {:?}", e);
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+async fn reload(
+    path: &Path,
+    overrides: &Overrides,
+    live_config: &RwLock<Config>,
+    fingerprint: &RwLock<String>,
+    auth: &Auth,
+    app: &Router,
+    tls_config: &RustlsConfig,
+    handle: &mut axum_server::Handle,
+) -> Result<()> {
+    let current = live_config.read().unwrap().clone();
+    let mut config = settings::load(path)?;
+    overrides.apply(&mut config);
+    settings::ensure_valid(&config)?;
+    let config = settings::reloaded(&current, config);
+
+    // Nothing changes until everything that can fail has succeeded
+    let secret = auth::Secret::new(&config)?;
+    // The files may have been replaced even if their paths stayed the same
+    let cert_paths = CertPaths::new(&config, &state_dir(path));
+    cert_paths.ensure()?;
+    let new_fingerprint = tls::fingerprint(&cert_paths.cert)?;
+    let new_tls_config = RustlsConfig::from_pem_file(&cert_paths.cert, &cert_paths.key)
+        .await
+        .context("Failed to load the certificate")?;
+    if config.address() != current.address() {
+        // Requests in flight may finish, sessions don't depend on the listener
+        let new_handle = if config.port == current.port {
+            // Another address on the same port can't be bound while the old one is, e.g.
+            // 0.0.0.0 and 127.0.0.1
+            handle.graceful_shutdown(Some(Duration::from_secs(10)));
+            match listen_when_free(app, tls_config, config.address()).await {
+                Ok(new_handle) => new_handle,
+                Err(e) => {
+                    *handle = listen_when_free(app, tls_config, current.address()).await?;
+                    return Err(e);
+                }
+            }
+        } else {
+            let new_handle = listen(app.clone(), tls_config.clone(), config.address()).await?;
+            handle.graceful_shutdown(Some(Duration::from_secs(10)));
+            new_handle
+        };
+        *handle = new_handle;
+        println!("Tenebra is listening on {}.", config.address());
+    }
+
+    // The new listener shares the TLS config, so it picks up the certificate too
+    tls_config.reload_from_config(new_tls_config.get_inner());
+    if *fingerprint.read().unwrap() != new_fingerprint {
+        println!("Certificate fingerprint (SHA-256): {}", new_fingerprint);
+        *fingerprint.write().unwrap() = new_fingerprint;
+    }
+    auth.reload(secret);
+
+    println!("{}", config);
+    *live_config.write().unwrap() = config;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sessions::SessionInfo;
+
+/// Process-wide counters. Per-session values live in [`crate::sessions::SessionStats`].
+#[derive(Debug)]
+pub struct Metrics {
+    pub sessions_started: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub keyframes_forced: AtomicU64,
+    pub input_events: AtomicU64,
+    pub file_bytes_received: AtomicU64,
+    pub file_bytes_sent: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    sessions_started: AtomicU64::new(0),
+    auth_failures: AtomicU64::new(0),
+    keyframes_forced: AtomicU64::new(0),
+    input_events: AtomicU64::new(0),
+    file_bytes_received: AtomicU64::new(0),
+    file_bytes_sent: AtomicU64::new(0),
+};
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP tenebra_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE tenebra_{} {}", name, kind).unwrap();
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, "counter", help);
+    writeln!(out, "tenebra_{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+}
+
+fn per_session<T: std::fmt::Display>(
+    out: &mut String,
+    sessions: &[SessionInfo],
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&SessionInfo) -> Option<T>,
+) {
+    header(out, name, kind, help);
+    for session in sessions {
+        if let Some(value) = value(session) {
+            writeln!(
+                out,
+                "tenebra_{}{{session=\"{}\",codec=\"{}\"}} {}",
+                name, session.id, session.codec, value
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Render everything in the Prometheus text exposition format.
+#[rustfmt::skip]
+pub fn render(sessions: &[SessionInfo]) -> String {
+    let mut out = String::new();
+
+    header(&mut out, "sessions", "gauge", "Connected sessions.");
+    writeln!(out, "tenebra_sessions {}", sessions.len()).unwrap();
+    counter(&mut out, "sessions_started_total", "Sessions started since launch.", &METRICS.sessions_started);
+    counter(&mut out, "auth_failures_total", "Failed password and token attempts.", &METRICS.auth_failures);
+    counter(&mut out, "keyframes_forced_total", "Keyframes forced on any encoder.", &METRICS.keyframes_forced);
+    counter(&mut out, "input_events_total", "Input events received from clients.", &METRICS.input_events);
+    counter(&mut out, "file_bytes_received_total", "File transfer bytes received from clients.", &METRICS.file_bytes_received);
+    counter(&mut out, "file_bytes_sent_total", "File transfer bytes sent to clients.", &METRICS.file_bytes_sent);
+
+    per_session(&mut out, sessions, "session_fps", "gauge", "Encoded video frames sent per second.", |s| Some(s.stats.fps));
+    per_session(&mut out, sessions, "session_bitrate_sent_bps", "gauge", "Media bits sent per second.", |s| Some(s.stats.bitrate_sent));
+    per_session(&mut out, sessions, "session_bwe_kbps", "gauge", "Current bandwidth estimate.", |s| s.bitrate);
+    per_session(&mut out, sessions, "session_rtt_ms", "gauge", "Round trip time.", |s| s.stats.rtt_ms);
+    per_session(&mut out, sessions, "session_packet_loss_ratio", "gauge", "Fraction of video packets lost.", |s| s.stats.packet_loss);
+    per_session(&mut out, sessions, "session_nacks_total", "counter", "NACKs received.", |s| Some(s.stats.nacks));
+    per_session(&mut out, sessions, "session_plis_total", "counter", "PLIs received.", |s| Some(s.stats.plis));
+    per_session(&mut out, sessions, "session_keyframe_requests_total", "counter", "Keyframe requests received.", |s| Some(s.stats.keyframe_requests));
+    per_session(&mut out, sessions, "session_input_events_per_second", "gauge", "Input events received per second.", |s| Some(s.stats.input_events_per_second));
+    per_session(&mut out, sessions, "session_file_bytes_received_total", "counter", "File transfer bytes received.", |s| Some(s.stats.file_bytes_received));
+    per_session(&mut out, sessions, "session_file_bytes_sent_total", "counter", "File transfer bytes sent.", |s| Some(s.stats.file_bytes_sent));
+
+    out
+}
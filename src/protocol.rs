@@ -0,0 +1,865 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::keys::Capability;
+
+/// Version 1 is the original untyped protocol, which never sent a `hello`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Everything this server knows how to do, advertised during the handshake.
+#[cfg(target_os = "linux")]
+pub const SERVER_FEATURES: &[&str] = &[
+    "input",
+    "filetransfer",
+    "filetransfer2",
+    "filebrowse",
+    "archive",
+    "progress",
+    "errors",
+    "adaptation",
+    "clipboard",
+    "resize",
+    "monitors",
+    "windows",
+];
+#[cfg(not(target_os = "linux"))]
+pub const SERVER_FEATURES: &[&str] = &[
+    "input",
+    "filetransfer",
+    "filetransfer2",
+    "filebrowse",
+    "archive",
+    "progress",
+    "errors",
+    "adaptation",
+];
+
+/// What a client that never says `hello` is assumed to support.
+const LEGACY_FEATURES: &[&str] = &["input", "filetransfer"];
+
+/// Every message a client may send over the datachannel.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    Hello {
+        version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    Disconnect,
+    ReleaseAll,
+
+    // Input
+    MouseMove {
+        x: i32,
+        y: i32,
+    },
+    // Absolute positions are on the video track `mid`, or the first one
+    MouseMoveAbs {
+        x: i32,
+        y: i32,
+        #[serde(default)]
+        mid: Option<String>,
+    },
+    Wheel {
+        x: i32,
+        y: i32,
+    },
+    MouseDown {
+        button: u8,
+    },
+    MouseUp {
+        button: u8,
+    },
+    KeyDown {
+        key: String,
+    },
+    KeyUp {
+        key: String,
+    },
+    TouchStart {
+        id: i32,
+        x: i32,
+        y: i32,
+        #[serde(default)]
+        mid: Option<String>,
+    },
+    TouchMove {
+        id: i32,
+        x: i32,
+        y: i32,
+        #[serde(default)]
+        mid: Option<String>,
+    },
+    TouchEnd {
+        id: i32,
+    },
+    Pen {
+        x: i32,
+        y: i32,
+        pressure: f64,
+        #[serde(rename = "tiltX")]
+        tilt_x: i32,
+        #[serde(rename = "tiltY")]
+        tilt_y: i32,
+        #[serde(default)]
+        mid: Option<String>,
+    },
+
+    // File transfers, see specs/file_transfer_v1.md
+    RequestTransfer {
+        id: u32,
+        size: Option<u64>,
+    },
+    TransferReady {
+        id: u32,
+        size: Option<u64>,
+    },
+    CancelTransfer {
+        id: u32,
+    },
+
+    // File transfers v2, see specs/file_transfer_v2.md
+    OfferFile {
+        id: u32,
+        #[serde(flatten)]
+        file: FileOffer,
+    },
+    RequestFile {
+        id: u32,
+        // A file in a shared directory, instead of asking the host to pick one
+        #[serde(default)]
+        path: Option<String>,
+        #[serde(default)]
+        resume: Option<ResumeFile>,
+    },
+    ListDir {
+        #[serde(default)]
+        path: String,
+    },
+    // Folders and groups of files, as a tar stream
+    OfferArchive {
+        id: u32,
+        // The folder the archive is unpacked into
+        name: String,
+        #[serde(default)]
+        compression: Compression,
+    },
+    RequestArchive {
+        id: u32,
+        // Shared files and folders to include, or empty to have the host pick a folder
+        #[serde(default)]
+        paths: Vec<String>,
+        #[serde(default)]
+        compression: Compression,
+    },
+
+    // Fit the screen to the client's viewport, in physical pixels
+    Viewport {
+        width: u32,
+        height: u32,
+    },
+
+    // Ask for a `monitors` message
+    ListMonitors,
+    // Ask for a `windows` message
+    ListWindows,
+    // Capture one top-level window, by its X11 id, on the video track `mid`, or the first one
+    SelectWindow {
+        id: u32,
+        #[serde(default)]
+        mid: Option<String>,
+    },
+    // Capture one monitor on the video track `mid`, or the first one. Without a `name`, the
+    // track goes back to the configured area
+    SelectMonitor {
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        mid: Option<String>,
+    },
+
+    // Replace the host clipboard
+    ClipboardSet {
+        items: Vec<ClipboardItem>,
+    },
+
+    #[serde(other)]
+    Unknown,
+}
+
+impl ClientMessage {
+    /// Whether this message is handled by the input thread.
+    pub fn is_input(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::MouseMove { .. }
+                | ClientMessage::MouseMoveAbs { .. }
+                | ClientMessage::Wheel { .. }
+                | ClientMessage::MouseDown { .. }
+                | ClientMessage::MouseUp { .. }
+                | ClientMessage::KeyDown { .. }
+                | ClientMessage::KeyUp { .. }
+                | ClientMessage::TouchStart { .. }
+                | ClientMessage::TouchMove { .. }
+                | ClientMessage::TouchEnd { .. }
+                | ClientMessage::Pen { .. }
+        )
+    }
+
+    /// The video track an absolute position is on, if the client said.
+    pub fn mid(&self) -> Option<&str> {
+        match self {
+            ClientMessage::MouseMoveAbs { mid, .. }
+            | ClientMessage::TouchStart { mid, .. }
+            | ClientMessage::TouchMove { mid, .. }
+            | ClientMessage::Pen { mid, .. } => mid.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Where an absolute position is on its video track.
+    pub fn position(&self) -> Option<(i32, i32)> {
+        match self {
+            ClientMessage::MouseMoveAbs { x, y, .. }
+            | ClientMessage::TouchStart { x, y, .. }
+            | ClientMessage::TouchMove { x, y, .. }
+            | ClientMessage::Pen { x, y, .. } => Some((*x, *y)),
+            _ => None,
+        }
+    }
+
+    /// Transform the absolute position of the message, if it has one.
+    pub fn map_position(&mut self, f: impl FnOnce(i32, i32) -> (i32, i32)) {
+        match self {
+            ClientMessage::MouseMoveAbs { x, y, .. }
+            | ClientMessage::TouchStart { x, y, .. }
+            | ClientMessage::TouchMove { x, y, .. }
+            | ClientMessage::Pen { x, y, .. } => (*x, *y) = f(*x, *y),
+            _ => {}
+        }
+    }
+
+    /// The capabilities that allow this message; holding any one of them is enough.
+    /// Messages that return an empty list are always allowed.
+    pub fn required_capabilities(&self) -> &'static [Capability] {
+        match self {
+            ClientMessage::Hello { .. } | ClientMessage::Disconnect | ClientMessage::Unknown => &[],
+            ClientMessage::ReleaseAll => &[Capability::Keyboard, Capability::Mouse],
+            ClientMessage::KeyDown { .. } | ClientMessage::KeyUp { .. } => &[Capability::Keyboard],
+            ClientMessage::MouseMove { .. }
+            | ClientMessage::MouseMoveAbs { .. }
+            | ClientMessage::Wheel { .. }
+            | ClientMessage::MouseDown { .. }
+            | ClientMessage::MouseUp { .. } => &[Capability::Mouse],
+            ClientMessage::TouchStart { .. }
+            | ClientMessage::TouchMove { .. }
+            | ClientMessage::TouchEnd { .. }
+            | ClientMessage::Pen { .. } => &[Capability::TouchPen],
+            // A known size means the client is sending the file
+            ClientMessage::RequestTransfer { size: Some(_), .. }
+            | ClientMessage::OfferFile { .. }
+            | ClientMessage::OfferArchive { .. } => &[Capability::FileUpload],
+            ClientMessage::RequestTransfer { size: None, .. }
+            | ClientMessage::RequestFile { .. }
+            | ClientMessage::RequestArchive { .. }
+            | ClientMessage::ListDir { .. } => &[Capability::FileDownload],
+            ClientMessage::TransferReady { .. } | ClientMessage::CancelTransfer { .. } => {
+                &[Capability::FileUpload, Capability::FileDownload]
+            }
+            ClientMessage::ClipboardSet { .. } => &[Capability::ClipboardWrite],
+            ClientMessage::Viewport { .. } => &[Capability::ResizeDisplay],
+            // Monitors show what is on the screen anyway, windows may be hidden behind others
+            ClientMessage::ListMonitors | ClientMessage::SelectMonitor { .. } => {
+                &[Capability::ViewVideo]
+            }
+            ClientMessage::ListWindows | ClientMessage::SelectWindow { .. } => {
+                &[Capability::CaptureWindows]
+            }
+        }
+    }
+}
+
+/// A file the client wants to upload.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FileOffer {
+    pub name: String,
+    #[serde(default)]
+    pub mime: Option<String>,
+    pub size: u64,
+    // Hex SHA-256 of the whole file, checked once it has arrived
+    pub sha256: String,
+}
+
+/// Where to pick up a download that was interrupted.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ResumeFile {
+    pub sha256: String,
+    pub offset: u64,
+}
+
+/// How an archive transfer is compressed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// A file or directory in a `dirlisting`.
+#[derive(Serialize, Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub dir: bool,
+    pub size: u64,
+    // Unix timestamp, in seconds
+    pub modified: u64,
+}
+
+/// A monitor in a `monitors` message, positioned on the whole desktop.
+#[derive(Serialize, Debug, Clone)]
+pub struct Monitor {
+    pub name: String,
+    pub primary: bool,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A top-level window in a `windows` message, positioned on the whole desktop.
+#[derive(Serialize, Debug, Clone)]
+pub struct Window {
+    pub id: u32,
+    pub title: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What a video track shows, in a `monitors` or `windows` message.
+#[derive(Serialize, Debug, Clone)]
+pub struct TrackMonitor {
+    pub mid: String,
+    pub monitor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<u32>,
+}
+
+/// One representation of the clipboard contents. `data` is base64 for binary types.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClipboardItem {
+    pub mime: String,
+    pub data: String,
+}
+
+/// Every message the server may send over the datachannel.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    Hello {
+        version: u32,
+        features: Vec<String>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        // The `type` of the message that caused the error, if it could be determined
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request: Option<String>,
+    },
+    TransferReady {
+        id: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+    },
+    CancelTransfer {
+        id: u32,
+    },
+    // Send chunks of an offered file, starting at `offset`
+    AcceptFile {
+        id: u32,
+        offset: u64,
+    },
+    // Describes a requested file, whose chunks follow starting at `offset`
+    FileInfo {
+        id: u32,
+        name: String,
+        mime: String,
+        size: u64,
+        sha256: String,
+        offset: u64,
+    },
+    TransferComplete {
+        id: u32,
+        sha256: String,
+    },
+    TransferFailed {
+        id: u32,
+        reason: String,
+    },
+    DirListing {
+        path: String,
+        entries: Vec<DirEntry>,
+    },
+    // Describes a requested archive, whose chunks follow
+    ArchiveInfo {
+        id: u32,
+        name: String,
+        compression: Compression,
+    },
+    // A file inside an archive was packed or unpacked. `files` and `bytes` are running totals
+    ArchiveProgress {
+        id: u32,
+        path: String,
+        files: u64,
+        bytes: u64,
+    },
+    // How far a transfer has come. `rate` is in bytes per second, `eta` in seconds
+    TransferProgress {
+        id: u32,
+        bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        total: Option<u64>,
+        rate: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        eta: Option<u64>,
+    },
+    // The transfer waits for others to finish before it starts
+    TransferQueued {
+        id: u32,
+    },
+    // The screen was resized after a `viewport` message
+    Resolution {
+        width: u32,
+        height: u32,
+    },
+    // The monitors of the host, and the one the first video track captures if any
+    Monitors {
+        monitors: Vec<Monitor>,
+        current: Option<String>,
+        tracks: Vec<TrackMonitor>,
+    },
+    // The video track `mid` is now encoded at `fps` and `scale` times the captured size
+    Quality {
+        mid: String,
+        fps: i32,
+        scale: f64,
+    },
+    // The top-level windows of the host
+    Windows {
+        windows: Vec<Window>,
+        tracks: Vec<TrackMonitor>,
+    },
+    // The host clipboard changed
+    Clipboard {
+        items: Vec<ClipboardItem>,
+    },
+}
+
+impl ServerMessage {
+    pub fn to_vec(&self) -> Vec<u8> {
+        // Serializing these types can't fail: every map key is a string
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorCode {
+    /// The message was not valid JSON, or its fields did not match its type.
+    Malformed,
+    /// The message's `type` is not one this server understands.
+    UnknownType,
+    /// The client is not permitted to send this message.
+    Forbidden,
+    /// The message was understood but refers to something that does not exist.
+    Invalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub request: Option<String>,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            request: None,
+        }
+    }
+
+    pub fn with_request(mut self, request: impl Into<String>) -> Self {
+        self.request = Some(request.into());
+        self
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl From<ProtocolError> for ServerMessage {
+    fn from(err: ProtocolError) -> Self {
+        ServerMessage::Error {
+            code: err.code,
+            message: err.message,
+            request: err.request,
+        }
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<ClientMessage, ProtocolError> {
+    let value: serde_json::Value = serde_json::from_slice(data)
+        .map_err(|e| ProtocolError::new(ErrorCode::Malformed, e.to_string()))?;
+    let r#type = value
+        .get("type")
+        .and_then(|t| t.as_str())
+        .map(str::to_owned)
+        .ok_or_else(|| ProtocolError::new(ErrorCode::Malformed, "missing `type` field"))?;
+
+    match serde_json::from_value(value) {
+        Ok(ClientMessage::Unknown) => Err(ProtocolError::new(
+            ErrorCode::UnknownType,
+            format!("unknown message type `{}`", r#type),
+        )
+        .with_request(r#type)),
+        Ok(message) => Ok(message),
+        Err(e) => {
+            Err(ProtocolError::new(ErrorCode::Malformed, e.to_string()).with_request(r#type))
+        }
+    }
+}
+
+/// The outcome of version negotiation with one client.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub version: u32,
+    features: HashSet<String>,
+}
+
+impl Handshake {
+    pub fn legacy() -> Self {
+        Self {
+            version: 1,
+            features: LEGACY_FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    /// Agree on the highest common version and the features both sides support. Versions start
+    /// at 1, so a client claiming version 0 is refused.
+    pub fn negotiate(
+        client_version: u32,
+        client_features: &[String],
+    ) -> Result<Self, ProtocolError> {
+        if client_version < 1 {
+            return Err(ProtocolError::new(
+                ErrorCode::Invalid,
+                format!("protocol version {} does not exist", client_version),
+            )
+            .with_request("hello"));
+        }
+        Ok(Self {
+            version: client_version.min(PROTOCOL_VERSION),
+            features: client_features
+                .iter()
+                .filter(|f| SERVER_FEATURES.contains(&f.as_str()))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+
+    pub fn reply(&self) -> ServerMessage {
+        let mut features: Vec<String> = self.features.iter().cloned().collect();
+        features.sort();
+        ServerMessage::Hello {
+            version: self.version,
+            features,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::{json, Value};
+
+    fn parse_json(value: Value) -> Result<ClientMessage, ProtocolError> {
+        parse(value.to_string().as_bytes())
+    }
+
+    fn to_json(message: &ServerMessage) -> Value {
+        serde_json::from_slice(&message.to_vec()).unwrap()
+    }
+
+    fn features(features: &[&str]) -> Vec<String> {
+        features.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn unknown_types_are_reported() {
+        let err = parse_json(json!({ "type": "teleport", "x": 1 })).unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownType);
+        assert_eq!(err.request.as_deref(), Some("teleport"));
+        assert_eq!(err.message, "unknown message type `teleport`");
+    }
+
+    #[test]
+    fn malformed_messages_are_reported() {
+        for data in [&b"not json"[..], b"[1, 2]", b"{}", b"{\"type\": 5}"] {
+            let err = parse(data).unwrap_err();
+            assert_eq!(err.code, ErrorCode::Malformed);
+            assert_eq!(err.request, None);
+        }
+    }
+
+    #[test]
+    fn missing_and_mistyped_fields_are_reported() {
+        let err = parse_json(json!({ "type": "mousemove", "x": 1 })).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Malformed);
+        assert_eq!(err.request.as_deref(), Some("mousemove"));
+        assert!(err.message.contains("missing field `y`"), "{}", err.message);
+
+        let err = parse_json(json!({ "type": "keydown", "key": 65 })).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Malformed);
+        assert_eq!(err.request.as_deref(), Some("keydown"));
+
+        let err = parse_json(json!({ "type": "hello", "version": -1 })).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Malformed);
+    }
+
+    #[test]
+    fn optional_fields_default() {
+        match parse_json(json!({ "type": "hello", "version": 2 })).unwrap() {
+            ClientMessage::Hello { version, features } => {
+                assert_eq!(version, 2);
+                assert!(features.is_empty());
+            }
+            msg => panic!("parsed as {:?}", msg),
+        }
+        match parse_json(json!({ "type": "requestarchive", "id": 3 })).unwrap() {
+            ClientMessage::RequestArchive {
+                id,
+                paths,
+                compression,
+            } => {
+                assert_eq!(id, 3);
+                assert!(paths.is_empty());
+                assert_eq!(compression, Compression::None);
+            }
+            msg => panic!("parsed as {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn v1_messages_still_parse() {
+        // What clients sent before there were a handshake and typed errors
+        let messages = [
+            json!({ "type": "mousemove", "x": -3, "y": 4 }),
+            json!({ "type": "mousemoveabs", "x": 10, "y": 20 }),
+            json!({ "type": "wheel", "x": 0, "y": -120 }),
+            json!({ "type": "mousedown", "button": 0 }),
+            json!({ "type": "mouseup", "button": 0 }),
+            json!({ "type": "keydown", "key": "KeyA" }),
+            json!({ "type": "keyup", "key": "KeyA" }),
+            json!({ "type": "touchstart", "id": 1, "x": 5, "y": 6 }),
+            json!({ "type": "touchmove", "id": 1, "x": 7, "y": 8 }),
+            json!({ "type": "touchend", "id": 1 }),
+            json!({ "type": "pen", "x": 1, "y": 2, "pressure": 0.5, "tiltX": 10, "tiltY": -10 }),
+            json!({ "type": "requesttransfer", "id": 1, "size": 1024 }),
+            json!({ "type": "requesttransfer", "id": 2, "size": null }),
+            json!({ "type": "transferready", "id": 1, "size": 1024 }),
+            json!({ "type": "canceltransfer", "id": 1 }),
+            json!({ "type": "releaseall" }),
+            json!({ "type": "disconnect" }),
+        ];
+        for message in &messages {
+            assert!(
+                parse_json(message.clone()).is_ok(),
+                "{} is refused",
+                message
+            );
+        }
+
+        match parse_json(messages[10].clone()).unwrap() {
+            ClientMessage::Pen {
+                pressure,
+                tilt_x,
+                tilt_y,
+                mid,
+                ..
+            } => {
+                assert_eq!((pressure, tilt_x, tilt_y), (0.5, 10, -10));
+                assert_eq!(mid, None);
+            }
+            msg => panic!("parsed as {:?}", msg),
+        }
+
+        let legacy = Handshake::legacy();
+        assert_eq!(legacy.version, 1);
+        assert!(legacy.supports("input"));
+        assert!(legacy.supports("filetransfer"));
+        assert!(!legacy.supports("errors"));
+        assert!(!legacy.supports("filetransfer2"));
+    }
+
+    #[test]
+    fn negotiates_the_lower_version() {
+        assert_eq!(Handshake::negotiate(1, &[]).unwrap().version, 1);
+        assert_eq!(
+            Handshake::negotiate(PROTOCOL_VERSION, &[]).unwrap().version,
+            PROTOCOL_VERSION
+        );
+        assert_eq!(
+            Handshake::negotiate(PROTOCOL_VERSION + 7, &[])
+                .unwrap()
+                .version,
+            PROTOCOL_VERSION
+        );
+    }
+
+    #[test]
+    fn refuses_version_zero() {
+        let err = Handshake::negotiate(0, &features(&["input"])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::Invalid);
+        assert_eq!(err.request.as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn features_are_intersected() {
+        let handshake = Handshake::negotiate(
+            2,
+            &features(&["progress", "teleport", "input", "archive", "input"]),
+        )
+        .unwrap();
+        assert!(handshake.supports("input"));
+        assert!(handshake.supports("archive"));
+        assert!(handshake.supports("progress"));
+        assert!(!handshake.supports("teleport"));
+        // The client didn't ask for it
+        assert!(!handshake.supports("errors"));
+        assert_eq!(
+            to_json(&handshake.reply()),
+            json!({ "type": "hello", "version": 2, "features": ["archive", "input", "progress"] })
+        );
+
+        let none = Handshake::negotiate(2, &[]).unwrap();
+        assert!(SERVER_FEATURES.iter().all(|f| !none.supports(f)));
+    }
+
+    #[test]
+    fn hello_round_trips() {
+        let reply = Handshake::negotiate(2, &features(&["errors", "input"]))
+            .unwrap()
+            .reply();
+        match parse(&reply.to_vec()).unwrap() {
+            ClientMessage::Hello { version, features } => {
+                let again = Handshake::negotiate(version, &features).unwrap();
+                assert_eq!(to_json(&again.reply()), to_json(&reply));
+            }
+            msg => panic!("parsed as {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn clipboard_round_trips() {
+        let items = vec![
+            ClipboardItem {
+                mime: "text/plain".to_string(),
+                data: "héllo\n\"quoted\"".to_string(),
+            },
+            ClipboardItem {
+                mime: "image/png".to_string(),
+                data: "iVBORw0KGgo=".to_string(),
+            },
+        ];
+        let mut value = to_json(&ServerMessage::Clipboard {
+            items: items.clone(),
+        });
+        assert_eq!(value["type"], "clipboard");
+        // A client sends back what it received as `clipboardset`
+        value["type"] = json!("clipboardset");
+        match parse_json(value).unwrap() {
+            ClientMessage::ClipboardSet { items: parsed } => {
+                assert_eq!(parsed.len(), items.len());
+                for (parsed, item) in parsed.iter().zip(&items) {
+                    assert_eq!(parsed.mime, item.mime);
+                    assert_eq!(parsed.data, item.data);
+                }
+            }
+            msg => panic!("parsed as {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn server_messages_serialize() {
+        let error = ProtocolError::new(ErrorCode::UnknownType, "unknown message type `x`")
+            .with_request("x");
+        assert_eq!(
+            to_json(&error.into()),
+            json!({
+                "type": "error",
+                "code": "unknowntype",
+                "message": "unknown message type `x`",
+                "request": "x",
+            })
+        );
+        assert_eq!(
+            to_json(&ProtocolError::new(ErrorCode::Malformed, "expected value").into()),
+            json!({ "type": "error", "code": "malformed", "message": "expected value" })
+        );
+        assert_eq!(
+            to_json(&ServerMessage::TransferReady { id: 4, size: None }),
+            json!({ "type": "transferready", "id": 4 })
+        );
+        assert_eq!(
+            to_json(&ServerMessage::ArchiveInfo {
+                id: 5,
+                name: "docs".to_string(),
+                compression: Compression::Zstd,
+            }),
+            json!({ "type": "archiveinfo", "id": 5, "name": "docs", "compression": "zstd" })
+        );
+        assert_eq!(
+            to_json(&ServerMessage::TransferProgress {
+                id: 6,
+                bytes: 10,
+                total: Some(20),
+                rate: 5,
+                eta: None,
+            }),
+            json!({ "type": "transferprogress", "id": 6, "bytes": 10, "total": 20, "rate": 5 })
+        );
+    }
+
+    #[test]
+    fn offers_flatten_the_file() {
+        let msg = parse_json(json!({
+            "type": "offerfile",
+            "id": 9,
+            "name": "a.txt",
+            "size": 3,
+            "sha256": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        }));
+        assert!(
+            matches!(msg, Ok(ClientMessage::OfferFile { id: 9, .. })),
+            "{:?}",
+            msg
+        );
+    }
+}
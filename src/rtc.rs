@@ -58,15 +58,12 @@ use log::*;
 use std::collections::HashMap;
 use std::io::ErrorKind;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
-use tokio::fs::File;
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
 use tokio::net::TcpListener;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::task::{spawn, AbortHandle};
+use tokio::sync::broadcast::error::RecvError;
 
 use anyhow::{Context, Result};
 
@@ -79,324 +76,78 @@ use str0m::media::{MediaKind, MediaTime, Mid};
 use str0m::net::{Protocol, Receive};
 use str0m::{Event, IceConnectionState, Input, Output, Rtc};
 
-use crate::dialogs::*;
-use crate::keys::Permissions;
+use self::codec::VideoCodec;
+use self::tracks::VideoTracks;
+use self::transfer::{DatachannelMessageKind, FileTransfers};
+use crate::clipboard::ClipboardEntry;
+use crate::display;
+use crate::keys::{Capability, Permissions};
+use crate::metrics::METRICS;
+use crate::sessions::{SessionGuard, Transport};
+use crate::protocol::{
+    self, ClientMessage, ClipboardItem, ErrorCode, Handshake, ProtocolError, ServerMessage,
+};
 use crate::AppState;
 use crate::CreateOffer;
-use crate::{ClientCommand, InputCommand};
+use crate::InputCommand;
 
+pub mod adaptation;
+pub mod codec;
+pub mod hub;
+mod archive;
+mod browse;
 mod pipeline;
+mod progress;
+mod recording;
 mod tcp;
-
-enum DatachannelMessageKind {
-    Binary,
-    Text
-}
-
-impl DatachannelMessageKind {
-    fn is_binary(&self) -> bool {
-        match self {
-            DatachannelMessageKind::Binary => true,
-            DatachannelMessageKind::Text => false,
-        }
+mod tracks;
+pub mod transfer;
+
+/// How long the viewport has to stay the same size before the screen is resized to it. Clients
+/// send one `viewport` after another while their window is dragged.
+const VIEWPORT_SETTLE: Duration = Duration::from_millis(500);
+
+fn decode_clipboard(items: &[ClipboardItem], max_size: usize) -> Result<Vec<ClipboardEntry>, ProtocolError> {
+    let entries = items
+        .iter()
+        .map(ClipboardEntry::from_item)
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| ProtocolError::new(ErrorCode::Malformed, e.to_string()).with_request("clipboardset"))?;
+    let size: usize = entries.iter().map(|entry| entry.data.len()).sum();
+    if size > max_size {
+        return Err(ProtocolError::new(
+            ErrorCode::Invalid,
+            format!("clipboard contents are {} bytes, the limit is {}", size, max_size),
+        )
+        .with_request("clipboardset"));
     }
+    Ok(entries)
 }
 
-struct FileTransfers {
-    // The datachannel and the corresponding data
-    rx: Receiver<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
-    tx: Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
-
-    // When file chunks arrive, we send them to this sender and the task dedicated to that file
-    // will handle those chunks
-    inbound_transfers: Arc<Mutex<HashMap<u32, Sender<Vec<u8>>>>>,
-    outbound_transfers: Arc<Mutex<HashMap<u32, AbortHandle>>>,
-}
-
-impl FileTransfers {
-    fn new() -> Self {
-        let (tx, rx) = channel(100);
-        Self {
-            tx,
-            rx,
-            inbound_transfers: Arc::new(Mutex::new(HashMap::new())),
-            outbound_transfers: Arc::new(Mutex::new(HashMap::new())),
-        }
-    }
-
-    async fn handle_inbound_file_chunk(&self, id: u32, chunk: Vec<u8>) -> Result<()> {
-        let sender = {
-            let mut inbound_transfers = self.inbound_transfers.lock().unwrap();
-            inbound_transfers.get_mut(&id).context(format!("inbound file chunk received for non-existent file transfer: {}", id))?.clone()
-        };
-        sender.send(chunk).await?;
-        Ok(())
+/// Keep recording the first video track after it moved to another pipeline.
+fn follow_recording(recorder: &mut Option<recording::SessionRecorder>, video: &VideoTracks) {
+    let Some(current) = recorder.as_ref() else {
+        return;
+    };
+    if current.records(video.primary()) {
+        return;
     }
-
-    fn cancel_transfer(&self, id: u32) {
-        // This suffices to cancel an inbound transfer as dropping the Sender will cause the
-        // recv loop to stop, thereby ending the corresponding tokio task.
-        info!("Explicitly canceling transfer {}.", id);
-        self.inbound_transfers.lock().unwrap().remove(&id);
-        let mut outbound_transfers = self.outbound_transfers.lock().unwrap();
-        if let Some(abort_handle) = outbound_transfers.get(&id) {
-            abort_handle.abort();
-            info!("Aborted handle for transfer {}.", id);
+    // Replacing the recorder finalizes the previous file
+    *recorder = match current.switch(video.primary()) {
+        Ok(next) => Some(next),
+        Err(e) => {
+            error!("Failed to continue recording: {:?}", e);
+            None
         }
-        outbound_transfers.remove(&id);
-    }
-
-    fn begin_inbound_transfer(
-        &self,
-        tx: Sender<Dialog>,
-        id: u32,
-        channel_id: ChannelId,
-        size: u64,
-    ) {
-        let inbound_transfers = Arc::downgrade(&self.inbound_transfers);
-        let datachannel_tx = self.tx.clone();
-        spawn(async move {
-            let path = spawn_file_dialog(&tx, FileDialogKind::Save).await;
-            match path {
-                Some(path) => {
-                    let file = File::create(path).await;
-                    if let Ok(mut file) = file {
-                        let (chunk_tx, mut chunk_rx) = channel(100);
-                        match inbound_transfers.upgrade() {
-                            Some(inbound_transfers) => { inbound_transfers.lock().unwrap().insert(id, chunk_tx); },
-                            None => return
-                        }
-                        let mut total_size = 0u64;
-                        datachannel_tx
-                            .send((
-                                channel_id,
-                                serde_json::to_vec(
-                                    &serde_json::json!({ "type": "transferready", "id": id }),
-                                )
-                                .unwrap(),
-                                DatachannelMessageKind::Text
-                            ))
-                            .await
-                            .ok();
-                        info!("Entering file write loop for transfer: {}", id);
-                        while let Some(chunk) = chunk_rx.recv().await {
-                            if let Err(e) = file.write_all(chunk.as_slice()).await {
-                                info!("Write error: {}", e);
-                                match inbound_transfers.upgrade() {
-                                    Some(inbound_transfers) => { inbound_transfers.lock().unwrap().remove(&id); },
-                                    None => return
-                                }
-                                datachannel_tx.send((
-                                    channel_id,
-                                    serde_json::to_vec(
-                                        &serde_json::json!({ "type": "canceltransfer", "id": id }),
-                                    )
-                                    .unwrap(),
-                                    DatachannelMessageKind::Text
-                                )).await.ok();
-                                spawn_message_dialog(
-                                    &tx,
-                                    "Tenebra File Transfer Error",
-                                    format!("Failed to write file: {}", e),
-                                    rfd::MessageLevel::Error,
-                                )
-                                .await;
-                                break;
-                            }
-                            total_size += chunk.len() as u64;
-                            if total_size >= size {
-                                info!("Transfer complete, removing self.");
-                                match inbound_transfers.upgrade() {
-                                    Some(inbound_transfers) => { inbound_transfers.lock().unwrap().remove(&id); },
-                                    None => return
-                                }
-                                info!("Removed!");
-                                spawn_message_dialog(
-                                    &tx,
-                                    "Tenebra File Transfer Notification",
-                                    format!(
-                                        "Finished file transfer. Wrote {}/{} bytes.",
-                                        total_size, size
-                                    ),
-                                    rfd::MessageLevel::Info,
-                                )
-                                .await;
-                                break;
-                            }
-                        }
-                        info!("Flushing file and exiting task.");
-                        if let Err(e) = file.flush().await {
-                            error!("Failed to sync file to disk: {}", e);
-                        }
-                    } else if let Err(e) = file {
-                        spawn_message_dialog(
-                            &tx,
-                            "Tenebra File Transfer Error",
-                            format!("Failed to create file: {}", e),
-                            rfd::MessageLevel::Error,
-                        )
-                        .await;
-                        datachannel_tx
-                            .send((
-                                channel_id,
-                                serde_json::to_vec(
-                                    &serde_json::json!({ "type": "canceltransfer", "id": id }),
-                                )
-                                .unwrap(),
-                                DatachannelMessageKind::Text
-                            ))
-                            .await
-                            .ok();
-                    }
-                }
-                None => {
-                    // Cancel the transfer
-                    datachannel_tx
-                        .send((
-                            channel_id,
-                            serde_json::to_vec(
-                                &serde_json::json!({ "type": "canceltransfer", "id": id }),
-                            )
-                            .unwrap(),
-                            DatachannelMessageKind::Text
-                        ))
-                        .await
-                        .ok();
-                }
-            }
-            info!("Reached end of task body!");
-        });
-    }
-
-    fn begin_outbound_transfer(&self, tx: Sender<Dialog>, id: u32, channel_id: ChannelId) {
-        let datachannel_tx = self.tx.clone();
-        let outbound_transfers = Arc::clone(&self.outbound_transfers);
-        let handle = spawn(async move {
-            let path = spawn_file_dialog(&tx, FileDialogKind::Open).await;
-            match path {
-                Some(path) => {
-                    let file = File::open(path).await;
-                    if let Ok(mut file) = file {
-                        let metadata = file.metadata().await;
-                        if let Ok(metadata) = metadata {
-                            let total_size = metadata.len();
-                            datachannel_tx.send((
-                                channel_id,
-                                serde_json::to_vec(
-                                    &serde_json::json!({ "type": "transferready", "id": id, "size": total_size }),
-                                )
-                                .unwrap(),
-                                DatachannelMessageKind::Text
-                            )).await.ok();
-                            const CHUNK_SIZE: usize = 16 * 1024;
-                            let mut buf = vec![0u8; CHUNK_SIZE];
-                            loop {
-                                let n = file.read(&mut buf).await;
-                                if let Ok(n) = n {
-                                    if n == 0 {
-                                        spawn_message_dialog(
-                                            &tx,
-                                            "Tenebra File Transfer Notification",
-                                            format!(
-                                                "Finished file transfer. Sent {} bytes.",
-                                                total_size
-                                            ),
-                                            rfd::MessageLevel::Info,
-                                        )
-                                        .await;
-                                        break;
-                                    }
-
-                                    let chunk = &buf[..n];
-                                    let mut v = Vec::with_capacity(4 + chunk.len());
-                                    v.extend_from_slice(&id.to_be_bytes());
-                                    v.extend_from_slice(chunk);
-
-                                    if datachannel_tx.send((channel_id, v, DatachannelMessageKind::Binary)).await.is_err() {
-                                        break; // Receiver closed
-                                    }
-                                } else {
-                                    datachannel_tx.send((
-                                        channel_id,
-                                        serde_json::to_vec(
-                                            &serde_json::json!({ "type": "canceltransfer", "id": id }),
-                                        )
-                                        .unwrap(),
-                                        DatachannelMessageKind::Text
-                                    )).await.ok();
-                                }
-                            }
-                        } else if let Err(e) = metadata {
-                            spawn_message_dialog(
-                                &tx,
-                                "Tenebra File Transfer Error",
-                                format!("Failed to query metadata of file file: {}", e),
-                                rfd::MessageLevel::Error,
-                            )
-                            .await;
-                            datachannel_tx
-                                .send((
-                                    channel_id,
-                                    serde_json::to_vec(
-                                        &serde_json::json!({ "type": "canceltransfer", "id": id }),
-                                    )
-                                    .unwrap(),
-                                    DatachannelMessageKind::Text
-                                ))
-                                .await
-                                .ok();
-                        }
-                    } else if let Err(e) = file {
-                        spawn_message_dialog(
-                            &tx,
-                            "Tenebra File Transfer Error",
-                            format!("Failed to open file: {}", e),
-                            rfd::MessageLevel::Error,
-                        )
-                        .await;
-                        datachannel_tx
-                            .send((
-                                channel_id,
-                                serde_json::to_vec(
-                                    &serde_json::json!({ "type": "canceltransfer", "id": id }),
-                                )
-                                .unwrap(),
-                                DatachannelMessageKind::Text
-                            ))
-                            .await
-                            .ok();
-                    }
-                }
-                None => {
-                    // Cancel the transfer
-                    datachannel_tx
-                        .send((
-                            channel_id,
-                            serde_json::to_vec(
-                                &serde_json::json!({ "type": "canceltransfer", "id": id }),
-                            )
-                            .unwrap(),
-                            DatachannelMessageKind::Text
-                        ))
-                        .await
-                        .ok();
-                }
-            }
-            outbound_transfers.lock().unwrap().remove(&id);
-        });
-        // This is technically a race condition: it's possible for the `remove` call above to run
-        // before this call, but the cost of fixing it is not worth it, and it's functionally
-        // impossible to trigger.
-        self.outbound_transfers
-            .lock()
-            .unwrap()
-            .insert(id, handle.abort_handle());
-    }
+    };
+}
 
-    async fn recv(&mut self) -> (ChannelId, Vec<u8>, DatachannelMessageKind) {
-        // .unwrap() is safe here because as long as `self` exists, so do `tx` and `rx`
-        self.rx.recv().await.unwrap()
+/// Write a control message directly to a channel, bypassing the file transfer queue.
+fn send_message(rtc: &mut Rtc, channel_id: ChannelId, message: &ServerMessage) -> Result<()> {
+    if let Some(mut channel) = rtc.channel(channel_id) {
+        channel.write(false, &message.to_vec())?;
     }
+    Ok(())
 }
 
 pub async fn run(
@@ -408,27 +159,82 @@ pub async fn run(
     state: AppState,
     offer: CreateOffer,
     permissions: Permissions,
+    session: SessionGuard,
+    codec: VideoCodec,
 ) -> Result<()> {
     let mut buf = Vec::new();
 
     let mut listener = tcp::Listener::listen(tcp_listener)?;
 
-    let mut file_transfers = FileTransfers::new();
+    let roots = browse::Roots::new(&state.config);
+    // Sessions of the same key can resume each other's downloads. Everyone shares the password,
+    // so a password session only resumes its own.
+    let owner = match (&offer.password, offer.key.as_deref().and_then(|key| key.split_once('.'))) {
+        (None, Some((key_id, _))) => format!("key {}", key_id),
+        _ => format!("session {}", session.id()),
+    };
+    let mut file_transfers = FileTransfers::new(state.transfers.clone(), roots.clone(), state.config.transfer_parallelism, owner);
 
     let fps = if offer.low_power_mode {
         30
     } else {
         60
     };
-    let mut video: (pipeline::ScreenRecordingPipeline, Option<Mid>) = (
-        pipeline::ScreenRecordingPipeline::new(state.config.clone(), offer.show_mouse, fps)?,
-        None,
-    );
-    let mut audio: (pipeline::AudioRecordingPipeline, Option<Mid>) =
-        (pipeline::AudioRecordingPipeline::new().await?, None);
+    let video_key = hub::VideoKey {
+        codec,
+        show_mouse: offer.show_mouse,
+        fps,
+        region: None,
+        window: None,
+    };
+    let mut video = VideoTracks::new(&state, video_key).await?;
+    let mut audio: (hub::AudioSubscription, Option<Mid>) =
+        (state.hub.join_audio().await?, None);
+
+    // Declared after the subscriptions so it is dropped, and the file finalized, first
+    let mut recorder = if state.config.record_sessions || offer.record {
+        let record_audio = cfg!(any(target_os = "linux", target_os = "windows"))
+            && state.config.sound_forwarding;
+        match recording::SessionRecorder::start(
+            &state.config.recording_dir,
+            session.id(),
+            video.primary(),
+            record_audio.then_some(&audio.0),
+        ) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                error!("Failed to start recording session {}: {:?}", session.id(), e);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
     let mut can_write_channel = true;
 
+    // Clients that never send `hello` speak the original protocol
+    let mut handshake = Handshake::legacy();
+    // The channel the client said `hello` on, where unsolicited messages are sent
+    let mut control_channel: Option<ChannelId> = None;
+
+    let mut clipboard_rx = state.clipboard.subscribe();
+
+    // The latest `viewport` and the channel to answer it on, applied once the client settles
+    let mut pending_viewport: Option<(u32, u32, ChannelId)> = None;
+    let mut viewport_deadline = Instant::now();
+
+    // Only touch the registry when the transport actually changes
+    let mut transport = None;
+
+    // Counted between stats reports, which turn them into rates
+    let mut frames_sent = 0u64;
+    let mut input_events = 0u64;
+    let mut last_stats = Instant::now();
+    // Egress byte counters and the bitrate derived from them, per track
+    let mut egress_bytes: HashMap<Mid, (u64, Instant)> = HashMap::new();
+    let mut egress_rates: HashMap<Mid, u64> = HashMap::new();
+
     let ret = loop {
         // Poll output until we get a timeout. The timeout means we are either awaiting UDP socket input
         // or the timeout to happen.
@@ -437,6 +243,16 @@ pub async fn run(
             Output::Timeout(v) => v,
 
             Output::Transmit(v) => {
+                let current = match v.proto {
+                    Protocol::Udp => Some(Transport::Udp),
+                    Protocol::Tcp => Some(Transport::Tcp),
+                    _ => None,
+                };
+                if let Some(current) = current.filter(|c| transport != Some(*c)) {
+                    transport = Some(current);
+                    session.set_transport(current);
+                }
+
                 match v.proto {
                     Protocol::Tcp => listener.send(&v.contents, v.destination).await?,
                     Protocol::Udp => {
@@ -472,10 +288,21 @@ pub async fn run(
                         rtc.bwe()
                             .set_desired_bitrate(Bitrate::kbps(state.config.target_bitrate as u64));
 
+                        let capability = match kind {
+                            MediaKind::Video => Capability::ViewVideo,
+                            MediaKind::Audio => Capability::HearAudio,
+                        };
+                        if !permissions.has(capability) {
+                            info!("Not sending {:?} to a session without the {} capability", kind, capability);
+                            continue;
+                        }
+
                         match kind {
                             MediaKind::Video => {
-                                video.0.start_pipeline();
-                                video.1 = Some(media_added.mid);
+                                if let Err(e) = video.add(&state, media_added.mid).await {
+                                    error!("Failed to add video track {}: {:?}", media_added.mid, e);
+                                }
+                                follow_recording(&mut recorder, &video);
                             }
                             MediaKind::Audio => {
                                 audio.0.start_pipeline();
@@ -483,21 +310,25 @@ pub async fn run(
                             }
                         }
                     }
-                    Event::KeyframeRequest(_) => {
-                        video.0.force_keyframe();
+                    Event::KeyframeRequest(request) => {
+                        session.update_stats(|stats| stats.keyframe_requests += 1);
+                        video.force_keyframe(request.mid);
                     }
                     Event::EgressBitrateEstimate(
                         BweKind::Twcc(bitrate) | BweKind::Remb(_, bitrate),
                     ) => {
-                        let mut bwe = (bitrate.as_u64() / 1000)
-                            .clamp(500, state.config.target_bitrate as u64 + 3000)
-                            as u32;
+                        // Sessions report the estimate itself, not what the encoder is given
+                        let estimate = bitrate.as_u64() / 1000;
+                        session.set_bitrate(estimate.min(u32::MAX as u64) as u32);
+
+                        let mut bwe =
+                            estimate.clamp(500, state.config.target_bitrate as u64 + 3000) as u32;
                         if audio.1.is_some() {
                             bwe -= 96;
                         }
 
-                        video.0.set_bitrate(bwe);
-                        debug!("Set current bitrate to {}", bwe);
+                        video.set_bitrate(bwe);
+                        debug!("Set current bitrate to {} (estimated {})", bwe, estimate);
                     }
                     Event::ChannelData(ChannelData {
                         data,
@@ -506,52 +337,227 @@ pub async fn run(
                         ..
                     }) => {
                         if !binary {
-                            let msg_str = String::from_utf8(data)?;
-                            let cmd: ClientCommand = serde_json::from_str(&msg_str)?;
-                            trace!("Client command: {:#?}", cmd);
-
-                            if cmd.r#type.as_str() == "disconnect" {
-                                info!("Client requested clean disconnect.");
-                                return Ok(());
-                            }
-
-                            match permissions {
-                                Permissions::FullControl => match cmd.r#type.as_str() {
-                                    "requesttransfer" => {
-                                        match (cmd.size, cmd.id) {
-                                            // inbound
-                                            (Some(size), Some(id)) => {
-                                                file_transfers.begin_inbound_transfer(
-                                                    state.dialog_tx.clone(),
-                                                    id as _,
-                                                    channel_id,
-                                                    size,
-                                                );
-                                            }
-                                            // outbound
-                                            (None, Some(id)) => file_transfers.begin_outbound_transfer(state.dialog_tx.clone(), id as _, channel_id),
-                                            _ => warn!(
-                                                "Malformed `requesttransfer` packet: {}",
-                                                msg_str
-                                            ),
+                            let msg = match protocol::parse(&data) {
+                                Ok(msg) => msg,
+                                Err(e) => {
+                                    warn!("Rejected client message: {}", e);
+                                    send_message(&mut rtc, channel_id, &e.into())?;
+                                    continue;
+                                }
+                            };
+                            trace!("Client message: {:#?}", msg);
+
+                            match msg {
+                                ClientMessage::Hello { version, features } => {
+                                    handshake = match Handshake::negotiate(version, &features) {
+                                        Ok(handshake) => handshake,
+                                        Err(e) => {
+                                            warn!("Rejected client hello: {}", e);
+                                            send_message(&mut rtc, channel_id, &e.into())?;
+                                            continue;
                                         }
+                                    };
+                                    file_transfers.set_report_progress(handshake.supports("progress"));
+                                    control_channel = Some(channel_id);
+                                    info!("Negotiated protocol version {} with client: {:?}", handshake.version, handshake);
+                                    send_message(&mut rtc, channel_id, &handshake.reply())?;
+                                    if handshake.supports("monitors") && permissions.has(Capability::ViewVideo) {
+                                        match display::monitors().await {
+                                            Ok(monitors) => send_message(
+                                                &mut rtc,
+                                                channel_id,
+                                                &ServerMessage::Monitors { monitors, current: video.current(), tracks: video.assignments() },
+                                            )?,
+                                            Err(e) => warn!("Failed to list monitors: {:?}", e),
+                                        }
+                                    }
+                                }
+                                ClientMessage::Disconnect => {
+                                    info!("Client requested clean disconnect.");
+                                    return Ok(());
+                                }
+                                _ if !permissions.has_any(msg.required_capabilities()) => {
+                                    let required = msg
+                                        .required_capabilities()
+                                        .iter()
+                                        .map(Capability::as_str)
+                                        .collect::<Vec<_>>()
+                                        .join(" or ");
+                                    warn!("Rejected client message {:?}, it requires {}", msg, required);
+                                    send_message(
+                                        &mut rtc,
+                                        channel_id,
+                                        &ProtocolError::new(
+                                            ErrorCode::Forbidden,
+                                            format!("this session lacks the {} capability", required),
+                                        )
+                                        .into(),
+                                    )?;
+                                }
+                                ClientMessage::RequestTransfer { id, size: Some(size) } => {
+                                    file_transfers.begin_inbound_transfer(
+                                        state.dialog_tx.clone(),
+                                        id,
+                                        channel_id,
+                                        size,
+                                    );
+                                }
+                                ClientMessage::RequestTransfer { id, size: None } => {
+                                    file_transfers.begin_outbound_transfer(state.dialog_tx.clone(), id, channel_id)
+                                }
+                                ClientMessage::TransferReady { .. } => warn!("Received `transferready` packet despite being server. Perhaps update tenebra?"),
+                                ClientMessage::CancelTransfer { id } => file_transfers.cancel_transfer(id),
+                                ClientMessage::OfferFile { id, file } => {
+                                    file_transfers.begin_inbound_transfer_v2(state.dialog_tx.clone(), id, channel_id, file)
+                                }
+                                ClientMessage::RequestFile { id, path, resume } => {
+                                    file_transfers.begin_outbound_transfer_v2(state.dialog_tx.clone(), id, channel_id, path, resume)
+                                }
+                                ClientMessage::OfferArchive { id, name, compression } => {
+                                    file_transfers.begin_archive_upload(state.dialog_tx.clone(), id, channel_id, name, compression)
+                                }
+                                ClientMessage::RequestArchive { id, paths, compression } => {
+                                    file_transfers.begin_archive_download(state.dialog_tx.clone(), id, channel_id, paths, compression)
+                                }
+                                ClientMessage::ListDir { path } => {
+                                    let reply = match roots.list(&path).await {
+                                        Ok(entries) => ServerMessage::DirListing { path, entries },
+                                        Err(e) => ProtocolError::new(ErrorCode::Invalid, e.to_string())
+                                            .with_request("listdir")
+                                            .into(),
+                                    };
+                                    send_message(&mut rtc, channel_id, &reply)?;
+                                }
+                                ClientMessage::Viewport { width, height } => {
+                                    if !state.config.dynamic_resolution {
+                                        let reply: ServerMessage = ProtocolError::new(ErrorCode::Forbidden, "dynamic resolution is disabled on this server")
+                                            .with_request("viewport")
+                                            .into();
+                                        send_message(&mut rtc, channel_id, &reply)?;
+                                    } else {
+                                        // Supersedes any earlier one, which then goes unanswered
+                                        pending_viewport = Some((width, height, channel_id));
+                                        viewport_deadline = Instant::now() + VIEWPORT_SETTLE;
+                                    }
+                                }
+                                ClientMessage::ListMonitors => {
+                                    let reply = match display::monitors().await {
+                                        Ok(monitors) => ServerMessage::Monitors { monitors, current: video.current(), tracks: video.assignments() },
+                                        Err(e) => ProtocolError::new(ErrorCode::Invalid, format!("{:#}", e))
+                                            .with_request("listmonitors")
+                                            .into(),
+                                    };
+                                    send_message(&mut rtc, channel_id, &reply)?;
+                                }
+                                ClientMessage::SelectMonitor { name, mid } => {
+                                    let selected = video.select(&state, mid.as_deref(), name.as_deref()).await;
+                                    follow_recording(&mut recorder, &video);
+                                    let reply = match selected {
+                                        Ok(monitors) => ServerMessage::Monitors { monitors, current: video.current(), tracks: video.assignments() },
+                                        Err(e) => ProtocolError::new(ErrorCode::Invalid, format!("{:#}", e))
+                                            .with_request("selectmonitor")
+                                            .into(),
+                                    };
+                                    send_message(&mut rtc, channel_id, &reply)?;
+                                }
+                                ClientMessage::ListWindows => {
+                                    let reply = match display::windows().await {
+                                        Ok(windows) => ServerMessage::Windows { windows, tracks: video.assignments() },
+                                        Err(e) => ProtocolError::new(ErrorCode::Invalid, format!("{:#}", e))
+                                            .with_request("listwindows")
+                                            .into(),
+                                    };
+                                    send_message(&mut rtc, channel_id, &reply)?;
+                                }
+                                ClientMessage::SelectWindow { id, mid } => {
+                                    let selected = video.select_window(&state, mid.as_deref(), id).await;
+                                    follow_recording(&mut recorder, &video);
+                                    let reply = match selected {
+                                        Ok(windows) => ServerMessage::Windows { windows, tracks: video.assignments() },
+                                        Err(e) => ProtocolError::new(ErrorCode::Invalid, format!("{:#}", e))
+                                            .with_request("selectwindow")
+                                            .into(),
+                                    };
+                                    send_message(&mut rtc, channel_id, &reply)?;
+                                }
+                                ClientMessage::ClipboardSet { items } => {
+                                    match decode_clipboard(&items, state.config.clipboard_max_size) {
+                                        Ok(entries) => state.clipboard.set(entries).await,
+                                        Err(e) => send_message(&mut rtc, channel_id, &e.into())?,
+                                    }
+                                }
+                                ClientMessage::ReleaseAll => {
+                                    state.input_tx.send(InputCommand::ReleaseAll).await?
+                                }
+                                mut msg if msg.is_input() => {
+                                    input_events += 1;
+                                    METRICS.input_events.fetch_add(1, Ordering::Relaxed);
+                                    // Clients point at the video as they receive it, which may be downscaled
+                                    let scale = video.scale(msg.mid());
+                                    if scale < 1.0 {
+                                        msg.map_position(|x, y| ((x as f64 / scale).round() as i32, (y as f64 / scale).round() as i32));
                                     }
-                                    "transferready" => warn!("Received `transferready` packet despite being server. Perhaps update tenebra?"),
-                                    "canceltransfer" => file_transfers.cancel_transfer(cmd.id.context("no id present on canceltransfer packet")? as _),
-                                    _ => {
-                                        state
-                                            .input_tx
-                                            .send(InputCommand::ClientCommand(cmd))
-                                            .await?
+                                    match video.origin(&state, msg.mid(), msg.position()) {
+                                        Some(origin) => state.input_tx.send(InputCommand::Input(msg, origin)).await?,
+                                        None => trace!("Dropping input outside the captured window"),
                                     }
-                                },
-                                _ => error!("Rejected input command: {:?}", cmd),
+                                }
+                                msg => warn!("Unhandled client message: {:?}", msg),
                             }
+                        } else if data.len() < 4 {
+                            send_message(
+                                &mut rtc,
+                                channel_id,
+                                &ProtocolError::new(ErrorCode::Malformed, "file chunk is missing its transfer id").into(),
+                            )?;
                         } else {
                             // File segment packet
                             let id = u32::from_be_bytes(data[0..4].try_into()?);
                             let chunk = data[4..].to_vec();
-                            file_transfers.handle_inbound_file_chunk(id, chunk).await?;
+                            match file_transfers.handle_inbound_file_chunk(id, chunk).await {
+                                Ok(len) => {
+                                    METRICS.file_bytes_received.fetch_add(len, Ordering::Relaxed);
+                                    session.update_stats(|stats| stats.file_bytes_received += len);
+                                }
+                                Err(e) => {
+                                    warn!("Dropped file chunk: {}", e);
+                                    send_message(
+                                        &mut rtc,
+                                        channel_id,
+                                        &ProtocolError::new(ErrorCode::Invalid, e.to_string()).into(),
+                                    )?;
+                                }
+                            }
+                        }
+                    }
+                    Event::MediaEgressStats(egress) => {
+                        let previous = egress_bytes.insert(egress.mid, (egress.bytes, egress.timestamp));
+                        if let Some((bytes, at)) = previous {
+                            let secs = egress.timestamp.saturating_duration_since(at).as_secs_f64();
+                            if secs > 0.0 {
+                                let rate = (egress.bytes.saturating_sub(bytes) * 8) as f64 / secs;
+                                egress_rates.insert(egress.mid, rate as u64);
+                            }
+                        }
+
+                        // Video is reported once per interval, so use it to pace the per-session rates
+                        if video.is_primary(egress.mid) {
+                            let secs = last_stats.elapsed().as_secs_f64();
+                            last_stats = Instant::now();
+                            let fps = frames_sent as f64 / secs;
+                            let input_rate = input_events as f64 / secs;
+                            frames_sent = 0;
+                            input_events = 0;
+                            let bitrate_sent = egress_rates.values().sum();
+                            session.update_stats(|stats| {
+                                stats.fps = fps;
+                                stats.input_events_per_second = input_rate;
+                                stats.bitrate_sent = bitrate_sent;
+                                stats.rtt_ms = egress.rtt.map(f64::from);
+                                stats.packet_loss = egress.loss.map(f64::from);
+                                stats.nacks = egress.nacks;
+                                stats.plis = egress.plis;
+                            });
                         }
                     }
                     Event::IceConnectionStateChange(connection_state) => {
@@ -583,10 +589,18 @@ pub async fn run(
 
         let input = tokio::select! {
             _ = tokio::time::sleep_until(time.into()) => Input::Timeout(Instant::now()),
+            _ = session.kicked() => {
+                info!("Session {} was terminated by an administrator.", session.id());
+                break Ok(());
+            }
             (channel_id, data, kind) = file_transfers.recv(), if can_write_channel => {
                 let channel = rtc.channel(channel_id);
                 if let Some(mut channel) = channel {
                     channel.write(kind.is_binary(), &data)?;
+                    if let DatachannelMessageKind::FileChunk(len) = kind {
+                        METRICS.file_bytes_sent.fetch_add(len, Ordering::Relaxed);
+                        session.update_stats(|stats| stats.file_bytes_sent += len);
+                    }
                     if channel.buffered_amount() > 512 * 1024 {
                         can_write_channel = false;
                     }
@@ -595,19 +609,55 @@ pub async fn run(
                 }
                 Input::Timeout(Instant::now())
             }
-            Some((buf, pts)) = video.0.recv_frame(), if video.1.is_some() => {
+            _ = tokio::time::sleep_until(viewport_deadline.into()), if pending_viewport.is_some() => {
+                let (width, height, channel_id) = pending_viewport.take().unwrap();
+                let reply = match state.resolution.request(session.id(), width, height).await {
+                    Ok(resized) => {
+                        if resized.changed {
+                            state.hub.restart_video().await;
+                        }
+                        ServerMessage::Resolution { width: resized.width, height: resized.height }
+                    }
+                    Err(e) => ProtocolError::new(ErrorCode::Invalid, format!("{:#}", e))
+                        .with_request("viewport")
+                        .into(),
+                };
+                send_message(&mut rtc, channel_id, &reply)?;
+                Input::Timeout(Instant::now())
+            }
+            contents = clipboard_rx.recv(), if handshake.supports("clipboard") && control_channel.is_some() => {
+                match contents {
+                    Ok(contents) if permissions.has(Capability::ClipboardRead) => {
+                        let items = contents.iter().map(ClipboardEntry::to_item).collect();
+                        send_message(&mut rtc, control_channel.unwrap(), &ServerMessage::Clipboard { items })?;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => warn!("Clipboard synchronization stopped."),
+                }
+                Input::Timeout(Instant::now())
+            }
+            Some((mid, (buf, pts))) = video.recv_frame() => {
                 let writer = rtc
-                    .writer(video.1.unwrap())
+                    .writer(mid)
                     .context("couldn't get rtc writer")?
                     .playout_delay(MediaTime::ZERO, MediaTime::ZERO);
                 let pt = writer
                     .payload_params()
-                    .find(|&params| params.spec().codec == Codec::H264)
+                    .find(|&params| params.spec().codec == Codec::from(codec))
                     .unwrap()
                     .pt();
                 let now = Instant::now();
                 let map = buf.map_readable().context("Failed to map video buffer")?;
                 writer.write(pt, now, MediaTime::from_micros(pts), map.as_slice())?;
+                if video.is_primary(mid) {
+                    frames_sent += 1;
+                }
+                if let Some(quality) = video.quality_changed(mid) {
+                    if let Some(channel_id) = control_channel.filter(|_| handshake.supports("adaptation")) {
+                        send_message(&mut rtc, channel_id, &ServerMessage::Quality { mid: mid.to_string(), fps: quality.fps, scale: quality.scale })?;
+                    }
+                }
                 Input::Timeout(Instant::now())
             }
             Some((buf, pts)) = audio.0.recv_frame(), if audio.1.is_some() => {
@@ -0,0 +1,290 @@
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// How far the estimate has to rise above a threshold before quality steps back up.
+const HYSTERESIS: f64 = 1.25;
+/// How long the estimate has to stay that high before quality steps back up.
+const STEP_UP_AFTER: Duration = Duration::from_secs(5);
+
+/// When to trade framerate and resolution for bitrate.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AdaptationConfig {
+    // Below this bandwidth estimate, in Kbit/s, the framerate is lowered
+    #[serde(default = "default_reduce_fps_below")]
+    pub reduce_fps_below: u32,
+    #[serde(default = "default_reduced_fps")]
+    pub reduced_fps: i32,
+    // Below this bandwidth estimate, in Kbit/s, the video is downscaled as well
+    #[serde(default = "default_downscale_below")]
+    pub downscale_below: u32,
+    #[serde(default = "default_downscale")]
+    pub downscale: f64,
+}
+
+impl Display for AdaptationConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} fps below {} Kbit/s, {}% size below {} Kbit/s",
+            self.reduced_fps,
+            self.reduce_fps_below,
+            (self.downscale * 100.0).round(),
+            self.downscale_below
+        )
+    }
+}
+
+fn default_reduce_fps_below() -> u32 {
+    1500
+}
+
+fn default_reduced_fps() -> i32 {
+    15
+}
+
+fn default_downscale_below() -> u32 {
+    800
+}
+
+fn default_downscale() -> f64 {
+    0.5
+}
+
+/// The framerate and scale a video pipeline encodes at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    pub fps: i32,
+    pub scale: f64,
+}
+
+impl Quality {
+    pub fn full(fps: i32) -> Self {
+        Self { fps, scale: 1.0 }
+    }
+}
+
+/// Steps a pipeline down from full quality to a lower framerate, and then to a smaller size,
+/// as the bandwidth estimate drops. It steps back up one level at a time, once the estimate
+/// has recovered with some headroom for a while.
+#[derive(Debug)]
+pub struct Adaptation {
+    config: AdaptationConfig,
+    fps: i32,
+    // 0 is full quality, 1 a lower framerate, 2 also downscaled
+    level: usize,
+    recovering_since: Option<Instant>,
+}
+
+impl Adaptation {
+    pub fn new(config: AdaptationConfig, fps: i32) -> Self {
+        Self {
+            config,
+            fps,
+            level: 0,
+            recovering_since: None,
+        }
+    }
+
+    pub fn quality(&self) -> Quality {
+        Quality {
+            fps: match self.level {
+                0 => self.fps,
+                _ => self.fps.min(self.config.reduced_fps),
+            },
+            scale: match self.level {
+                0 | 1 => 1.0,
+                _ => self.config.downscale.clamp(0.1, 1.0),
+            },
+        }
+    }
+
+    /// Feed a new bandwidth estimate, in Kbit/s. Returns the new quality if it changed.
+    pub fn update(&mut self, bitrate: u32) -> Option<Quality> {
+        self.update_at(bitrate, Instant::now())
+    }
+
+    /// [`Adaptation::update`] with an estimate that arrived at `now`.
+    fn update_at(&mut self, bitrate: u32, now: Instant) -> Option<Quality> {
+        let thresholds = [self.config.reduce_fps_below, self.config.downscale_below];
+        let target = thresholds
+            .iter()
+            .filter(|&&threshold| bitrate < threshold)
+            .count();
+        if target > self.level {
+            self.level = target;
+            self.recovering_since = None;
+            return Some(self.quality());
+        }
+
+        let threshold = match self.level {
+            0 => return None,
+            level => thresholds[level - 1],
+        };
+        if (bitrate as f64) < threshold as f64 * HYSTERESIS {
+            self.recovering_since = None;
+            return None;
+        }
+        let since = *self.recovering_since.get_or_insert(now);
+        if now.duration_since(since) < STEP_UP_AFTER {
+            return None;
+        }
+        self.level -= 1;
+        self.recovering_since = None;
+        Some(self.quality())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FPS: i32 = 60;
+
+    fn adaptation() -> Adaptation {
+        Adaptation::new(
+            AdaptationConfig {
+                reduce_fps_below: default_reduce_fps_below(),
+                reduced_fps: default_reduced_fps(),
+                downscale_below: default_downscale_below(),
+                downscale: default_downscale(),
+            },
+            FPS,
+        )
+    }
+
+    fn reduced() -> Quality {
+        Quality {
+            fps: 15,
+            scale: 1.0,
+        }
+    }
+
+    fn downscaled() -> Quality {
+        Quality {
+            fps: 15,
+            scale: 0.5,
+        }
+    }
+
+    #[test]
+    fn full_quality_above_the_thresholds() {
+        let mut adaptation = adaptation();
+        let now = Instant::now();
+        assert_eq!(adaptation.quality(), Quality::full(FPS));
+        assert_eq!(adaptation.update_at(5000, now), None);
+        assert_eq!(adaptation.update_at(1500, now), None);
+        assert_eq!(adaptation.quality(), Quality::full(FPS));
+    }
+
+    #[test]
+    fn steps_down_below_each_threshold() {
+        let mut adaptation = adaptation();
+        let now = Instant::now();
+        assert_eq!(adaptation.update_at(1499, now), Some(reduced()));
+        assert_eq!(adaptation.update_at(1000, now), None);
+        assert_eq!(adaptation.update_at(799, now), Some(downscaled()));
+        assert_eq!(adaptation.update_at(100, now), None);
+        assert_eq!(adaptation.quality(), downscaled());
+    }
+
+    #[test]
+    fn jumps_two_levels_at_once() {
+        let mut adaptation = adaptation();
+        assert_eq!(
+            adaptation.update_at(500, Instant::now()),
+            Some(downscaled())
+        );
+    }
+
+    #[test]
+    fn the_framerate_never_rises() {
+        let mut adaptation = Adaptation::new(adaptation().config, 10);
+        let quality = adaptation.update_at(1000, Instant::now()).unwrap();
+        assert_eq!(quality.fps, 10);
+    }
+
+    #[test]
+    fn steps_up_only_with_headroom() {
+        let mut adaptation = adaptation();
+        let start = Instant::now();
+        adaptation.update_at(1000, start);
+        // Back above the threshold, but not by 25%
+        let later = start + STEP_UP_AFTER * 10;
+        assert_eq!(adaptation.update_at(1874, start), None);
+        assert_eq!(adaptation.update_at(1874, later), None);
+        assert_eq!(adaptation.quality(), reduced());
+        // 1500 * 1.25
+        assert_eq!(adaptation.update_at(1875, later), None);
+        assert_eq!(
+            adaptation.update_at(1875, later + STEP_UP_AFTER),
+            Some(Quality::full(FPS))
+        );
+    }
+
+    #[test]
+    fn steps_up_after_a_delay() {
+        let mut adaptation = adaptation();
+        let start = Instant::now();
+        adaptation.update_at(1000, start);
+        assert_eq!(adaptation.update_at(3000, start), None);
+        assert_eq!(
+            adaptation.update_at(3000, start + STEP_UP_AFTER - Duration::from_millis(1)),
+            None
+        );
+        assert_eq!(
+            adaptation.update_at(3000, start + STEP_UP_AFTER),
+            Some(Quality::full(FPS))
+        );
+    }
+
+    #[test]
+    fn a_dip_restarts_the_delay() {
+        let mut adaptation = adaptation();
+        let start = Instant::now();
+        adaptation.update_at(1000, start);
+        adaptation.update_at(3000, start);
+        // Still above the threshold, but not with headroom
+        adaptation.update_at(1600, start + Duration::from_secs(3));
+        let resumed = start + Duration::from_secs(4);
+        assert_eq!(adaptation.update_at(3000, resumed), None);
+        assert_eq!(adaptation.update_at(3000, start + STEP_UP_AFTER), None);
+        assert_eq!(
+            adaptation.update_at(3000, resumed + STEP_UP_AFTER),
+            Some(Quality::full(FPS))
+        );
+    }
+
+    #[test]
+    fn steps_up_one_level_at_a_time() {
+        let mut adaptation = adaptation();
+        let start = Instant::now();
+        adaptation.update_at(100, start);
+        assert_eq!(adaptation.quality(), downscaled());
+        // Far above both thresholds, but only one level goes at a time
+        assert_eq!(adaptation.update_at(10_000, start), None);
+        let first = start + STEP_UP_AFTER;
+        assert_eq!(adaptation.update_at(10_000, first), Some(reduced()));
+        assert_eq!(adaptation.update_at(10_000, first), None);
+        assert_eq!(
+            adaptation.update_at(10_000, first + STEP_UP_AFTER),
+            Some(Quality::full(FPS))
+        );
+    }
+
+    #[test]
+    fn steps_up_from_downscaled_with_headroom_over_its_threshold() {
+        let mut adaptation = adaptation();
+        let start = Instant::now();
+        adaptation.update_at(100, start);
+        // Just short of 800 * 1.25
+        adaptation.update_at(999, start);
+        assert_eq!(adaptation.update_at(999, start + STEP_UP_AFTER), None);
+        adaptation.update_at(1000, start + STEP_UP_AFTER);
+        assert_eq!(
+            adaptation.update_at(1000, start + STEP_UP_AFTER * 2),
+            Some(reduced())
+        );
+    }
+}
@@ -0,0 +1,545 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+
+use log::*;
+
+use sha2::{Digest, Sha256};
+
+use str0m::channel::ChannelId;
+
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use super::progress::Meter;
+use super::transfer::{DatachannelMessageKind, CHUNK_SIZE, IDLE_TIMEOUT};
+use crate::protocol::{Compression, ServerMessage};
+
+type Outgoing = Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>;
+
+/// Called with the archive path and size of each file packed or unpacked.
+type FileDone<'a> = dyn FnMut(&Path, u64) -> io::Result<()> + 'a;
+
+/// Reports each file as it is packed or unpacked.
+pub(super) struct Progress {
+    tx: Outgoing,
+    channel_id: ChannelId,
+    id: u32,
+    files: u64,
+    bytes: u64,
+}
+
+impl Progress {
+    pub(super) fn new(tx: Outgoing, channel_id: ChannelId, id: u32) -> Self {
+        Self {
+            tx,
+            channel_id,
+            id,
+            files: 0,
+            bytes: 0,
+        }
+    }
+
+    fn file_done(&mut self, path: &Path, size: u64) -> io::Result<()> {
+        self.files += 1;
+        self.bytes += size;
+        let message = ServerMessage::ArchiveProgress {
+            id: self.id,
+            // Archive paths always use forward slashes
+            path: path.to_string_lossy().replace('\\', "/"),
+            files: self.files,
+            bytes: self.bytes,
+        };
+        self.tx
+            .blocking_send((
+                self.channel_id,
+                message.to_vec(),
+                DatachannelMessageKind::Text,
+            ))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the session ended"))
+    }
+}
+
+/// Presents the chunks of an upload as one stream, checking that their offsets line up.
+pub(super) struct ChunkReader {
+    rx: Receiver<(u64, Vec<u8>)>,
+    chunk: Vec<u8>,
+    pos: usize,
+    offset: u64,
+    hasher: Sha256,
+    meter: Meter,
+    // Lets the blocking thread wait for a chunk with a timeout
+    runtime: Handle,
+}
+
+impl ChunkReader {
+    /// Must be called from within the runtime.
+    pub(super) fn new(rx: Receiver<(u64, Vec<u8>)>, meter: Meter) -> Self {
+        Self {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+            offset: 0,
+            hasher: Sha256::new(),
+            meter,
+            runtime: Handle::current(),
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            let next = self
+                .runtime
+                .block_on(tokio::time::timeout(IDLE_TIMEOUT, self.rx.recv()))
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("received nothing for {:?}", IDLE_TIMEOUT),
+                    )
+                })?;
+            let (offset, chunk) = next.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the transfer ended before the archive did",
+                )
+            })?;
+            if offset != self.offset {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("expected a chunk at offset {}, got {}", self.offset, offset),
+                ));
+            }
+            self.offset += chunk.len() as u64;
+            self.hasher.update(&chunk);
+            self.meter.advance_blocking(chunk.len() as u64);
+            self.chunk = chunk;
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Cuts a download into v2 chunks and queues them on the datachannel.
+pub(super) struct ChunkWriter {
+    tx: Outgoing,
+    channel_id: ChannelId,
+    id: u32,
+    buf: Vec<u8>,
+    offset: u64,
+    hasher: Sha256,
+    // Cleared when the transfer is cancelled, since the blocking thread can't be aborted
+    alive: Arc<AtomicBool>,
+    meter: Meter,
+}
+
+impl ChunkWriter {
+    pub(super) fn new(
+        tx: Outgoing,
+        channel_id: ChannelId,
+        id: u32,
+        alive: Arc<AtomicBool>,
+        meter: Meter,
+    ) -> Self {
+        Self {
+            tx,
+            channel_id,
+            id,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            offset: 0,
+            hasher: Sha256::new(),
+            alive,
+            meter,
+        }
+    }
+
+    fn send(&mut self, n: usize) -> io::Result<()> {
+        if !self.alive.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the transfer was cancelled",
+            ));
+        }
+        let data: Vec<u8> = self.buf.drain(..n).collect();
+        self.hasher.update(&data);
+        let mut v = Vec::with_capacity(12 + n);
+        v.extend_from_slice(&self.id.to_be_bytes());
+        v.extend_from_slice(&self.offset.to_be_bytes());
+        v.extend_from_slice(&data);
+        self.tx
+            .blocking_send((
+                self.channel_id,
+                v,
+                DatachannelMessageKind::FileChunk(n as u64),
+            ))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the session ended"))?;
+        self.offset += n as u64;
+        self.meter.advance_blocking(n as u64);
+        Ok(())
+    }
+
+    /// Send whatever is left, returning the number of bytes sent and their digest.
+    fn finish(mut self) -> io::Result<(u64, String)> {
+        if !self.buf.is_empty() {
+            self.send(self.buf.len())?;
+        }
+        Ok((self.offset, format!("{:x}", self.hasher.finalize())))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= CHUNK_SIZE {
+            self.send(CHUNK_SIZE)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Partial chunks wait for more data, `finish` sends the last one
+        Ok(())
+    }
+}
+
+/// Unpack an uploaded archive into `dest` as it arrives. Returns the number of archive bytes
+/// received and their digest.
+pub(super) fn unpack(
+    mut reader: ChunkReader,
+    compression: Compression,
+    dest: &Path,
+    progress: &mut Progress,
+) -> Result<(u64, String)> {
+    unpack_from(&mut reader, compression, dest, &mut |path, size| {
+        progress.file_done(path, size)
+    })?;
+    Ok((reader.offset, format!("{:x}", reader.hasher.finalize())))
+}
+
+fn unpack_from(
+    reader: impl Read,
+    compression: Compression,
+    dest: &Path,
+    file_done: &mut FileDone,
+) -> Result<()> {
+    fs::create_dir_all(dest).context("Failed to create the destination directory")?;
+    match compression {
+        Compression::None => unpack_entries(reader, dest, file_done),
+        Compression::Zstd => {
+            let decoder = zstd::Decoder::new(reader)?.single_frame();
+            unpack_entries(decoder, dest, file_done)
+        }
+    }
+}
+
+fn unpack_entries(reader: impl Read, dest: &Path, file_done: &mut FileDone) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_mtime(true);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let kind = entry.header().entry_type();
+        let path = entry.path()?.into_owned();
+        // Links could point anywhere on the host
+        if !kind.is_file() && !kind.is_dir() {
+            warn!(
+                "Skipping {} in archive, only files and directories are unpacked",
+                path.display()
+            );
+            continue;
+        }
+        let size = entry.size();
+        // `unpack_in` refuses absolute paths, `..` and anything else that would escape `dest`
+        if !entry.unpack_in(dest)? {
+            bail!(
+                "`{}` would be unpacked outside of the destination",
+                path.display()
+            );
+        }
+        if kind.is_file() {
+            file_done(&path, size)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stream `sources`, each at the top of the archive under its own name. Returns the number of
+/// archive bytes sent and their digest.
+pub(super) fn pack(
+    sources: &[PathBuf],
+    compression: Compression,
+    writer: ChunkWriter,
+    progress: &mut Progress,
+) -> Result<(u64, String)> {
+    let writer = pack_into(writer, sources, compression, &mut |path, size| {
+        progress.file_done(path, size)
+    })?;
+    Ok(writer.finish()?)
+}
+
+fn pack_into<W: Write>(
+    writer: W,
+    sources: &[PathBuf],
+    compression: Compression,
+    file_done: &mut FileDone,
+) -> Result<W> {
+    match compression {
+        Compression::None => build(writer, sources, file_done),
+        Compression::Zstd => {
+            // Level 0 is zstd's default
+            let encoder = zstd::Encoder::new(writer, 0)?;
+            Ok(build(encoder, sources, file_done)?.finish()?)
+        }
+    }
+}
+
+fn build<W: Write>(writer: W, sources: &[PathBuf], file_done: &mut FileDone) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    for source in sources {
+        let name = source
+            .file_name()
+            .context("cannot archive a filesystem root")?;
+        append_tree(&mut builder, source, Path::new(name), file_done)?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+fn append_tree<W: Write>(
+    builder: &mut tar::Builder<W>,
+    source: &Path,
+    name: &Path,
+    file_done: &mut FileDone,
+) -> Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    if metadata.is_dir() {
+        builder.append_dir(name, source)?;
+        let mut children = fs::read_dir(source)?.collect::<io::Result<Vec<_>>>()?;
+        children.sort_by_key(|child| child.file_name());
+        for child in children {
+            append_tree(
+                builder,
+                &child.path(),
+                &name.join(child.file_name()),
+                file_done,
+            )?;
+        }
+    } else if metadata.is_file() {
+        builder.append_path_with_name(source, name)?;
+        file_done(name, metadata.len())?;
+    } else {
+        // Symlinks are left out, they could lead outside of what was shared
+        debug!("Skipping {} while archiving", source.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    use tar::{EntryType, Header};
+
+    fn pack_to_vec(sources: &[PathBuf], compression: Compression) -> (Vec<u8>, Vec<PathBuf>) {
+        let mut packed = Vec::new();
+        let archive = pack_into(Vec::new(), sources, compression, &mut |path, _| {
+            packed.push(path.to_path_buf());
+            Ok(())
+        })
+        .unwrap();
+        (archive, packed)
+    }
+
+    fn unpack_slice(archive: &[u8], compression: Compression, dest: &Path) -> Result<()> {
+        unpack_from(archive, compression, dest, &mut |_, _| Ok(()))
+    }
+
+    /// An archive holding a single entry, with its path written as is so that the tar crate
+    /// doesn't refuse to build hostile ones.
+    fn raw_archive(kind: EntryType, path: &str, link: Option<&str>, data: &[u8]) -> Vec<u8> {
+        let mut header = Header::new_gnu();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        if let Some(link) = link {
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+        }
+        header.set_entry_type(kind);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(1_000_000_000);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, data).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn round_trip(compression: Compression) {
+        let src = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let folder = src.path().join("folder");
+        fs::create_dir_all(folder.join("empty")).unwrap();
+        fs::create_dir_all(folder.join("sub")).unwrap();
+        fs::write(folder.join("a.txt"), "hello").unwrap();
+        fs::write(folder.join("sub").join("b.bin"), vec![7u8; 100_000]).unwrap();
+        fs::write(src.path().join("single.txt"), "single").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        fs::File::options()
+            .write(true)
+            .open(folder.join("a.txt"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let (archive, packed) = pack_to_vec(
+            &[folder.clone(), src.path().join("single.txt")],
+            compression,
+        );
+        assert_eq!(
+            packed,
+            [
+                Path::new("folder/a.txt"),
+                Path::new("folder/sub/b.bin"),
+                Path::new("single.txt"),
+            ]
+        );
+
+        let mut unpacked = Vec::new();
+        unpack_from(
+            archive.as_slice(),
+            compression,
+            dest.path(),
+            &mut |path, size| {
+                unpacked.push((path.to_path_buf(), size));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            unpacked,
+            [
+                (PathBuf::from("folder/a.txt"), 5),
+                (PathBuf::from("folder/sub/b.bin"), 100_000),
+                (PathBuf::from("single.txt"), 6),
+            ]
+        );
+
+        let out = dest.path().join("folder");
+        assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(
+            fs::read(out.join("sub").join("b.bin")).unwrap(),
+            vec![7u8; 100_000]
+        );
+        assert!(out.join("empty").is_dir());
+        assert_eq!(fs::read(dest.path().join("single.txt")).unwrap(), b"single");
+        assert_eq!(
+            fs::metadata(out.join("a.txt")).unwrap().modified().unwrap(),
+            mtime
+        );
+    }
+
+    #[test]
+    fn round_trip_uncompressed() {
+        round_trip(Compression::None);
+    }
+
+    #[test]
+    fn round_trip_zstd() {
+        round_trip(Compression::Zstd);
+    }
+
+    #[test]
+    fn zstd_archives_are_compressed() {
+        let src = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("zeros"), vec![0u8; 1_000_000]).unwrap();
+        let sources = [src.path().join("zeros")];
+        let (plain, _) = pack_to_vec(&sources, Compression::None);
+        let (compressed, _) = pack_to_vec(&sources, Compression::Zstd);
+        assert!(compressed.len() < plain.len() / 100);
+        // Neither can be read as the other
+        let dest = tempfile::tempdir().unwrap();
+        assert!(unpack_slice(&compressed, Compression::None, dest.path()).is_err());
+        assert!(unpack_slice(&plain, Compression::Zstd, dest.path()).is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn symlinks_are_not_packed() {
+        let src = tempfile::tempdir().unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let folder = src.path().join("folder");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", folder.join("passwd")).unwrap();
+        std::os::unix::fs::symlink("/etc", folder.join("etc")).unwrap();
+
+        let (archive, packed) = pack_to_vec(&[folder], Compression::None);
+        assert_eq!(packed, [Path::new("folder/a.txt")]);
+        unpack_slice(&archive, Compression::None, dest.path()).unwrap();
+        let out = dest.path().join("folder");
+        assert!(out.join("a.txt").is_file());
+        assert!(fs::symlink_metadata(out.join("passwd")).is_err());
+        assert!(fs::symlink_metadata(out.join("etc")).is_err());
+    }
+
+    #[test]
+    fn parent_paths_are_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("dest");
+        for path in ["../evil.txt", "sub/../../evil.txt"] {
+            let archive = raw_archive(EntryType::Regular, path, None, b"evil");
+            assert!(unpack_slice(&archive, Compression::None, &dest).is_err());
+            assert!(!tmp.path().join("evil.txt").exists());
+        }
+    }
+
+    #[test]
+    fn absolute_paths_stay_in_the_destination() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("dest");
+        let target = tmp.path().join("evil.txt");
+        let archive = raw_archive(EntryType::Regular, target.to_str().unwrap(), None, b"evil");
+        unpack_slice(&archive, Compression::None, &dest).unwrap();
+        assert!(!target.exists());
+        let inside = target.components().skip(1).collect::<PathBuf>();
+        assert_eq!(fs::read(dest.join(inside)).unwrap(), b"evil");
+    }
+
+    #[test]
+    fn links_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("dest");
+        let secret = tmp.path().join("secret.txt");
+        fs::write(&secret, "secret").unwrap();
+        for kind in [EntryType::Symlink, EntryType::Link] {
+            let archive = raw_archive(kind, "link", Some(secret.to_str().unwrap()), b"");
+            unpack_slice(&archive, Compression::None, &dest).unwrap();
+            assert!(fs::symlink_metadata(dest.join("link")).is_err());
+        }
+        let archive = raw_archive(EntryType::Symlink, "up", Some(".."), b"");
+        unpack_slice(&archive, Compression::None, &dest).unwrap();
+        assert!(fs::symlink_metadata(dest.join("up")).is_err());
+        assert_eq!(fs::read(&secret).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn mtimes_are_kept() {
+        let tmp = tempfile::tempdir().unwrap();
+        let archive = raw_archive(EntryType::Regular, "old.txt", None, b"old");
+        unpack_slice(&archive, Compression::None, tmp.path()).unwrap();
+        assert_eq!(
+            fs::metadata(tmp.path().join("old.txt"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+
+use super::transfer::part_path;
+use crate::protocol::DirEntry;
+use crate::Config;
+
+/// The host directories clients can reach without anyone picking files at the host.
+///
+/// Clients name files with virtual paths such as `docs/report.pdf`, whose first component is a
+/// key of `shared_dirs`. Nothing outside those directories can be reached, symlinks included.
+#[derive(Debug, Clone, Default)]
+pub struct Roots {
+    shared: BTreeMap<String, PathBuf>,
+    drop_dir: Option<PathBuf>,
+}
+
+impl Roots {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            shared: config.shared_dirs.clone(),
+            drop_dir: config.drop_dir.clone(),
+        }
+    }
+
+    pub fn has_drop_dir(&self) -> bool {
+        self.drop_dir.is_some()
+    }
+
+    /// Map a virtual path to an existing file or directory on the host.
+    pub async fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let name = components
+            .next()
+            .context("the path does not name a shared directory")?;
+        let root = self
+            .shared
+            .get(name)
+            .with_context(|| format!("there is no shared directory called `{}`", name))?;
+        let mut resolved = root.clone();
+        for component in components {
+            if !is_plain_name(component) {
+                bail!("`{}` is not allowed in a path", component);
+            }
+            resolved.push(component);
+        }
+
+        // Components are plain names, but a symlink could still lead out of the root
+        let root = tokio::fs::canonicalize(root)
+            .await
+            .with_context(|| format!("shared directory `{}` is unavailable", name))?;
+        let resolved = tokio::fs::canonicalize(&resolved)
+            .await
+            .with_context(|| format!("`{}` does not exist", path))?;
+        if !resolved.starts_with(&root) {
+            bail!("`{}` is outside of its shared directory", path);
+        }
+        Ok(resolved)
+    }
+
+    /// List a shared directory. The empty path lists the shared directories themselves.
+    pub async fn list(&self, path: &str) -> Result<Vec<DirEntry>> {
+        if path.split('/').all(str::is_empty) {
+            return Ok(self
+                .shared
+                .keys()
+                .map(|name| DirEntry {
+                    name: name.clone(),
+                    dir: true,
+                    size: 0,
+                    modified: 0,
+                })
+                .collect());
+        }
+
+        let dir = self.resolve(path).await?;
+        let mut read_dir = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("`{}` is not a readable directory", path))?;
+        let mut entries = Vec::new();
+        while let Some(entry) = read_dir.next_entry().await? {
+            // Skip anything that vanished or can't be inspected rather than failing the listing
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            entries.push(DirEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+            });
+        }
+        entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+        Ok(entries)
+    }
+
+    /// Pick a path in the drop directory for an upload called `name`, never overwriting a file.
+    pub async fn drop_path(&self, name: &str) -> Result<PathBuf> {
+        let dir = self
+            .drop_dir
+            .as_ref()
+            .context("no drop directory is configured")?;
+        tokio::fs::create_dir_all(dir)
+            .await
+            .context("Failed to create the drop directory")?;
+        unique_path(dir, name).await
+    }
+}
+
+/// A path in `dir` for something called `name` that doesn't exist yet, adding ` (1)`, ` (2)`
+/// and so on to the name as needed.
+pub async fn unique_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    if !is_plain_name(name) {
+        bail!("`{}` is not a valid file name", name);
+    }
+    let path = Path::new(name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let mut candidate = dir.join(name);
+    let mut n = 1;
+    // An unfinished upload will move into place later, so its name is taken too
+    while tokio::fs::try_exists(&candidate).await?
+        || tokio::fs::try_exists(part_path(&candidate)).await?
+    {
+        candidate = dir.join(format!("{} ({}){}", stem, n, extension));
+        n += 1;
+    }
+    Ok(candidate)
+}
+
+/// Whether `name` is a single, ordinary path component on every platform we run on.
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.contains(['/', '\\', '\0'])
+        // Drive prefixes and alternate data streams
+        && !(cfg!(target_os = "windows") && name.contains(':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    /// A shared directory called `docs` holding `a.txt` and `sub/b.txt`, and a file outside of
+    /// it.
+    fn fixture() -> (TempDir, Roots) {
+        let tmp = tempfile::tempdir().unwrap();
+        let docs = tmp.path().join("docs");
+        std::fs::create_dir_all(docs.join("sub")).unwrap();
+        std::fs::write(docs.join("a.txt"), "a").unwrap();
+        std::fs::write(docs.join("sub").join("b.txt"), "b").unwrap();
+        std::fs::write(tmp.path().join("secret.txt"), "secret").unwrap();
+        let roots = Roots {
+            shared: BTreeMap::from([("docs".to_string(), docs)]),
+            drop_dir: None,
+        };
+        (tmp, roots)
+    }
+
+    #[tokio::test]
+    async fn resolves_paths_inside_the_root() {
+        let (tmp, roots) = fixture();
+        let docs = tmp.path().join("docs").canonicalize().unwrap();
+        assert_eq!(roots.resolve("docs").await.unwrap(), docs);
+        assert_eq!(roots.resolve("/docs/").await.unwrap(), docs);
+        assert_eq!(
+            roots.resolve("docs/sub/b.txt").await.unwrap(),
+            docs.join("sub").join("b.txt")
+        );
+        assert_eq!(
+            roots.resolve("docs//a.txt").await.unwrap(),
+            docs.join("a.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_paths_leaving_the_root() {
+        let (_tmp, roots) = fixture();
+        for path in [
+            "",
+            "secret.txt",
+            "docs/..",
+            "docs/../secret.txt",
+            "docs/sub/../../secret.txt",
+            "docs/./a.txt",
+            "docs/sub\\..\\..\\secret.txt",
+            "docs/a.txt\0",
+            "docs/missing.txt",
+        ] {
+            assert!(roots.resolve(path).await.is_err(), "{:?} resolved", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_absolute_and_prefixed_paths() {
+        let (tmp, roots) = fixture();
+        let secret = tmp.path().join("secret.txt");
+        let absolute = format!("docs/{}", secret.display());
+        assert!(roots.resolve(&absolute).await.is_err());
+        assert!(roots.resolve("docs/C:\\Windows").await.is_err());
+        assert!(roots.resolve("C:/docs/a.txt").await.is_err());
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn refuses_symlinks_out_of_the_root() {
+        let (tmp, roots) = fixture();
+        let docs = tmp.path().join("docs");
+        std::os::unix::fs::symlink(tmp.path().join("secret.txt"), docs.join("file")).unwrap();
+        std::os::unix::fs::symlink(tmp.path(), docs.join("dir")).unwrap();
+        std::os::unix::fs::symlink(docs.join("a.txt"), docs.join("inside")).unwrap();
+        assert!(roots.resolve("docs/file").await.is_err());
+        assert!(roots.resolve("docs/dir").await.is_err());
+        assert!(roots.resolve("docs/dir/secret.txt").await.is_err());
+        assert_eq!(
+            roots.resolve("docs/inside").await.unwrap(),
+            docs.join("a.txt").canonicalize().unwrap()
+        );
+    }
+
+    #[test]
+    fn plain_names() {
+        for name in ["a.txt", "report 2024.pdf", ".hidden", "..."] {
+            assert!(is_plain_name(name), "{:?} is refused", name);
+        }
+        for name in [
+            "",
+            ".",
+            "..",
+            "/",
+            "/etc",
+            "a/b",
+            "a\\b",
+            "..\\a",
+            "a\0",
+            "\\\\server\\share",
+        ] {
+            assert!(!is_plain_name(name), "{:?} is allowed", name);
+        }
+        assert_eq!(is_plain_name("C:"), !cfg!(target_os = "windows"));
+        assert_eq!(is_plain_name("a.txt:stream"), !cfg!(target_os = "windows"));
+    }
+
+    #[tokio::test]
+    async fn unique_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        assert_eq!(unique_path(dir, "a.txt").await.unwrap(), dir.join("a.txt"));
+
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        assert_eq!(
+            unique_path(dir, "a.txt").await.unwrap(),
+            dir.join("a (1).txt")
+        );
+        std::fs::write(dir.join("a (1).txt.part"), "").unwrap();
+        assert_eq!(
+            unique_path(dir, "a.txt").await.unwrap(),
+            dir.join("a (2).txt")
+        );
+
+        std::fs::write(dir.join("notes"), "").unwrap();
+        assert_eq!(
+            unique_path(dir, "notes").await.unwrap(),
+            dir.join("notes (1)")
+        );
+    }
+
+    #[tokio::test]
+    async fn unique_paths_refuse_other_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        for name in [
+            "",
+            "..",
+            "../a.txt",
+            "/etc/passwd",
+            "a\\..\\b",
+            "a\0",
+            "sub/a.txt",
+        ] {
+            assert!(
+                unique_path(tmp.path(), name).await.is_err(),
+                "{:?} is allowed",
+                name
+            );
+        }
+    }
+}
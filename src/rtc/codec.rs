@@ -0,0 +1,192 @@
+use std::fmt::Display;
+
+use anyhow::{Context, Result};
+
+use gstreamer::prelude::*;
+use gstreamer::{Caps, Element, ElementFactory};
+
+use serde::Deserialize;
+
+use str0m::format::Codec;
+
+use crate::Config;
+
+/// AV1 encoders in order of preference; the first one installed is used.
+const AV1_ENCODERS: &[&str] = &["svtav1enc", "rav1enc", "av1enc"];
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    H264,
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+impl VideoCodec {
+    /// The encoding name used in SDP `rtpmap` lines.
+    fn sdp_name(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "H264",
+            VideoCodec::H265 => "H265",
+            VideoCodec::Vp8 => "VP8",
+            VideoCodec::Vp9 => "VP9",
+            VideoCodec::Av1 => "AV1",
+        }
+    }
+
+    /// Pick the first codec in `preference` that the client offered.
+    pub fn negotiate(preference: &[VideoCodec], offer_sdp: &str) -> Option<VideoCodec> {
+        let offered: Vec<String> = offer_sdp
+            .lines()
+            .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+            .filter_map(|rtpmap| rtpmap.split_whitespace().nth(1))
+            .filter_map(|encoding| encoding.split('/').next())
+            .map(|name| name.to_ascii_uppercase())
+            .collect();
+
+        preference
+            .iter()
+            .find(|codec| offered.iter().any(|name| name == codec.sdp_name()))
+            .copied()
+    }
+
+    pub fn enable(&self, builder: str0m::RtcConfig) -> str0m::RtcConfig {
+        match self {
+            VideoCodec::H264 => builder.enable_h264(true),
+            VideoCodec::H265 => builder.enable_h265(true),
+            VideoCodec::Vp8 => builder.enable_vp8(true),
+            VideoCodec::Vp9 => builder.enable_vp9(true),
+            VideoCodec::Av1 => builder.enable_av1(true),
+        }
+    }
+
+    /// The raw video format the software encoder is fed.
+    pub fn raw_format(&self, full_chroma: bool) -> &'static str {
+        match (self, full_chroma) {
+            (VideoCodec::H264, true) => "Y444",
+            (VideoCodec::H264, false) => "NV12",
+            // Browsers only reliably decode 4:2:0 for everything else
+            _ => "I420",
+        }
+    }
+
+    /// Caps of the encoded stream as it leaves the pipeline.
+    pub fn caps(&self) -> Caps {
+        match self {
+            VideoCodec::H264 => Caps::builder("video/x-h264")
+                .field("stream-format", "byte-stream")
+                .field("alignment", "au")
+                .build(),
+            VideoCodec::H265 => Caps::builder("video/x-h265")
+                .field("stream-format", "byte-stream")
+                .field("alignment", "au")
+                .build(),
+            VideoCodec::Vp8 => Caps::builder("video/x-vp8").build(),
+            VideoCodec::Vp9 => Caps::builder("video/x-vp9").build(),
+            VideoCodec::Av1 => Caps::builder("video/x-av1")
+                .field("stream-format", "obu-stream")
+                .field("alignment", "tu")
+                .build(),
+        }
+    }
+
+    /// The parser that prepares this codec for muxing.
+    pub fn parser(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264parse",
+            VideoCodec::H265 => "h265parse",
+            // VP8 frames need no parsing
+            VideoCodec::Vp8 => "identity",
+            VideoCodec::Vp9 => "vp9parse",
+            VideoCodec::Av1 => "av1parse",
+        }
+    }
+
+    /// Build a low-latency software encoder. H.264 encoders are platform specific and built by
+    /// the pipelines themselves.
+    pub fn software_encoder(&self, config: &Config) -> Result<Element> {
+        let bitrate = config.target_bitrate - 96;
+        let enc = match self {
+            VideoCodec::H264 => unreachable!("H.264 encoders are built by the pipeline"),
+            VideoCodec::H265 => ElementFactory::make("x265enc")
+                .property_from_str("speed-preset", "superfast")
+                .property_from_str("tune", "zerolatency")
+                .property("key-int-max", 2560i32)
+                .build()?,
+            VideoCodec::Vp8 | VideoCodec::Vp9 => {
+                let name = if *self == VideoCodec::Vp8 {
+                    "vp8enc"
+                } else {
+                    "vp9enc"
+                };
+                ElementFactory::make(name)
+                    .property("deadline", 1i64)
+                    .property("cpu-used", 8i32)
+                    .property("threads", 4i32)
+                    .property("lag-in-frames", 0i32)
+                    .property("keyframe-max-dist", 2560i32)
+                    .property_from_str("end-usage", "cbr")
+                    .build()?
+            }
+            VideoCodec::Av1 => {
+                let name = AV1_ENCODERS
+                    .iter()
+                    .find(|name| ElementFactory::find(name).is_some())
+                    .context("No AV1 encoder is installed")?;
+                match *name {
+                    "svtav1enc" => ElementFactory::make(name)
+                        .property("preset", 12u32)
+                        .property("intra-period-length", 2560i32)
+                        .build()?,
+                    "rav1enc" => ElementFactory::make(name)
+                        .property("speed-preset", 10u32)
+                        .property("low-latency", true)
+                        .property("max-key-frame-interval", 2560u64)
+                        .build()?,
+                    _ => ElementFactory::make(name)
+                        .property("cpu-used", 10i32)
+                        .property("lag-in-frames", 0u32)
+                        .property("keyframe-max-dist", 2560u32)
+                        .property_from_str("usage-profile", "realtime")
+                        .property_from_str("end-usage", "cbr")
+                        .build()?,
+                }
+            }
+        };
+        set_software_bitrate(&enc, bitrate);
+        Ok(enc)
+    }
+}
+
+/// Software encoders disagree on both the name and the unit of their bitrate property.
+pub fn set_software_bitrate(enc: &Element, kbps: u32) {
+    let Some(factory) = enc.factory() else {
+        return;
+    };
+    match factory.name().as_str() {
+        "vp8enc" | "vp9enc" => enc.set_property("target-bitrate", (kbps * 1000) as i32),
+        "rav1enc" => enc.set_property("bitrate", (kbps * 1000) as i32),
+        "svtav1enc" | "av1enc" => enc.set_property("target-bitrate", kbps),
+        _ => enc.set_property("bitrate", kbps),
+    }
+}
+
+impl From<VideoCodec> for Codec {
+    fn from(codec: VideoCodec) -> Self {
+        match codec {
+            VideoCodec::H264 => Codec::H264,
+            VideoCodec::H265 => Codec::H265,
+            VideoCodec::Vp8 => Codec::Vp8,
+            VideoCodec::Vp9 => Codec::Vp9,
+            VideoCodec::Av1 => Codec::Av1,
+        }
+    }
+}
+
+impl Display for VideoCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.sdp_name())
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use log::*;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::adaptation::{Adaptation, Quality};
+use super::codec::VideoCodec;
+use super::pipeline::{AudioRecordingPipeline, EncodedFrame, ScreenRecordingPipeline};
+use crate::display::WindowWatcher;
+use crate::metrics::METRICS;
+use crate::Config;
+
+/// Keyframe requests that arrive this soon after a forced keyframe are answered by that keyframe.
+const KEYFRAME_COALESCE_WINDOW: Duration = Duration::from_millis(500);
+/// How often a captured window is checked for moves and resizes.
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Tells the bandwidth estimates of the subscriptions to one pipeline apart.
+static NEXT_VIEWER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Viewers whose capture settings compare equal share one encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VideoKey {
+    pub codec: VideoCodec,
+    pub show_mouse: bool,
+    pub fps: i32,
+    // Captured instead of the configured area, e.g. a monitor the client picked
+    pub region: Option<Region>,
+    // A single X11 window captured instead of any region
+    pub window: Option<u32>,
+}
+
+/// A rectangle of the desktop, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Owns the capture/encode pipelines and fans their output out to every connected viewer.
+///
+/// The hub only holds weak references: a pipeline lives exactly as long as at least one
+/// subscription to it exists, so it is built by the first viewer and torn down when the last
+/// one leaves.
+#[derive(Debug, Default)]
+pub struct MediaHub {
+    video: tokio::sync::Mutex<HashMap<VideoKey, Weak<SharedVideo>>>,
+    audio: tokio::sync::Mutex<Weak<SharedAudio>>,
+}
+
+impl MediaHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn join_video(&self, config: &Config, key: VideoKey) -> Result<VideoSubscription> {
+        let mut video = self.video.lock().await;
+        video.retain(|_, shared| shared.strong_count() > 0);
+
+        let shared = match video.get(&key).and_then(Weak::upgrade) {
+            Some(shared) => {
+                info!("Joining existing video pipeline {:?}", key);
+                shared
+            }
+            None => {
+                info!("Creating video pipeline {:?}", key);
+                let mut config = config.clone();
+                if let Some(region) = key.region {
+                    config.startx = region.x;
+                    config.starty = region.y;
+                    // The end coordinates are inclusive
+                    config.endx = Some(region.x + region.width as i32 - 1);
+                    config.endy = Some(region.y + region.height as i32 - 1);
+                }
+                let adaptation = config
+                    .adaptation
+                    .clone()
+                    .map(|adaptation| Mutex::new(Adaptation::new(adaptation, key.fps)));
+                let pipeline =
+                    ScreenRecordingPipeline::new(config, key.codec, key.show_mouse, key.fps)?;
+                let window = match key.window {
+                    Some(id) => {
+                        let watcher = WindowWatcher::open(id).await?;
+                        let geometry = watcher
+                            .geometry()
+                            .await?
+                            .with_context(|| format!("window {:#x} is not showing", id))?;
+                        pipeline.capture_window(id, geometry.width, geometry.height)?;
+                        Some((watcher, geometry))
+                    }
+                    None => None,
+                };
+                let shared = Arc::new(SharedVideo {
+                    pipeline,
+                    started: AtomicBool::new(false),
+                    bitrates: Mutex::new(HashMap::new()),
+                    last_keyframe: Mutex::new(None),
+                    window: Mutex::new(window.as_ref().map(|(_, geometry)| *geometry)),
+                    adaptation,
+                    quality: Mutex::new(Quality::full(key.fps)),
+                });
+                if let (Some(id), Some((watcher, geometry))) = (key.window, window) {
+                    tokio::spawn(follow_window(
+                        Arc::downgrade(&shared),
+                        id,
+                        watcher,
+                        geometry,
+                    ));
+                }
+                video.insert(key, Arc::downgrade(&shared));
+                shared
+            }
+        };
+
+        Ok(VideoSubscription {
+            id: NEXT_VIEWER_ID.fetch_add(1, Ordering::Relaxed),
+            rx: shared.pipeline.subscribe(),
+            shared,
+        })
+    }
+
+    /// Restart every running video pipeline, e.g. because the screen was resized.
+    pub async fn restart_video(&self) {
+        let running = self
+            .video
+            .lock()
+            .await
+            .values()
+            .filter_map(Weak::upgrade)
+            .filter(|shared| shared.started.load(Ordering::SeqCst))
+            .collect::<Vec<_>>();
+        for shared in running {
+            info!("Restarting video pipeline {:?}", shared.pipeline.codec());
+            shared.pipeline.restart().await;
+            shared.request_keyframe();
+        }
+    }
+
+    pub async fn join_audio(&self) -> Result<AudioSubscription> {
+        let mut audio = self.audio.lock().await;
+
+        let shared = match audio.upgrade() {
+            Some(shared) => shared,
+            None => {
+                info!("Creating audio pipeline");
+                let shared = Arc::new(SharedAudio {
+                    pipeline: AudioRecordingPipeline::new().await?,
+                    started: AtomicBool::new(false),
+                });
+                *audio = Arc::downgrade(&shared);
+                shared
+            }
+        };
+
+        Ok(AudioSubscription {
+            rx: shared.pipeline.subscribe(),
+            shared,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct SharedVideo {
+    pipeline: ScreenRecordingPipeline,
+    started: AtomicBool,
+    // The most recent bandwidth estimate of every viewer, in Kbit/s
+    bitrates: Mutex<HashMap<u64, u32>>,
+    last_keyframe: Mutex<Option<Instant>>,
+    // Where the captured window is, while it is showing
+    window: Mutex<Option<Region>>,
+    adaptation: Option<Mutex<Adaptation>>,
+    quality: Mutex<Quality>,
+}
+
+impl SharedVideo {
+    fn request_keyframe(&self) {
+        let mut last_keyframe = self.last_keyframe.lock().unwrap();
+        match *last_keyframe {
+            Some(last) if last.elapsed() < KEYFRAME_COALESCE_WINDOW => {
+                debug!(
+                    "Coalescing keyframe request into the one forced {:?} ago",
+                    last.elapsed()
+                );
+            }
+            _ => {
+                self.pipeline.force_keyframe();
+                METRICS.keyframes_forced.fetch_add(1, Ordering::Relaxed);
+                *last_keyframe = Some(Instant::now());
+            }
+        }
+    }
+
+    fn apply_bitrate(&self) {
+        // The encoder is shared, so it can only go as fast as the slowest viewer
+        let Some(bitrate) = self.bitrates.lock().unwrap().values().min().copied() else {
+            return;
+        };
+        self.pipeline.set_bitrate(bitrate);
+        let Some(adaptation) = &self.adaptation else {
+            return;
+        };
+        if let Some(quality) = adaptation.lock().unwrap().update(bitrate) {
+            info!(
+                "Adapting video to {} Kbit/s: {} fps at {}% size",
+                bitrate,
+                quality.fps,
+                (quality.scale * 100.0).round()
+            );
+            self.pipeline.adapt(quality);
+            *self.quality.lock().unwrap() = quality;
+        }
+    }
+}
+
+/// Keep the capture of window `id` fitted to it, and its position known for input, until the
+/// pipeline goes away.
+async fn follow_window(
+    shared: Weak<SharedVideo>,
+    id: u32,
+    watcher: WindowWatcher,
+    mut captured: Region,
+) {
+    let mut interval = tokio::time::interval(WINDOW_POLL_INTERVAL);
+    let mut showing = true;
+    loop {
+        interval.tick().await;
+        let geometry = match watcher.geometry().await {
+            Ok(geometry) => geometry,
+            Err(e) => {
+                error!("Lost track of window {:#x}: {:?}", id, e);
+                break;
+            }
+        };
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        *shared.window.lock().unwrap() = geometry;
+
+        let Some(geometry) = geometry else {
+            if showing {
+                warn!("Window {:#x} was closed or hidden.", id);
+                showing = false;
+            }
+            continue;
+        };
+        // Moves need nothing, the source captures the window wherever it is
+        let resized = (geometry.width, geometry.height) != (captured.width, captured.height);
+        if !resized && showing {
+            continue;
+        }
+        showing = true;
+        captured = geometry;
+        info!(
+            "Capturing window {:#x} again at {}x{}.",
+            id, geometry.width, geometry.height
+        );
+        if let Err(e) = shared
+            .pipeline
+            .capture_window(id, geometry.width, geometry.height)
+        {
+            error!("Failed to follow window {:#x}: {:?}", id, e);
+            continue;
+        }
+        if shared.started.load(Ordering::SeqCst) {
+            shared.pipeline.restart().await;
+            shared.request_keyframe();
+        }
+    }
+}
+
+impl Drop for SharedVideo {
+    fn drop(&mut self) {
+        info!("Last viewer left, stopping video pipeline.");
+    }
+}
+
+#[derive(Debug)]
+pub struct VideoSubscription {
+    id: u64,
+    shared: Arc<SharedVideo>,
+    rx: broadcast::Receiver<EncodedFrame>,
+}
+
+impl VideoSubscription {
+    pub fn start_pipeline(&self) {
+        if !self.shared.started.swap(true, Ordering::SeqCst) {
+            self.shared.pipeline.start_pipeline();
+        } else {
+            // Late joiners can't decode anything until the next keyframe
+            self.shared.request_keyframe();
+        }
+    }
+
+    pub fn force_keyframe(&self) {
+        self.shared.request_keyframe();
+    }
+
+    pub fn codec(&self) -> VideoCodec {
+        self.shared.pipeline.codec()
+    }
+
+    /// The framerate and scale the pipeline encodes at now.
+    pub fn quality(&self) -> Quality {
+        *self.shared.quality.lock().unwrap()
+    }
+
+    /// Where the captured window is on the desktop, if this captures a window that is showing.
+    pub fn window(&self) -> Option<Region> {
+        *self.shared.window.lock().unwrap()
+    }
+
+    /// An independent feed of the same encoded frames, e.g. for recording.
+    pub fn frames(&self) -> broadcast::Receiver<EncodedFrame> {
+        self.shared.pipeline.subscribe()
+    }
+
+    /// Whether both subscriptions get their frames from the same pipeline.
+    pub fn shares_pipeline(&self, other: &VideoSubscription) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    pub fn set_bitrate(&self, new_bitrate: u32) {
+        self.shared
+            .bitrates
+            .lock()
+            .unwrap()
+            .insert(self.id, new_bitrate);
+        self.shared.apply_bitrate();
+    }
+
+    pub async fn recv_frame(&mut self) -> Option<EncodedFrame> {
+        loop {
+            match self.rx.recv().await {
+                Ok(frame) => return Some(frame),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Viewer {} fell behind by {} frames, requesting keyframe.",
+                        self.id, skipped
+                    );
+                    self.shared.request_keyframe();
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Another subscription to the same pipeline, which keeps it running but has no say in its
+/// bitrate until it reports one of its own.
+impl Clone for VideoSubscription {
+    fn clone(&self) -> Self {
+        Self {
+            id: NEXT_VIEWER_ID.fetch_add(1, Ordering::Relaxed),
+            shared: self.shared.clone(),
+            rx: self.shared.pipeline.subscribe(),
+        }
+    }
+}
+
+impl Drop for VideoSubscription {
+    fn drop(&mut self) {
+        self.shared.bitrates.lock().unwrap().remove(&self.id);
+        self.shared.apply_bitrate();
+    }
+}
+
+#[derive(Debug)]
+pub struct SharedAudio {
+    pipeline: AudioRecordingPipeline,
+    started: AtomicBool,
+}
+
+impl Drop for SharedAudio {
+    fn drop(&mut self) {
+        info!("Last listener left, stopping audio pipeline.");
+    }
+}
+
+#[derive(Debug)]
+pub struct AudioSubscription {
+    shared: Arc<SharedAudio>,
+    rx: broadcast::Receiver<EncodedFrame>,
+}
+
+impl Clone for AudioSubscription {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            rx: self.shared.pipeline.subscribe(),
+        }
+    }
+}
+
+impl AudioSubscription {
+    pub fn start_pipeline(&self) {
+        if !self.shared.started.swap(true, Ordering::SeqCst) {
+            self.shared.pipeline.start_pipeline();
+        }
+    }
+
+    /// An independent feed of the same encoded frames, e.g. for recording.
+    pub fn frames(&self) -> broadcast::Receiver<EncodedFrame> {
+        self.shared.pipeline.subscribe()
+    }
+
+    pub async fn recv_frame(&mut self) -> Option<EncodedFrame> {
+        loop {
+            match self.rx.recv().await {
+                Ok(frame) => return Some(frame),
+                // Dropped audio frames are just a glitch, there is nothing to recover
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
@@ -56,19 +56,61 @@
 
 #[allow(unused)]
 use std::str::{self, FromStr};
-use tokio::sync::mpsc::unbounded_channel;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::broadcast;
 
 use gstreamer::prelude::*;
 use gstreamer::{element_error, Element, ElementFactory, Pipeline, State};
 
-use tokio::sync::mpsc::UnboundedReceiver;
-
 use anyhow::Result;
 
 use log::*;
 
+use super::adaptation::Quality;
+use super::codec::{self, VideoCodec};
+#[cfg(target_os = "linux")]
+use crate::display::{DamageArea, DamageTracker};
 use crate::Config;
 
+/// How many encoded buffers a slow subscriber may fall behind before it starts losing frames.
+const BROADCAST_CAPACITY: usize = 16;
+/// With damage-driven capture, an idle screen is still sent this often.
+#[cfg(target_os = "linux")]
+const IDLE_KEEPALIVE: std::time::Duration = std::time::Duration::from_secs(1);
+
+pub type EncodedFrame = (gstreamer::Buffer, u64);
+
+/// Keeps video timestamps increasing across restarts of the pipeline, which start counting
+/// from zero again.
+#[derive(Debug)]
+struct Timeline {
+    offset: u64,
+    last: u64,
+    last_at: Instant,
+}
+
+impl Timeline {
+    fn new() -> Self {
+        Self {
+            offset: 0,
+            last: 0,
+            last_at: Instant::now(),
+        }
+    }
+
+    fn stamp(&mut self, pts: u64) -> u64 {
+        self.last = pts + self.offset;
+        self.last_at = Instant::now();
+        self.last
+    }
+
+    /// Continue from the last timestamp, plus the time the pipeline was down.
+    fn restarted(&mut self) {
+        self.offset = self.last + self.last_at.elapsed().as_micros() as u64;
+    }
+}
+
 #[cfg(target_os = "linux")]
 async fn get_pulseaudio_monitor_name() -> Result<String> {
     use anyhow::Context;
@@ -105,24 +147,24 @@ async fn get_pulseaudio_monitor_name() -> Result<String> {
 #[derive(Debug)]
 pub struct AudioRecordingPipeline {
     pipeline: Pipeline,
-    buffer_rx: UnboundedReceiver<(gstreamer::Buffer, u64)>,
+    buffer_tx: broadcast::Sender<EncodedFrame>,
 }
 
 impl AudioRecordingPipeline {
     #[cfg(target_os = "macos")]
     pub async fn new() -> Result<Self> {
         // TODO: no-op pipeline
-        let (buffer_tx, buffer_rx) = unbounded_channel();
+        let (buffer_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
         let pipeline = Pipeline::default();
         Ok(Self {
             pipeline,
-            buffer_rx,
+            buffer_tx,
         })
     }
 
     #[cfg(target_os = "windows")]
     pub async fn new() -> Result<Self> {
-        let (buffer_tx, buffer_rx) = unbounded_channel();
+        let (buffer_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
         let src = ElementFactory::make("wasapi2src")
             .property("loopback", true)
             .build()?;
@@ -150,6 +192,7 @@ impl AudioRecordingPipeline {
             .max_buffers(1)
             .build();
 
+        let callback_tx = buffer_tx.clone();
         appsink.set_callbacks(
             gstreamer_app::AppSinkCallbacks::builder()
                 .new_sample(move |appsink| {
@@ -168,8 +211,8 @@ impl AudioRecordingPipeline {
 
                     let pts = buffer.pts().unwrap().useconds();
 
-                    // we can .ok() this, because if it DOES fail, the thread will be terminated soon
-                    buffer_tx.send((buffer.to_owned(), pts)).ok();
+                    // this only fails when nobody is subscribed, in which case the frame is useless anyway
+                    callback_tx.send((buffer.to_owned(), pts)).ok();
                     Ok(gstreamer::FlowSuccess::Ok)
                 })
                 .build(),
@@ -193,13 +236,13 @@ impl AudioRecordingPipeline {
 
         Ok(Self {
             pipeline,
-            buffer_rx,
+            buffer_tx,
         })
     }
 
     #[cfg(target_os = "linux")]
     pub async fn new() -> Result<Self> {
-        let (buffer_tx, buffer_rx) = unbounded_channel();
+        let (buffer_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
         let monitor_device_name = get_pulseaudio_monitor_name().await.unwrap_or(String::from(""));
         info!("Picked audio monitor device name: {}", monitor_device_name);
         let src = ElementFactory::make("pulsesrc")
@@ -227,6 +270,7 @@ impl AudioRecordingPipeline {
             .max_buffers(1)
             .build();
 
+        let callback_tx = buffer_tx.clone();
         appsink.set_callbacks(
             gstreamer_app::AppSinkCallbacks::builder()
                 .new_sample(move |appsink| {
@@ -245,8 +289,8 @@ impl AudioRecordingPipeline {
 
                     let pts = buffer.pts().unwrap().useconds();
 
-                    // we can .ok() this, because if it DOES fail, the thread will be terminated soon
-                    buffer_tx.send((buffer.to_owned(), pts)).ok();
+                    // this only fails when nobody is subscribed, in which case the frame is useless anyway
+                    callback_tx.send((buffer.to_owned(), pts)).ok();
                     Ok(gstreamer::FlowSuccess::Ok)
                 })
                 .build(),
@@ -258,12 +302,12 @@ impl AudioRecordingPipeline {
 
         Ok(Self {
             pipeline,
-            buffer_rx,
+            buffer_tx,
         })
     }
 
-    pub async fn recv_frame(&mut self) -> Option<(gstreamer::Buffer, u64)> {
-        self.buffer_rx.recv().await
+    pub fn subscribe(&self) -> broadcast::Receiver<EncodedFrame> {
+        self.buffer_tx.subscribe()
     }
 
     #[cfg(any(target_os = "linux", target_os = "windows"))]
@@ -284,22 +328,141 @@ impl Drop for AudioRecordingPipeline {
     }
 }
 
+/// Lowers the framerate and size of raw video while the pipeline runs.
+#[derive(Debug)]
+struct Adaptive {
+    rate: Element,
+    scale: Element,
+    size: Element,
+    // What the size filter lets through at full size
+    caps: gstreamer::Caps,
+    // The latest quality, sized again whenever the source changes size, e.g. after a resize,
+    // a monitor switch or a restart
+    quality: Arc<Mutex<Option<Quality>>>,
+}
+
+impl Adaptive {
+    fn new(d3d11: bool) -> Result<Self> {
+        let rate = ElementFactory::make("videorate")
+            .property("drop-only", true)
+            .build()?;
+        let (scale, caps) = if !d3d11 {
+            (
+                ElementFactory::make("videoscale").build()?,
+                gstreamer::Caps::builder("video/x-raw").build(),
+            )
+        } else {
+            (
+                ElementFactory::make("d3d11scale").build()?,
+                gstreamer::Caps::from_str("video/x-raw(memory:D3D11Memory)")?,
+            )
+        };
+        let size = ElementFactory::make("capsfilter")
+            .property("caps", &caps)
+            .build()?;
+        let quality = Arc::new(Mutex::new(None::<Quality>));
+
+        // New caps reach the scaler before the first frame of the new size does
+        let probe_size = size.clone();
+        let probe_caps = caps.clone();
+        let probe_quality = quality.clone();
+        scale.static_pad("sink").unwrap().add_probe(
+            gstreamer::PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| {
+                let Some(gstreamer::PadProbeData::Event(event)) = &info.data else {
+                    return gstreamer::PadProbeReturn::Ok;
+                };
+                let gstreamer::EventView::Caps(source) = event.view() else {
+                    return gstreamer::PadProbeReturn::Ok;
+                };
+                if let Some(quality) = *probe_quality.lock().unwrap() {
+                    if let Some(caps) = sized_caps(&probe_caps, source.caps(), quality.scale) {
+                        probe_size.set_property("caps", &caps);
+                    }
+                }
+                gstreamer::PadProbeReturn::Ok
+            },
+        );
+
+        Ok(Self {
+            rate,
+            scale,
+            size,
+            caps,
+            quality,
+        })
+    }
+
+    fn elements(&self) -> [Element; 3] {
+        [self.rate.clone(), self.scale.clone(), self.size.clone()]
+    }
+
+    fn apply(&self, quality: Quality) {
+        *self.quality.lock().unwrap() = Some(quality);
+        self.rate.set_property("max-rate", quality.fps);
+        // Scale from whatever the source produces now
+        let source = self
+            .scale
+            .static_pad("sink")
+            .and_then(|pad| pad.current_caps());
+        let caps = match source {
+            Some(source) => sized_caps(&self.caps, &source, quality.scale),
+            None if quality.scale < 1.0 => {
+                // The probe sizes it once the caps arrive
+                debug!("Downscaling video once its size is known");
+                None
+            }
+            None => Some(self.caps.clone()),
+        };
+        if let Some(caps) = caps {
+            self.size.set_property("caps", &caps);
+        }
+    }
+}
+
+/// `base` restricted to `source` scaled by `scale`, or `None` if `source` has no size.
+fn sized_caps(
+    base: &gstreamer::Caps,
+    source: &gstreamer::CapsRef,
+    scale: f64,
+) -> Option<gstreamer::Caps> {
+    let mut caps = base.clone();
+    if scale < 1.0 {
+        let structure = source.structure(0)?;
+        let width = structure.get::<i32>("width").ok()?;
+        let height = structure.get::<i32>("height").ok()?;
+        // Encoders want even sizes
+        let scaled = |length: i32| ((length as f64 * scale) as i32).max(2) & !1;
+        let structure = caps.make_mut().structure_mut(0).unwrap();
+        structure.set("width", scaled(width));
+        structure.set("height", scaled(height));
+        structure.set("pixel-aspect-ratio", gstreamer::Fraction::new(1, 1));
+    }
+    Some(caps)
+}
+
 #[derive(Debug)]
 pub struct ScreenRecordingPipeline {
     enc: Element,
     pipeline: Pipeline,
-    buffer_rx: UnboundedReceiver<(gstreamer::Buffer, u64)>,
+    buffer_tx: broadcast::Sender<EncodedFrame>,
     config: Config,
+    codec: VideoCodec,
+    timeline: Arc<Mutex<Timeline>>,
+    adaptive: Option<Adaptive>,
+    #[cfg(target_os = "linux")]
+    damage: Option<Arc<DamageTracker>>,
 }
 
 impl ScreenRecordingPipeline {
     #[cfg(target_os = "linux")]
-    pub fn new(config: Config, show_mouse: bool, fps: i32) -> Result<Self> {
-        let (buffer_tx, buffer_rx) = unbounded_channel();
+    pub fn new(config: Config, codec: VideoCodec, show_mouse: bool, fps: i32) -> Result<Self> {
+        let (buffer_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
         let mut elements = vec![];
         let pipeline = Pipeline::default();
         elements.push(
             ElementFactory::make("ximagesrc")
+                .name("capture")
                 .property("use-damage", false)
                 .property("startx", config.startx as u32)
                 .property("starty", config.starty as u32)
@@ -318,7 +481,19 @@ impl ScreenRecordingPipeline {
             .build()?;
         elements.push(video_capsfilter);
 
-        let videoconvert = if config.vapostproc {
+        let adaptive = match config.adaptation {
+            Some(_) => Some(Adaptive::new(false)?),
+            None => None,
+        };
+        if let Some(adaptive) = &adaptive {
+            elements.extend(adaptive.elements());
+        }
+
+        // VA-API is only used for H.264, everything else is encoded in software
+        let vapostproc = config.vapostproc && codec == VideoCodec::H264;
+        let vaapi = config.vaapi && codec == VideoCodec::H264;
+
+        let videoconvert = if vapostproc {
             ElementFactory::make("vapostproc").build()?
         } else {
             ElementFactory::make("videoconvert")
@@ -328,15 +503,15 @@ impl ScreenRecordingPipeline {
 
         elements.push(videoconvert);
 
-        let format = if config.full_chroma { "Y444" } else { "NV12" };
+        let format = codec.raw_format(config.full_chroma);
 
-        if config.full_chroma && config.vapostproc {
+        if config.full_chroma && vapostproc {
             warn!(
                 "Full-chroma is not supported with VA-API! This configuration option has been ignored."
             );
         }
 
-        let format_caps = if !config.vapostproc {
+        let format_caps = if !vapostproc {
             gstreamer::Caps::builder("video/x-raw")
                 .field("format", format)
                 .build()
@@ -352,7 +527,9 @@ impl ScreenRecordingPipeline {
             .build()?;
         elements.push(format_capsfilter);
 
-        let enc = if !config.vaapi {
+        let enc = if codec != VideoCodec::H264 {
+            codec.software_encoder(&config)?
+        } else if !vaapi {
             ElementFactory::make("x264enc")
                 .property("threads", 4u32)
                 .property("b-adapt", false)
@@ -383,28 +560,35 @@ impl ScreenRecordingPipeline {
 
         elements.push(enc.clone());
 
-        let profile = match (config.vaapi, config.full_chroma) {
-            (true, _) => "high",
-            (_, true) => "high-4:4:4",
-            (false, false) => "baseline",
-        };
+        let final_caps = if codec == VideoCodec::H264 {
+            let profile = match (vaapi, config.full_chroma) {
+                (true, _) => "high",
+                (_, true) => "high-4:4:4",
+                (false, false) => "baseline",
+            };
 
-        let final_caps = gstreamer::Caps::builder("video/x-h264")
-            .field("profile", profile)
-            .field("stream-format", "byte-stream")
-            .build();
+            gstreamer::Caps::builder("video/x-h264")
+                .field("profile", profile)
+                .field("stream-format", "byte-stream")
+                .build()
+        } else {
+            codec.caps()
+        };
 
-        let h264_capsfilter = ElementFactory::make("capsfilter")
+        let enc_capsfilter = ElementFactory::make("capsfilter")
             .property("caps", &final_caps)
             .build()?;
 
-        elements.push(h264_capsfilter);
+        elements.push(enc_capsfilter);
 
         let appsink = gstreamer_app::AppSink::builder()
             .caps(&final_caps)
             .sync(false)
             .build();
 
+        let timeline = Arc::new(Mutex::new(Timeline::new()));
+        let callback_tx = buffer_tx.clone();
+        let callback_timeline = timeline.clone();
         appsink.set_callbacks(
             gstreamer_app::AppSinkCallbacks::builder()
                 .new_sample(move |appsink| {
@@ -419,9 +603,12 @@ impl ScreenRecordingPipeline {
                         );
                         gstreamer::FlowError::Error
                     })?;
-                    let pts = buffer.pts().unwrap().useconds();
-                    // we can .ok() this, because if it DOES fail, the thread will be terminated soon
-                    buffer_tx.send((buffer.to_owned(), pts)).ok();
+                    let pts = callback_timeline
+                        .lock()
+                        .unwrap()
+                        .stamp(buffer.pts().unwrap().useconds());
+                    // this only fails when nobody is subscribed, in which case the frame is useless anyway
+                    callback_tx.send((buffer.to_owned(), pts)).ok();
                     Ok(gstreamer::FlowSuccess::Ok)
                 })
                 .build(),
@@ -433,17 +620,59 @@ impl ScreenRecordingPipeline {
         pipeline.add_many(&elements)?;
         Element::link_many(&elements)?;
 
+        let damage = if config.damage_capture {
+            let area = DamageArea {
+                startx: config.startx,
+                starty: config.starty,
+                endx: config.endx,
+                endy: config.endy,
+            };
+            match DamageTracker::start(area, show_mouse) {
+                Ok(damage) => Some(Arc::new(damage)),
+                Err(e) => {
+                    warn!("Damage tracking is unavailable: {:?}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(damage) = &damage {
+            // Frames that show nothing new never reach the encoder
+            let probe_damage = damage.clone();
+            let last_sent = Mutex::new(None::<Instant>);
+            elements[0].static_pad("src").unwrap().add_probe(
+                gstreamer::PadProbeType::BUFFER,
+                move |_, _| {
+                    let mut last_sent = last_sent.lock().unwrap();
+                    let idle = last_sent.is_some_and(|sent| sent.elapsed() < IDLE_KEEPALIVE);
+                    if probe_damage.take() || !idle {
+                        *last_sent = Some(Instant::now());
+                        gstreamer::PadProbeReturn::Ok
+                    } else {
+                        gstreamer::PadProbeReturn::Drop
+                    }
+                },
+            );
+        }
+
         Ok(Self {
             config,
+            codec,
             enc,
             pipeline,
-            buffer_rx,
+            buffer_tx,
+            timeline,
+            adaptive,
+            damage,
         })
     }
 
     #[cfg(target_os = "macos")]
-    pub fn new(config: Config, show_mouse: bool, fps: i32) -> Result<Self> {
-        let (buffer_tx, buffer_rx) = unbounded_channel();
+    pub fn new(config: Config, codec: VideoCodec, show_mouse: bool, fps: i32) -> Result<Self> {
+        let (buffer_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
+        // VideoToolbox is only used for H.264, everything else is encoded in software
+        let vaapi = config.vaapi && codec == VideoCodec::H264;
         let mut elements = vec![];
         let pipeline = Pipeline::default();
         elements.push(
@@ -478,7 +707,15 @@ impl ScreenRecordingPipeline {
             .build()?;
         elements.push(video_capsfilter);
 
-        if config.full_chroma {
+        let adaptive = match config.adaptation {
+            Some(_) => Some(Adaptive::new(false)?),
+            None => None,
+        };
+        if let Some(adaptive) = &adaptive {
+            elements.extend(adaptive.elements());
+        }
+
+        if config.full_chroma || codec != VideoCodec::H264 {
             let videoconvert = ElementFactory::make("videoconvert")
                 .property("n-threads", 4u32)
                 .build()?;
@@ -487,7 +724,7 @@ impl ScreenRecordingPipeline {
                 .property(
                     "caps",
                     gstreamer::Caps::builder("video/x-raw")
-                        .field("format", "Y444")
+                        .field("format", codec.raw_format(config.full_chroma))
                         .build(),
                 )
                 .build()?;
@@ -495,7 +732,9 @@ impl ScreenRecordingPipeline {
             elements.push(format_capsfilter);
         }
 
-        let enc = if !config.vaapi {
+        let enc = if codec != VideoCodec::H264 {
+            codec.software_encoder(&config)?
+        } else if !vaapi {
             ElementFactory::make("x264enc")
                 .property("threads", 4u32)
                 .property("b-adapt", false)
@@ -515,10 +754,12 @@ impl ScreenRecordingPipeline {
 
         elements.push(enc.clone());
 
-        let final_caps = if config.vaapi {
+        let final_caps = if vaapi {
             gstreamer::Caps::builder("video/x-h264")
                 .field("stream-format", "avc")
                 .build()
+        } else if codec != VideoCodec::H264 {
+            codec.caps()
         } else {
             let profile = if config.full_chroma {
                 "high-4:4:4"
@@ -532,17 +773,21 @@ impl ScreenRecordingPipeline {
                 .build()
         };
 
-        let h264_capsfilter = ElementFactory::make("capsfilter")
+        let enc_capsfilter = ElementFactory::make("capsfilter")
             .property("caps", &final_caps)
             .build()?;
 
-        elements.push(h264_capsfilter);
+        elements.push(enc_capsfilter);
 
-        let final_caps = gstreamer::Caps::builder("video/x-h264")
-            .field("stream-format", "byte-stream")
-            .build();
+        let final_caps = if codec == VideoCodec::H264 {
+            gstreamer::Caps::builder("video/x-h264")
+                .field("stream-format", "byte-stream")
+                .build()
+        } else {
+            codec.caps()
+        };
 
-        if config.vaapi {
+        if vaapi {
             let parse = ElementFactory::make("h264parse")
                 .property("config-interval", -1)
                 .build()?;
@@ -558,6 +803,9 @@ impl ScreenRecordingPipeline {
             .sync(false)
             .build();
 
+        let timeline = Arc::new(Mutex::new(Timeline::new()));
+        let callback_tx = buffer_tx.clone();
+        let callback_timeline = timeline.clone();
         appsink.set_callbacks(
             gstreamer_app::AppSinkCallbacks::builder()
                 .new_sample(move |appsink| {
@@ -572,9 +820,12 @@ impl ScreenRecordingPipeline {
                         );
                         gstreamer::FlowError::Error
                     })?;
-                    let pts = buffer.pts().unwrap().useconds();
-                    // we can .ok() this, because if it DOES fail, the thread will be terminated soon
-                    buffer_tx.send((buffer.to_owned(), pts)).ok();
+                    let pts = callback_timeline
+                        .lock()
+                        .unwrap()
+                        .stamp(buffer.pts().unwrap().useconds());
+                    // this only fails when nobody is subscribed, in which case the frame is useless anyway
+                    callback_tx.send((buffer.to_owned(), pts)).ok();
                     Ok(gstreamer::FlowSuccess::Ok)
                 })
                 .build(),
@@ -587,15 +838,20 @@ impl ScreenRecordingPipeline {
 
         Ok(Self {
             config,
+            codec,
             enc,
             pipeline,
-            buffer_rx,
+            buffer_tx,
+            timeline,
+            adaptive,
         })
     }
 
     #[cfg(target_os = "windows")]
-    pub fn new(config: Config, show_mouse: bool, fps: i32) -> Result<Self> {
-        let (buffer_tx, buffer_rx) = unbounded_channel();
+    pub fn new(config: Config, codec: VideoCodec, show_mouse: bool, fps: i32) -> Result<Self> {
+        let (buffer_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
+        // Media Foundation is only used for H.264, everything else is encoded in software
+        let vaapi = config.vaapi && codec == VideoCodec::H264;
         let mut elements = vec![];
         let pipeline = Pipeline::default();
         let src = ElementFactory::make("d3d11screencapturesrc")
@@ -610,7 +866,7 @@ impl ScreenRecordingPipeline {
             .build()?;
         elements.push(src);
 
-        let video_caps = if !config.vaapi {
+        let video_caps = if !vaapi {
             gstreamer::Caps::builder("video/x-raw")
                 .field("framerate", gstreamer::Fraction::new(fps, 1))
                 .build()
@@ -624,7 +880,15 @@ impl ScreenRecordingPipeline {
             .build()?;
         elements.push(video_capsfilter);
 
-        let videoconvert = if !config.vaapi {
+        let adaptive = match config.adaptation {
+            Some(_) => Some(Adaptive::new(vaapi)?),
+            None => None,
+        };
+        if let Some(adaptive) = &adaptive {
+            elements.extend(adaptive.elements());
+        }
+
+        let videoconvert = if !vaapi {
             ElementFactory::make("videoconvert")
                 .property("n-threads", 4u32)
                 .build()?
@@ -634,9 +898,9 @@ impl ScreenRecordingPipeline {
 
         elements.push(videoconvert);
 
-        let format = if config.full_chroma { "Y444" } else { "NV12" };
+        let format = codec.raw_format(config.full_chroma);
 
-        let format_caps = if !config.vaapi {
+        let format_caps = if !vaapi {
             gstreamer::Caps::builder("video/x-raw")
                 .field("format", format)
                 .build()
@@ -652,7 +916,9 @@ impl ScreenRecordingPipeline {
             .build()?;
         elements.push(format_capsfilter);
 
-        let enc = if !config.vaapi {
+        let enc = if codec != VideoCodec::H264 {
+            codec.software_encoder(&config)?
+        } else if !vaapi {
             ElementFactory::make("x264enc")
                 .property("threads", 4u32)
                 .property("b-adapt", false)
@@ -681,28 +947,35 @@ impl ScreenRecordingPipeline {
 
         elements.push(enc.clone());
 
-        let profile = match (config.vaapi, config.full_chroma) {
-            (true, _) => "high",
-            (_, true) => "high-4:4:4",
-            (false, false) => "baseline",
-        };
+        let final_caps = if codec == VideoCodec::H264 {
+            let profile = match (vaapi, config.full_chroma) {
+                (true, _) => "high",
+                (_, true) => "high-4:4:4",
+                (false, false) => "baseline",
+            };
 
-        let final_caps = gstreamer::Caps::builder("video/x-h264")
-            .field("profile", profile)
-            .field("stream-format", "byte-stream")
-            .build();
+            gstreamer::Caps::builder("video/x-h264")
+                .field("profile", profile)
+                .field("stream-format", "byte-stream")
+                .build()
+        } else {
+            codec.caps()
+        };
 
-        let h264_capsfilter = ElementFactory::make("capsfilter")
+        let enc_capsfilter = ElementFactory::make("capsfilter")
             .property("caps", &final_caps)
             .build()?;
 
-        elements.push(h264_capsfilter);
+        elements.push(enc_capsfilter);
 
         let appsink = gstreamer_app::AppSink::builder()
             .caps(&final_caps)
             .sync(false)
             .build();
 
+        let timeline = Arc::new(Mutex::new(Timeline::new()));
+        let callback_tx = buffer_tx.clone();
+        let callback_timeline = timeline.clone();
         appsink.set_callbacks(
             gstreamer_app::AppSinkCallbacks::builder()
                 .new_sample(move |appsink| {
@@ -718,9 +991,12 @@ impl ScreenRecordingPipeline {
 
                         gstreamer::FlowError::Error
                     })?;
-                    let pts = buffer.pts().unwrap().useconds();
-                    // we can .ok() this, because if it DOES fail, the thread will be terminated soon
-                    buffer_tx.send((buffer.to_owned(), pts)).ok();
+                    let pts = callback_timeline
+                        .lock()
+                        .unwrap()
+                        .stamp(buffer.pts().unwrap().useconds());
+                    // this only fails when nobody is subscribed, in which case the frame is useless anyway
+                    callback_tx.send((buffer.to_owned(), pts)).ok();
                     Ok(gstreamer::FlowSuccess::Ok)
                 })
                 .build(),
@@ -734,14 +1010,28 @@ impl ScreenRecordingPipeline {
 
         Ok(Self {
             config,
+            codec,
             enc,
             pipeline,
-            buffer_rx,
+            buffer_tx,
+            timeline,
+            adaptive,
         })
     }
 
+    /// Whether the H.264 encoder is a platform hardware encoder.
+    fn hardware(&self) -> bool {
+        self.config.vaapi && self.codec == VideoCodec::H264
+    }
+
+    pub fn codec(&self) -> VideoCodec {
+        self.codec
+    }
+
     pub fn set_bitrate(&self, new_bitrate: u32) {
-        if self.config.vaapi {
+        if self.codec != VideoCodec::H264 {
+            codec::set_software_bitrate(&self.enc, new_bitrate);
+        } else if self.hardware() {
             // Setting bitrate on macOS causes it vtenc_h264 to DEADLOCK
             // YET ANOTHER ASTOUNDINGLY BROKEN PIECE OF SOFTWARE
             // WRITTEN BY THE """DEVELOPERS""" AT APPLE INC
@@ -763,10 +1053,23 @@ impl ScreenRecordingPipeline {
         }
     }
 
+    /// Encode at the framerate and scale of `quality`, if adaptation is configured.
+    pub fn adapt(&self, quality: Quality) {
+        if let Some(adaptive) = &self.adaptive {
+            adaptive.apply(quality);
+        }
+    }
+
     pub fn force_keyframe(&self) {
         info!("Forcing keyframe");
 
-        if !(cfg!(target_os = "macos") && self.config.vaapi) {
+        // The keyframe is only encoded once the next frame gets through
+        #[cfg(target_os = "linux")]
+        if let Some(damage) = &self.damage {
+            damage.mark();
+        }
+
+        if !(cfg!(target_os = "macos") && self.hardware()) {
             let force_keyframe_event = gstreamer::Structure::builder("GstForceKeyUnit").build();
 
             // Send the event to the encoder element
@@ -776,14 +1079,57 @@ impl ScreenRecordingPipeline {
         }
     }
 
-    pub async fn recv_frame(&mut self) -> Option<(gstreamer::Buffer, u64)> {
-        self.buffer_rx.recv().await
+    pub fn subscribe(&self) -> broadcast::Receiver<EncodedFrame> {
+        self.buffer_tx.subscribe()
     }
 
     pub fn start_pipeline(&self) {
         let pipeline_clone = self.pipeline.clone();
         tokio::task::spawn_blocking(move || pipeline_clone.set_state(State::Playing).ok());
     }
+
+    /// Capture only the X11 window `id`, which is `width`x`height` now. Called again after the
+    /// window is resized, before restarting.
+    pub fn capture_window(&self, id: u32, width: u32, height: u32) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use anyhow::Context;
+
+            let src = self
+                .pipeline
+                .by_name("capture")
+                .context("the pipeline has no capture source")?;
+            src.set_property("xid", id as u64);
+            // Crop to an even size, which every encoder can take
+            src.set_property("startx", 0u32);
+            src.set_property("starty", 0u32);
+            src.set_property("endx", (width.max(2) & !1) - 1);
+            src.set_property("endy", (height.max(2) & !1) - 1);
+            // The window can move anywhere, so changes anywhere count
+            if let Some(damage) = &self.damage {
+                damage.set_area(None);
+            }
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (id, width, height);
+            anyhow::bail!("capturing a window is only supported on Linux")
+        }
+    }
+
+    /// Stop the pipeline and start it again, so that the source picks up a new screen size.
+    pub async fn restart(&self) {
+        let pipeline_clone = self.pipeline.clone();
+        let timeline = self.timeline.clone();
+        tokio::task::spawn_blocking(move || {
+            pipeline_clone.set_state(State::Null).ok();
+            timeline.lock().unwrap().restarted();
+            pipeline_clone.set_state(State::Playing).ok();
+        })
+        .await
+        .ok();
+    }
 }
 
 impl Drop for ScreenRecordingPipeline {
@@ -0,0 +1,210 @@
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use notify_rust::Notification;
+
+use str0m::channel::ChannelId;
+
+use tokio::sync::mpsc::Sender;
+
+use super::transfer::DatachannelMessageKind;
+use crate::dialogs::is_headless;
+use crate::protocol::ServerMessage;
+
+type Outgoing = Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>;
+
+/// How often progress is reported to the client and the host.
+const INTERVAL: Duration = Duration::from_millis(500);
+/// How much each interval counts towards the smoothed rate.
+const SMOOTHING: f64 = 0.3;
+
+/// Measures a transfer as it moves, reporting bytes done, throughput and the time left.
+pub(super) struct Meter {
+    tx: Outgoing,
+    channel_id: ChannelId,
+    id: u32,
+    // Whether the client negotiated the `progress` feature
+    report: bool,
+    bytes: u64,
+    total: Option<u64>,
+    // Bytes per second
+    rate: Option<f64>,
+    last: Instant,
+    last_bytes: u64,
+    notification: Option<HostNotification>,
+}
+
+impl Meter {
+    /// Start measuring at `bytes`, which is more than zero when a transfer is resumed.
+    pub(super) fn new(
+        tx: Outgoing,
+        channel_id: ChannelId,
+        id: u32,
+        report: bool,
+        bytes: u64,
+        total: Option<u64>,
+    ) -> Self {
+        Self {
+            tx,
+            channel_id,
+            id,
+            report,
+            bytes,
+            total,
+            rate: None,
+            last: Instant::now(),
+            last_bytes: bytes,
+            notification: None,
+        }
+    }
+
+    /// Also show the progress in a desktop notification on the host, titled `title`.
+    pub(super) fn notify(mut self, title: impl Into<String>) -> Self {
+        if !is_headless() {
+            self.notification = Some(HostNotification::spawn(title.into()));
+        }
+        self
+    }
+
+    /// Count `n` more bytes, sending a progress message if one is due.
+    pub(super) async fn advance(&mut self, n: u64) {
+        if let Some(message) = self.tick(n) {
+            self.tx
+                .send((
+                    self.channel_id,
+                    message.to_vec(),
+                    DatachannelMessageKind::Text,
+                ))
+                .await
+                .ok();
+        }
+    }
+
+    /// Like `advance`, for use outside of the runtime.
+    pub(super) fn advance_blocking(&mut self, n: u64) {
+        if let Some(message) = self.tick(n) {
+            self.tx
+                .blocking_send((
+                    self.channel_id,
+                    message.to_vec(),
+                    DatachannelMessageKind::Text,
+                ))
+                .ok();
+        }
+    }
+
+    fn tick(&mut self, n: u64) -> Option<ServerMessage> {
+        self.bytes += n;
+        let elapsed = self.last.elapsed();
+        if elapsed < INTERVAL {
+            return None;
+        }
+        let current = (self.bytes - self.last_bytes) as f64 / elapsed.as_secs_f64();
+        let rate = match self.rate {
+            Some(rate) => rate + SMOOTHING * (current - rate),
+            None => current,
+        };
+        self.rate = Some(rate);
+        self.last = Instant::now();
+        self.last_bytes = self.bytes;
+
+        let eta = self
+            .total
+            .filter(|_| rate >= 1.0)
+            .map(|total| (total.saturating_sub(self.bytes) as f64 / rate).ceil() as u64);
+        if let Some(notification) = &self.notification {
+            notification.update(describe(self.bytes, self.total, rate, eta));
+        }
+        self.report.then_some(ServerMessage::TransferProgress {
+            id: self.id,
+            bytes: self.bytes,
+            total: self.total,
+            rate: rate as u64,
+            eta,
+        })
+    }
+}
+
+/// A desktop notification kept up to date from its own thread, since showing one can block.
+struct HostNotification {
+    // Dropping the sender closes the notification
+    tx: SyncSender<String>,
+}
+
+impl HostNotification {
+    fn spawn(title: String) -> Self {
+        let (tx, rx) = sync_channel(1);
+        std::thread::spawn(move || show_notification(&title, rx));
+        Self { tx }
+    }
+
+    fn update(&self, body: String) {
+        // Skip this update if the last one hasn't been shown yet
+        self.tx.try_send(body).ok();
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn show_notification(title: &str, rx: Receiver<String>) {
+    let handle = Notification::new()
+        .appname("Tenebra")
+        .summary(title)
+        .body("Starting...")
+        .timeout(notify_rust::Timeout::Never)
+        .show();
+    let mut handle = match handle {
+        Ok(handle) => handle,
+        Err(e) => {
+            debug!("Failed to show transfer notification: {}", e);
+            return;
+        }
+    };
+    while let Ok(body) = rx.recv() {
+        handle.body(&body);
+        if let Err(e) = handle.update() {
+            debug!("Failed to update transfer notification: {}", e);
+            return;
+        }
+    }
+    handle.close();
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn show_notification(title: &str, _rx: Receiver<String>) {
+    // These notifications can't be updated in place, so only announce the transfer
+    if let Err(e) = Notification::new().appname("Tenebra").summary(title).show() {
+        debug!("Failed to show transfer notification: {}", e);
+    }
+}
+
+fn describe(bytes: u64, total: Option<u64>, rate: f64, eta: Option<u64>) -> String {
+    let mut description = match total {
+        Some(total) => format!("{} of {}", format_bytes(bytes), format_bytes(total)),
+        None => format_bytes(bytes),
+    };
+    description += &format!(" at {}/s", format_bytes(rate as u64));
+    if let Some(eta) = eta {
+        description += &match eta {
+            0..=59 => format!(", {} s left", eta),
+            60..=3599 => format!(", {} min left", eta.div_ceil(60)),
+            _ => format!(", {} h {} min left", eta / 3600, eta % 3600 / 60),
+        };
+    }
+    description
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
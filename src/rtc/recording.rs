@@ -0,0 +1,227 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use gstreamer::prelude::*;
+use gstreamer::{Element, ElementFactory, Pipeline, State};
+use gstreamer_app::AppSrc;
+
+use log::*;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::{spawn, AbortHandle};
+
+use super::hub::{AudioSubscription, VideoSubscription};
+use super::pipeline::EncodedFrame;
+
+/// How long to wait for the muxer to write its index before giving up on a clean finish.
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tees the encoded output of a session into a Matroska file.
+///
+/// The recorder has its own subscriptions to the shared pipelines, so a slow disk can only ever
+/// make the recording drop frames, never the live stream. They also keep the pipelines running
+/// when the session's video track switches to another one, see [`SessionRecorder::switch`].
+/// Dropping the recorder finalizes the file.
+pub struct SessionRecorder {
+    pipeline: Pipeline,
+    sources: Vec<AppSrc>,
+    feeders: Vec<AbortHandle>,
+    path: PathBuf,
+    dir: PathBuf,
+    session_id: String,
+    video: VideoSubscription,
+    audio: Option<AudioSubscription>,
+}
+
+impl SessionRecorder {
+    pub fn start(
+        dir: &Path,
+        session_id: &str,
+        video: &VideoSubscription,
+        audio: Option<&AudioSubscription>,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(dir).context("Failed to create recording directory")?;
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let mut path = dir.join(format!("{}-{}.mkv", session_id, timestamp));
+        // A switch can start the next file of the session within the same second
+        for part in 1.. {
+            if !path.exists() {
+                break;
+            }
+            path = dir.join(format!("{}-{}-{}.mkv", session_id, timestamp, part));
+        }
+
+        let pipeline = Pipeline::default();
+        let mux = ElementFactory::make("matroskamux").build()?;
+        let sink = ElementFactory::make("filesink")
+            .property("location", path.to_string_lossy().as_ref())
+            .property("async", false)
+            .build()?;
+        pipeline.add_many([&mux, &sink])?;
+        mux.link(&sink)?;
+
+        let mut sources = vec![];
+        let mut feeders = vec![];
+
+        let codec = video.codec();
+        let video_src = Self::add_branch(&pipeline, &mux, codec.caps(), codec.parser())?;
+        feeders.push(Self::feed(video_src.clone(), video.frames(), "video"));
+        sources.push(video_src);
+
+        if let Some(audio) = audio {
+            let audio_src = Self::add_branch(
+                &pipeline,
+                &mux,
+                gstreamer::Caps::builder("audio/x-opus")
+                    .field("rate", 48000i32)
+                    .field("channels", 2i32)
+                    .field("channel-mapping-family", 0i32)
+                    .build(),
+                "opusparse",
+            )?;
+            feeders.push(Self::feed(audio_src.clone(), audio.frames(), "audio"));
+            sources.push(audio_src);
+            audio.start_pipeline();
+        }
+
+        pipeline.set_state(State::Playing)?;
+
+        // Make sure the recording doesn't have to wait a whole GOP for its first frame
+        video.start_pipeline();
+        video.force_keyframe();
+
+        info!("Recording session {} to {}", session_id, path.display());
+
+        Ok(Self {
+            pipeline,
+            sources,
+            feeders,
+            path,
+            dir: dir.to_path_buf(),
+            session_id: session_id.to_string(),
+            video: video.clone(),
+            audio: audio.cloned(),
+        })
+    }
+
+    /// Whether this records the pipeline behind `video`.
+    pub fn records(&self, video: &VideoSubscription) -> bool {
+        self.video.shares_pipeline(video)
+    }
+
+    /// Continue the recording from `video` in a new file. The new pipeline may capture a
+    /// different size, which Matroska can't change mid-stream.
+    pub fn switch(&self, video: &VideoSubscription) -> Result<Self> {
+        info!(
+            "The recorded video track of session {} switched, continuing in a new file",
+            self.session_id
+        );
+        Self::start(&self.dir, &self.session_id, video, self.audio.as_ref())
+    }
+
+    fn add_branch(
+        pipeline: &Pipeline,
+        mux: &Element,
+        caps: gstreamer::Caps,
+        parser: &str,
+    ) -> Result<AppSrc> {
+        let src = AppSrc::builder()
+            .caps(&caps)
+            .format(gstreamer::Format::Time)
+            .is_live(true)
+            // The live pipelines have their own clocks, so restamp everything on arrival
+            .do_timestamp(true)
+            .block(false)
+            .build();
+        let parse = ElementFactory::make(parser).build()?;
+        let queue = ElementFactory::make("queue").build()?;
+
+        pipeline.add_many([src.upcast_ref(), &parse, &queue])?;
+        Element::link_many([src.upcast_ref(), &parse, &queue, mux])?;
+        Ok(src)
+    }
+
+    fn feed(
+        src: AppSrc,
+        mut frames: broadcast::Receiver<EncodedFrame>,
+        kind: &'static str,
+    ) -> AbortHandle {
+        spawn(async move {
+            // Video can only start at a keyframe
+            let mut waiting = kind == "video";
+            loop {
+                let (buffer, _) = match frames.recv().await {
+                    Ok(frame) => frame,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Recording dropped {} buffers.", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => {
+                        // Feeders are stopped before the pipelines can go away, unless they fail
+                        warn!("The recorded {} feed ended before the session did.", kind);
+                        break;
+                    }
+                };
+
+                if waiting {
+                    if buffer.flags().contains(gstreamer::BufferFlags::DELTA_UNIT) {
+                        continue;
+                    }
+                    waiting = false;
+                }
+
+                let mut buffer = buffer.copy();
+                {
+                    let buffer = buffer.get_mut().unwrap();
+                    buffer.set_pts(None);
+                    buffer.set_dts(None);
+                }
+                if src.push_buffer(buffer).is_err() {
+                    break;
+                }
+            }
+        })
+        .abort_handle()
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        for feeder in &self.feeders {
+            feeder.abort();
+        }
+
+        // Finalizing blocks until the muxer has flushed, which must not happen on a runtime thread
+        let pipeline = self.pipeline.clone();
+        let sources = std::mem::take(&mut self.sources);
+        let path = self.path.clone();
+        std::thread::spawn(move || {
+            for src in sources {
+                src.end_of_stream().ok();
+            }
+            let finished = pipeline.bus().and_then(|bus| {
+                bus.timed_pop_filtered(
+                    gstreamer::ClockTime::from_seconds(FINALIZE_TIMEOUT.as_secs()),
+                    &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error],
+                )
+            });
+            match finished.as_ref().map(|msg| msg.view()) {
+                Some(gstreamer::MessageView::Eos(_)) => {
+                    info!("Finished recording {}", path.display())
+                }
+                Some(gstreamer::MessageView::Error(err)) => {
+                    error!("Recording {} failed: {}", path.display(), err.error())
+                }
+                _ => warn!(
+                    "Recording {} did not finalize in time, the file may be incomplete.",
+                    path.display()
+                ),
+            }
+            pipeline.set_state(State::Null).ok();
+        });
+    }
+}
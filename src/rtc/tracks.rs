@@ -0,0 +1,321 @@
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::task::Poll;
+
+use anyhow::{bail, Context, Result};
+
+use log::*;
+
+use str0m::media::Mid;
+
+use super::adaptation::Quality;
+use super::hub::{Region, VideoKey, VideoSubscription};
+use super::pipeline::EncodedFrame;
+use crate::display;
+use crate::protocol::{Monitor, TrackMonitor, Window};
+use crate::AppState;
+
+/// A video m-line of the session and what it shows.
+struct VideoTrack {
+    // Unset until the client's offer has been matched to the first track
+    mid: Option<Mid>,
+    key: VideoKey,
+    subscription: VideoSubscription,
+    // The monitor it captures, or `None` for the configured area or a window
+    monitor: Option<String>,
+    // The quality the client was last told about
+    reported: Quality,
+}
+
+/// Every video track of a session. There is always at least one, which is also the one that is
+/// recorded; when the client offers more, each captures its own monitor.
+pub(super) struct VideoTracks {
+    tracks: Vec<VideoTrack>,
+    // The session's latest bandwidth estimate, in Kbit/s
+    bitrate: Option<u32>,
+}
+
+type FrameFuture<'a> = Pin<Box<dyn Future<Output = Option<(Mid, EncodedFrame)>> + Send + 'a>>;
+
+impl VideoTracks {
+    pub async fn new(state: &AppState, key: VideoKey) -> Result<Self> {
+        Ok(Self {
+            tracks: vec![VideoTrack {
+                mid: None,
+                key,
+                subscription: state.hub.join_video(&state.config, key).await?,
+                monitor: None,
+                reported: Quality::full(key.fps),
+            }],
+            bitrate: None,
+        })
+    }
+
+    /// The first track, which sessions without multiple monitors only ever have.
+    pub fn primary(&self) -> &VideoSubscription {
+        &self.tracks[0].subscription
+    }
+
+    pub fn is_primary(&self, mid: Mid) -> bool {
+        self.tracks[0].mid == Some(mid)
+    }
+
+    /// Start sending video on a new m-line. From the second one on, the tracks are spread over
+    /// the monitors in the order RandR lists them, unless the client already picked one.
+    pub async fn add(&mut self, state: &AppState, mid: Mid) -> Result<()> {
+        if self.tracks[0].mid.is_none() {
+            self.tracks[0].mid = Some(mid);
+            self.tracks[0].subscription.start_pipeline();
+            return Ok(());
+        }
+
+        let monitors = display::monitors()
+            .await
+            .inspect_err(|e| warn!("Failed to list monitors for video track {}: {:?}", mid, e))
+            .unwrap_or_default();
+        let index = self.tracks.len();
+        if index == 1 && self.tracks[0].monitor.is_none() && self.tracks[0].key.window.is_none() {
+            if let Some(monitor) = monitors.first() {
+                self.show_monitor(state, 0, Some(monitor)).await?;
+            }
+        }
+
+        let monitor = monitors.get(index);
+        let key = VideoKey {
+            region: monitor.map(region),
+            window: None,
+            ..self.tracks[0].key
+        };
+        info!("Adding video track {} showing {:?}", mid, key.region);
+        let subscription = state.hub.join_video(&state.config, key).await?;
+        subscription.start_pipeline();
+        if let Some(bitrate) = self.bitrate {
+            subscription.set_bitrate(bitrate / (self.tracks.len() + 1) as u32);
+        }
+        self.tracks.push(VideoTrack {
+            mid: Some(mid),
+            key,
+            subscription,
+            monitor: monitor.map(|monitor| monitor.name.clone()),
+            reported: Quality::full(key.fps),
+        });
+        Ok(())
+    }
+
+    /// Show the monitor called `name` on the track `mid`, or the configured area for `None`.
+    /// The first track is used when `mid` is `None`. Returns every monitor.
+    pub async fn select(
+        &mut self,
+        state: &AppState,
+        mid: Option<&str>,
+        name: Option<&str>,
+    ) -> Result<Vec<Monitor>> {
+        let index = self.index(mid)?;
+        let monitors = display::monitors().await?;
+        let monitor = match name {
+            Some(name) => Some(
+                monitors
+                    .iter()
+                    .find(|monitor| monitor.name == name)
+                    .with_context(|| format!("there is no monitor named {}", name))?,
+            ),
+            None => None,
+        };
+        self.show_monitor(state, index, monitor).await?;
+        Ok(monitors)
+    }
+
+    /// Show only the top-level window `id` on the track `mid`, or the first one. Returns every
+    /// window.
+    pub async fn select_window(
+        &mut self,
+        state: &AppState,
+        mid: Option<&str>,
+        id: u32,
+    ) -> Result<Vec<Window>> {
+        let index = self.index(mid)?;
+        let windows = display::windows().await?;
+        if !windows.iter().any(|window| window.id == id) {
+            bail!("there is no window {:#x}", id);
+        }
+        let key = VideoKey {
+            region: None,
+            window: Some(id),
+            ..self.tracks[index].key
+        };
+        self.point(state, index, key).await?;
+        self.tracks[index].monitor = None;
+        Ok(windows)
+    }
+
+    async fn show_monitor(
+        &mut self,
+        state: &AppState,
+        index: usize,
+        monitor: Option<&Monitor>,
+    ) -> Result<()> {
+        let key = VideoKey {
+            region: monitor.map(region),
+            window: None,
+            ..self.tracks[index].key
+        };
+        self.point(state, index, key).await?;
+        self.tracks[index].monitor = monitor.map(|monitor| monitor.name.clone());
+        Ok(())
+    }
+
+    /// Switch the track at `index` over to the pipeline for `key`.
+    async fn point(&mut self, state: &AppState, index: usize, key: VideoKey) -> Result<()> {
+        let share = self
+            .bitrate
+            .map(|bitrate| bitrate / self.tracks.len() as u32);
+        let track = &mut self.tracks[index];
+        if key != track.key {
+            info!("Switching video track {:?} to {:?}", track.mid, key);
+            track.subscription = state.hub.join_video(&state.config, key).await?;
+            track.key = key;
+            if track.mid.is_some() {
+                track.subscription.start_pipeline();
+            }
+            // The new encoder has not heard this viewer's bandwidth estimate yet
+            if let Some(share) = share {
+                track.subscription.set_bitrate(share);
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&self, mid: Option<&str>) -> Result<usize> {
+        match mid {
+            Some(mid) => self
+                .tracks
+                .iter()
+                .position(|track| {
+                    track
+                        .mid
+                        .is_some_and(|track_mid| track_mid.to_string() == mid)
+                })
+                .with_context(|| format!("there is no video track {}", mid)),
+            None => Ok(0),
+        }
+    }
+
+    /// The monitor shown on the first track.
+    pub fn current(&self) -> Option<String> {
+        self.tracks[0].monitor.clone()
+    }
+
+    pub fn assignments(&self) -> Vec<TrackMonitor> {
+        self.tracks
+            .iter()
+            .filter_map(|track| {
+                Some(TrackMonitor {
+                    mid: track.mid?.to_string(),
+                    monitor: track.monitor.clone(),
+                    window: track.key.window,
+                })
+            })
+            .collect()
+    }
+
+    /// Where absolute input aimed at the track `mid`, or the first one, is measured from. Input
+    /// at `position` is dropped, with `None`, when it misses the window the track captures.
+    pub fn origin(
+        &self,
+        state: &AppState,
+        mid: Option<&str>,
+        position: Option<(i32, i32)>,
+    ) -> Option<(i32, i32)> {
+        let track = &self.tracks[self.index(mid).unwrap_or(0)];
+        if track.key.window.is_some() {
+            let window = track.subscription.window()?;
+            if let Some((x, y)) = position {
+                if x < 0 || y < 0 || x >= window.width as i32 || y >= window.height as i32 {
+                    return None;
+                }
+            }
+            return Some((window.x, window.y));
+        }
+        Some(match track.key.region {
+            Some(region) => (region.x, region.y),
+            None => (state.config.startx, state.config.starty),
+        })
+    }
+
+    /// How much smaller than the captured area the track `mid`, or the first one, is encoded.
+    pub fn scale(&self, mid: Option<&str>) -> f64 {
+        self.tracks[self.index(mid).unwrap_or(0)]
+            .subscription
+            .quality()
+            .scale
+    }
+
+    /// The new quality of the track `mid`, if it changed since the last call.
+    pub fn quality_changed(&mut self, mid: Mid) -> Option<Quality> {
+        let track = self
+            .tracks
+            .iter_mut()
+            .find(|track| track.mid == Some(mid))?;
+        let quality = track.subscription.quality();
+        if quality == track.reported {
+            return None;
+        }
+        track.reported = quality;
+        Some(quality)
+    }
+
+    pub fn force_keyframe(&self, mid: Mid) {
+        if let Some(track) = self.tracks.iter().find(|track| track.mid == Some(mid)) {
+            track.subscription.force_keyframe();
+        }
+    }
+
+    /// Share the session's bandwidth estimate, in Kbit/s, evenly among the tracks.
+    pub fn set_bitrate(&mut self, bitrate: u32) {
+        self.bitrate = Some(bitrate);
+        let share = bitrate / self.tracks.len() as u32;
+        for track in &self.tracks {
+            track.subscription.set_bitrate(share);
+        }
+    }
+
+    /// Wait for the next frame on any track that is being sent.
+    pub async fn recv_frame(&mut self) -> Option<(Mid, EncodedFrame)> {
+        let mut receiving: Vec<FrameFuture> = self
+            .tracks
+            .iter_mut()
+            .filter_map(|track| {
+                let mid = track.mid?;
+                let frame: FrameFuture = Box::pin(async move {
+                    track
+                        .subscription
+                        .recv_frame()
+                        .await
+                        .map(|frame| (mid, frame))
+                });
+                Some(frame)
+            })
+            .collect();
+        if receiving.is_empty() {
+            return std::future::pending().await;
+        }
+        poll_fn(|cx| {
+            for frame in receiving.iter_mut() {
+                if let Poll::Ready(frame) = frame.as_mut().poll(cx) {
+                    return Poll::Ready(frame);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+fn region(monitor: &Monitor) -> Region {
+    Region {
+        x: monitor.x,
+        y: monitor.y,
+        width: monitor.width,
+        height: monitor.height,
+    }
+}
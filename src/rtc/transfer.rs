@@ -0,0 +1,1507 @@
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use log::*;
+
+use serde::{Deserialize, Serialize};
+
+use sha2::{Digest, Sha256};
+
+use str0m::channel::ChannelId;
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{spawn, AbortHandle};
+
+use super::archive;
+use super::browse::{unique_path, Roots};
+use super::progress::Meter;
+use crate::dialogs::*;
+use crate::keys::unix_now;
+use crate::protocol::{Compression, FileOffer, ResumeFile, ServerMessage};
+
+pub(super) const CHUNK_SIZE: usize = 16 * 1024;
+/// How long an interrupted transfer can be resumed for.
+const RESUME_FOR: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How long an upload may go without a chunk before it is given up, freeing its slot.
+pub(super) const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub(super) enum DatachannelMessageKind {
+    // A binary file chunk carrying this many bytes of the file
+    FileChunk(u64),
+    Text
+}
+
+impl DatachannelMessageKind {
+    pub(super) fn is_binary(&self) -> bool {
+        match self {
+            DatachannelMessageKind::FileChunk(_) => true,
+            DatachannelMessageKind::Text => false,
+        }
+    }
+}
+
+/// Where the chunks of an inbound transfer go.
+enum Inbound {
+    V1(Sender<Vec<u8>>),
+    // Chunks carry their offset. An explicit cancel discards the partial file, a dropped
+    // connection keeps it around to be resumed.
+    V2 {
+        tx: Sender<(u64, Vec<u8>)>,
+        cancelled: Arc<AtomicBool>,
+    },
+}
+
+/// How a v2 transfer task ended.
+enum Outcome {
+    // The number of bytes moved by this session
+    Completed(u64),
+    // The host dismissed the file dialog
+    Declined,
+    Cancelled,
+    // The session went away, the transfer can be resumed later
+    Interrupted,
+}
+
+pub(super) struct FileTransfers {
+    // The datachannel and the corresponding data
+    rx: Receiver<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
+    tx: Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
+
+    // When file chunks arrive, we send them to this sender and the task dedicated to that file
+    // will handle those chunks
+    inbound_transfers: Arc<Mutex<HashMap<u32, Inbound>>>,
+    outbound_transfers: Arc<Mutex<HashMap<u32, AbortHandle>>>,
+
+    // Shared by every session, so a later one can resume what an earlier one started
+    store: Arc<Mutex<ResumeStore>>,
+    roots: Roots,
+
+    queue: Queue,
+    // Whether the client negotiated the `progress` feature
+    report_progress: bool,
+    // Who downloads from this session, only they can resume a download the host picked
+    owner: String,
+}
+
+impl FileTransfers {
+    pub(super) fn new(
+        store: Arc<Mutex<ResumeStore>>,
+        roots: Roots,
+        parallelism: usize,
+        owner: String,
+    ) -> Self {
+        let (tx, rx) = channel(100);
+        Self {
+            tx,
+            rx,
+            inbound_transfers: Arc::new(Mutex::new(HashMap::new())),
+            outbound_transfers: Arc::new(Mutex::new(HashMap::new())),
+            store,
+            roots,
+            queue: Queue::new(parallelism),
+            report_progress: false,
+            owner,
+        }
+    }
+
+    /// Send progress and queue messages for transfers that begin from now on.
+    pub(super) fn set_report_progress(&mut self, report: bool) {
+        self.report_progress = report;
+    }
+
+    /// Route a chunk, minus its transfer id, to its transfer. Returns the number of file bytes in it.
+    pub(super) async fn handle_inbound_file_chunk(&self, id: u32, chunk: Vec<u8>) -> Result<u64> {
+        let inbound = {
+            let inbound_transfers = self.inbound_transfers.lock().unwrap();
+            match inbound_transfers.get(&id).context(format!(
+                "inbound file chunk received for non-existent file transfer: {}",
+                id
+            ))? {
+                Inbound::V1(sender) => Inbound::V1(sender.clone()),
+                Inbound::V2 { tx, cancelled } => Inbound::V2 {
+                    tx: tx.clone(),
+                    cancelled: cancelled.clone(),
+                },
+            }
+        };
+        match inbound {
+            Inbound::V1(sender) => {
+                let len = chunk.len() as u64;
+                sender.send(chunk).await?;
+                Ok(len)
+            }
+            Inbound::V2 { tx, .. } => {
+                if chunk.len() < 8 {
+                    bail!("file chunk for transfer {} is missing its offset", id);
+                }
+                let offset = u64::from_be_bytes(chunk[..8].try_into()?);
+                let data = chunk[8..].to_vec();
+                let len = data.len() as u64;
+                tx.send((offset, data)).await?;
+                Ok(len)
+            }
+        }
+    }
+
+    pub(super) fn cancel_transfer(&self, id: u32) {
+        // This suffices to cancel an inbound transfer as dropping the Sender will cause the
+        // recv loop to stop, thereby ending the corresponding tokio task.
+        info!("Explicitly canceling transfer {}.", id);
+        self.queue.cancel(id);
+        if let Some(Inbound::V2 { cancelled, .. }) =
+            self.inbound_transfers.lock().unwrap().remove(&id)
+        {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+        let mut outbound_transfers = self.outbound_transfers.lock().unwrap();
+        if let Some(abort_handle) = outbound_transfers.get(&id) {
+            abort_handle.abort();
+            info!("Aborted handle for transfer {}.", id);
+        }
+        outbound_transfers.remove(&id);
+    }
+
+    pub(super) fn begin_inbound_transfer(
+        &self,
+        tx: Sender<Dialog>,
+        id: u32,
+        channel_id: ChannelId,
+        size: u64,
+    ) {
+        let inbound_transfers = Arc::downgrade(&self.inbound_transfers);
+        let datachannel_tx = self.tx.clone();
+        let roots = self.roots.clone();
+        let queue = self.queue.clone();
+        let report_progress = self.report_progress;
+        spawn(async move {
+            // v1 uploads have no name, so make one up
+            let path = if roots.has_drop_dir() {
+                match roots.drop_path(&format!("upload-{}-{}", unix_now(), id)).await {
+                    Ok(path) => Some(path),
+                    Err(e) => {
+                        error!("Failed to pick a path for transfer {}: {:?}", id, e);
+                        None
+                    }
+                }
+            } else {
+                spawn_file_dialog(&tx, FileDialogKind::Save).await
+            };
+            match path {
+                Some(path) => {
+                    let Some(_permit) = queue.wait_turn(&datachannel_tx, id, channel_id, report_progress).await else {
+                        return;
+                    };
+                    let file = File::create(path).await;
+                    if let Ok(mut file) = file {
+                        let (chunk_tx, mut chunk_rx) = channel(100);
+                        match inbound_transfers.upgrade() {
+                            Some(inbound_transfers) => { inbound_transfers.lock().unwrap().insert(id, Inbound::V1(chunk_tx)); },
+                            None => return
+                        }
+                        if queue.take_cancelled(id, &inbound_transfers) {
+                            return;
+                        }
+                        let mut total_size = 0u64;
+                        datachannel_tx
+                            .send((
+                                channel_id,
+                                ServerMessage::TransferReady { id, size: None }.to_vec(),
+                                DatachannelMessageKind::Text
+                            ))
+                            .await
+                            .ok();
+                        let mut meter = Meter::new(datachannel_tx.clone(), channel_id, id, report_progress, 0, Some(size))
+                            .notify("Receiving a file");
+                        info!("Entering file write loop for transfer: {}", id);
+                        loop {
+                            let chunk = match tokio::time::timeout(IDLE_TIMEOUT, chunk_rx.recv()).await {
+                                Ok(Some(chunk)) => chunk,
+                                Ok(None) => break,
+                                Err(_) => {
+                                    warn!("Transfer {} received nothing for {:?}, giving up.", id, IDLE_TIMEOUT);
+                                    if let Some(inbound_transfers) = inbound_transfers.upgrade() {
+                                        inbound_transfers.lock().unwrap().remove(&id);
+                                    }
+                                    datachannel_tx.send((
+                                        channel_id,
+                                        ServerMessage::CancelTransfer { id }.to_vec(),
+                                        DatachannelMessageKind::Text
+                                    )).await.ok();
+                                    break;
+                                }
+                            };
+                            if let Err(e) = file.write_all(chunk.as_slice()).await {
+                                info!("Write error: {}", e);
+                                match inbound_transfers.upgrade() {
+                                    Some(inbound_transfers) => { inbound_transfers.lock().unwrap().remove(&id); },
+                                    None => return
+                                }
+                                datachannel_tx.send((
+                                    channel_id,
+                                    ServerMessage::CancelTransfer { id }.to_vec(),
+                                    DatachannelMessageKind::Text
+                                )).await.ok();
+                                spawn_message_dialog(
+                                    &tx,
+                                    "Tenebra File Transfer Error",
+                                    format!("Failed to write file: {}", e),
+                                    rfd::MessageLevel::Error,
+                                )
+                                .await;
+                                break;
+                            }
+                            total_size += chunk.len() as u64;
+                            meter.advance(chunk.len() as u64).await;
+                            if total_size >= size {
+                                info!("Transfer complete, removing self.");
+                                match inbound_transfers.upgrade() {
+                                    Some(inbound_transfers) => { inbound_transfers.lock().unwrap().remove(&id); },
+                                    None => return
+                                }
+                                info!("Removed!");
+                                spawn_message_dialog(
+                                    &tx,
+                                    "Tenebra File Transfer Notification",
+                                    format!(
+                                        "Finished file transfer. Wrote {}/{} bytes.",
+                                        total_size, size
+                                    ),
+                                    rfd::MessageLevel::Info,
+                                )
+                                .await;
+                                break;
+                            }
+                        }
+                        info!("Flushing file and exiting task.");
+                        if let Err(e) = file.flush().await {
+                            error!("Failed to sync file to disk: {}", e);
+                        }
+                    } else if let Err(e) = file {
+                        spawn_message_dialog(
+                            &tx,
+                            "Tenebra File Transfer Error",
+                            format!("Failed to create file: {}", e),
+                            rfd::MessageLevel::Error,
+                        )
+                        .await;
+                        datachannel_tx
+                            .send((
+                                channel_id,
+                                ServerMessage::CancelTransfer { id }.to_vec(),
+                                DatachannelMessageKind::Text
+                            ))
+                            .await
+                            .ok();
+                    }
+                }
+                None => {
+                    // Cancel the transfer
+                    datachannel_tx
+                        .send((
+                            channel_id,
+                            ServerMessage::CancelTransfer { id }.to_vec(),
+                            DatachannelMessageKind::Text
+                        ))
+                        .await
+                        .ok();
+                }
+            }
+            info!("Reached end of task body!");
+        });
+    }
+
+    pub(super) fn begin_outbound_transfer(&self, tx: Sender<Dialog>, id: u32, channel_id: ChannelId) {
+        let datachannel_tx = self.tx.clone();
+        let outbound_transfers = Arc::clone(&self.outbound_transfers);
+        let queue = self.queue.clone();
+        let report_progress = self.report_progress;
+        let handle = spawn(async move {
+            let path = spawn_file_dialog(&tx, FileDialogKind::Open).await;
+            match path {
+                Some(path) => {
+                    let Some(_permit) = queue.wait_turn(&datachannel_tx, id, channel_id, report_progress).await else {
+                        return;
+                    };
+                    let file = File::open(&path).await;
+                    if let Ok(mut file) = file {
+                        let metadata = file.metadata().await;
+                        if let Ok(metadata) = metadata {
+                            let total_size = metadata.len();
+                            datachannel_tx.send((
+                                channel_id,
+                                ServerMessage::TransferReady { id, size: Some(total_size) }.to_vec(),
+                                DatachannelMessageKind::Text
+                            )).await.ok();
+                            let title = format!("Sending {}", path.file_name().unwrap_or_default().to_string_lossy());
+                            let mut meter = Meter::new(datachannel_tx.clone(), channel_id, id, report_progress, 0, Some(total_size))
+                                .notify(title);
+                            let mut buf = vec![0u8; CHUNK_SIZE];
+                            loop {
+                                let n = file.read(&mut buf).await;
+                                if let Ok(n) = n {
+                                    if n == 0 {
+                                        spawn_message_dialog(
+                                            &tx,
+                                            "Tenebra File Transfer Notification",
+                                            format!(
+                                                "Finished file transfer. Sent {} bytes.",
+                                                total_size
+                                            ),
+                                            rfd::MessageLevel::Info,
+                                        )
+                                        .await;
+                                        break;
+                                    }
+
+                                    let chunk = &buf[..n];
+                                    let mut v = Vec::with_capacity(4 + chunk.len());
+                                    v.extend_from_slice(&id.to_be_bytes());
+                                    v.extend_from_slice(chunk);
+
+                                    if datachannel_tx.send((channel_id, v, DatachannelMessageKind::FileChunk(n as u64))).await.is_err() {
+                                        break; // Receiver closed
+                                    }
+                                    meter.advance(n as u64).await;
+                                } else {
+                                    datachannel_tx.send((
+                                        channel_id,
+                                        ServerMessage::CancelTransfer { id }.to_vec(),
+                                        DatachannelMessageKind::Text
+                                    )).await.ok();
+                                }
+                            }
+                        } else if let Err(e) = metadata {
+                            spawn_message_dialog(
+                                &tx,
+                                "Tenebra File Transfer Error",
+                                format!("Failed to query metadata of file file: {}", e),
+                                rfd::MessageLevel::Error,
+                            )
+                            .await;
+                            datachannel_tx
+                                .send((
+                                    channel_id,
+                                    ServerMessage::CancelTransfer { id }.to_vec(),
+                                    DatachannelMessageKind::Text
+                                ))
+                                .await
+                                .ok();
+                        }
+                    } else if let Err(e) = file {
+                        spawn_message_dialog(
+                            &tx,
+                            "Tenebra File Transfer Error",
+                            format!("Failed to open file: {}", e),
+                            rfd::MessageLevel::Error,
+                        )
+                        .await;
+                        datachannel_tx
+                            .send((
+                                channel_id,
+                                ServerMessage::CancelTransfer { id }.to_vec(),
+                                DatachannelMessageKind::Text
+                            ))
+                            .await
+                            .ok();
+                    }
+                }
+                None => {
+                    // Cancel the transfer
+                    datachannel_tx
+                        .send((
+                            channel_id,
+                            ServerMessage::CancelTransfer { id }.to_vec(),
+                            DatachannelMessageKind::Text
+                        ))
+                        .await
+                        .ok();
+                }
+            }
+            outbound_transfers.lock().unwrap().remove(&id);
+        });
+        // This is technically a race condition: it's possible for the `remove` call above to run
+        // before this call, but the cost of fixing it is not worth it, and it's functionally
+        // impossible to trigger.
+        self.outbound_transfers
+            .lock()
+            .unwrap()
+            .insert(id, handle.abort_handle());
+    }
+
+    /// Receive a file announced with `offerfile`, resuming a partial copy of it if there is one.
+    pub(super) fn begin_inbound_transfer_v2(
+        &self,
+        tx: Sender<Dialog>,
+        id: u32,
+        channel_id: ChannelId,
+        offer: FileOffer,
+    ) {
+        let inbound_transfers = Arc::downgrade(&self.inbound_transfers);
+        let datachannel_tx = self.tx.clone();
+        let store = self.store.clone();
+        let roots = self.roots.clone();
+        let queue = self.queue.clone();
+        let report_progress = self.report_progress;
+        spawn(async move {
+            let sha256 = offer.sha256.to_ascii_lowercase();
+            let outcome = if !is_sha256(&sha256) {
+                Err(anyhow!("`sha256` must be 64 hexadecimal digits"))
+            } else if !store.lock().unwrap().claim(&sha256) {
+                Err(anyhow!("this file is already being received by another session"))
+            } else {
+                let outcome = receive_file(
+                    &store,
+                    &roots,
+                    &queue,
+                    report_progress,
+                    &inbound_transfers,
+                    &datachannel_tx,
+                    &tx,
+                    id,
+                    channel_id,
+                    &offer,
+                    &sha256,
+                )
+                .await;
+                store.lock().unwrap().release(&sha256);
+                outcome
+            };
+            if let Some(inbound_transfers) = inbound_transfers.upgrade() {
+                inbound_transfers.lock().unwrap().remove(&id);
+            }
+            report(outcome, &tx, &datachannel_tx, id, channel_id, |written| {
+                format!(
+                    "Finished receiving {}. Wrote {} bytes and verified the checksum.",
+                    offer.name, written
+                )
+            })
+            .await;
+        });
+    }
+
+    /// Send a file requested with `requestfile`: a shared file named by `path`, the file an
+    /// interrupted transfer was sending, or whatever the host picks.
+    pub(super) fn begin_outbound_transfer_v2(
+        &self,
+        tx: Sender<Dialog>,
+        id: u32,
+        channel_id: ChannelId,
+        path: Option<String>,
+        resume: Option<ResumeFile>,
+    ) {
+        let datachannel_tx = self.tx.clone();
+        let outbound_transfers = Arc::clone(&self.outbound_transfers);
+        let store = self.store.clone();
+        let roots = self.roots.clone();
+        let queue = self.queue.clone();
+        let report_progress = self.report_progress;
+        let owner = self.owner.clone();
+        let handle = spawn(async move {
+            let outcome = send_file(
+                &store,
+                &roots,
+                &queue,
+                report_progress,
+                &owner,
+                &datachannel_tx,
+                &tx,
+                id,
+                channel_id,
+                path,
+                resume,
+            )
+            .await;
+            report(outcome, &tx, &datachannel_tx, id, channel_id, |sent| {
+                format!("Finished file transfer. Sent {} bytes.", sent)
+            })
+            .await;
+            outbound_transfers.lock().unwrap().remove(&id);
+        });
+        // Same race as in `begin_outbound_transfer`
+        self.outbound_transfers
+            .lock()
+            .unwrap()
+            .insert(id, handle.abort_handle());
+    }
+
+    /// Receive a folder or several files as a tar archive, unpacking it as it arrives.
+    pub(super) fn begin_archive_upload(
+        &self,
+        tx: Sender<Dialog>,
+        id: u32,
+        channel_id: ChannelId,
+        name: String,
+        compression: Compression,
+    ) {
+        let inbound_transfers = Arc::downgrade(&self.inbound_transfers);
+        let datachannel_tx = self.tx.clone();
+        let roots = self.roots.clone();
+        let queue = self.queue.clone();
+        let report_progress = self.report_progress;
+        spawn(async move {
+            let outcome = receive_archive(
+                &roots,
+                &queue,
+                report_progress,
+                &inbound_transfers,
+                &datachannel_tx,
+                &tx,
+                id,
+                channel_id,
+                &name,
+                compression,
+            )
+            .await;
+            if let Some(inbound_transfers) = inbound_transfers.upgrade() {
+                inbound_transfers.lock().unwrap().remove(&id);
+            }
+            report(outcome, &tx, &datachannel_tx, id, channel_id, |received| {
+                format!("Finished receiving {}. Received {} bytes.", name, received)
+            })
+            .await;
+        });
+    }
+
+    /// Send shared files and folders named by `paths`, or a folder the host picks, as a tar
+    /// archive built on the fly.
+    pub(super) fn begin_archive_download(
+        &self,
+        tx: Sender<Dialog>,
+        id: u32,
+        channel_id: ChannelId,
+        paths: Vec<String>,
+        compression: Compression,
+    ) {
+        let datachannel_tx = self.tx.clone();
+        let outbound_transfers = Arc::clone(&self.outbound_transfers);
+        let roots = self.roots.clone();
+        let queue = self.queue.clone();
+        let report_progress = self.report_progress;
+        let handle = spawn(async move {
+            let outcome = send_archive(
+                &roots,
+                &queue,
+                report_progress,
+                &datachannel_tx,
+                &tx,
+                id,
+                channel_id,
+                paths,
+                compression,
+            )
+            .await;
+            report(outcome, &tx, &datachannel_tx, id, channel_id, |sent| {
+                format!("Finished folder transfer. Sent {} bytes.", sent)
+            })
+            .await;
+            outbound_transfers.lock().unwrap().remove(&id);
+        });
+        // Same race as in `begin_outbound_transfer`
+        self.outbound_transfers
+            .lock()
+            .unwrap()
+            .insert(id, handle.abort_handle());
+    }
+
+    pub(super) async fn recv(&mut self) -> (ChannelId, Vec<u8>, DatachannelMessageKind) {
+        // .unwrap() is safe here because as long as `self` exists, so do `tx` and `rx`
+        self.rx.recv().await.unwrap()
+    }
+}
+
+/// Limits how many transfers of a session move data at once, the others wait their turn.
+#[derive(Clone)]
+struct Queue {
+    slots: Arc<Semaphore>,
+    // Transfers waiting for a slot, woken early if they are cancelled
+    waiting: Arc<Mutex<HashMap<u32, Arc<Notify>>>>,
+    // Every transfer the client cancelled, even one still in a dialog or not started yet
+    cancelled: Arc<Mutex<HashSet<u32>>>,
+}
+
+impl Queue {
+    fn new(parallelism: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(parallelism.max(1))),
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+            cancelled: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn is_cancelled(&self, id: u32) -> bool {
+        self.cancelled.lock().unwrap().contains(&id)
+    }
+
+    /// Check for a cancel that arrived before an upload registered its chunk sender, which
+    /// `cancel_transfer` had nothing to remove for. Unregisters the upload if there was one.
+    fn take_cancelled(
+        &self,
+        id: u32,
+        inbound_transfers: &Weak<Mutex<HashMap<u32, Inbound>>>,
+    ) -> bool {
+        if !self.is_cancelled(id) {
+            return false;
+        }
+        if let Some(inbound_transfers) = inbound_transfers.upgrade() {
+            inbound_transfers.lock().unwrap().remove(&id);
+        }
+        info!("Transfer {} was cancelled before it started.", id);
+        true
+    }
+
+    /// Wait for a free slot, telling the client when there isn't one yet. Returns `None` if the
+    /// transfer was cancelled while it waited.
+    async fn wait_turn(
+        &self,
+        datachannel_tx: &Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
+        id: u32,
+        channel_id: ChannelId,
+        report_progress: bool,
+    ) -> Option<OwnedSemaphorePermit> {
+        // Covers cancels that arrived while the host was still in a dialog
+        if self.is_cancelled(id) {
+            return None;
+        }
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        info!("Transfer {} is queued behind other transfers.", id);
+        let cancelled = Arc::new(Notify::new());
+        {
+            let mut waiting = self.waiting.lock().unwrap();
+            // `cancel` takes this lock too, so a cancel either shows up here or finds us waiting
+            if self.is_cancelled(id) {
+                return None;
+            }
+            waiting.insert(id, cancelled.clone());
+        }
+        if report_progress {
+            datachannel_tx
+                .send((
+                    channel_id,
+                    ServerMessage::TransferQueued { id }.to_vec(),
+                    DatachannelMessageKind::Text,
+                ))
+                .await
+                .ok();
+        }
+        let permit = tokio::select! {
+            // The semaphore is never closed
+            permit = self.slots.clone().acquire_owned() => permit.ok(),
+            _ = cancelled.notified() => None,
+        };
+        self.waiting.lock().unwrap().remove(&id);
+        permit
+    }
+
+    fn cancel(&self, id: u32) {
+        let waiting = self.waiting.lock().unwrap();
+        self.cancelled.lock().unwrap().insert(id);
+        if let Some(cancelled) = waiting.get(&id) {
+            // Stores a permit, so this works even if `notified` isn't being polled yet
+            cancelled.notify_one();
+        }
+    }
+}
+
+/// Tell the host and the client how a v2 transfer ended.
+async fn report(
+    outcome: Result<Outcome>,
+    tx: &Sender<Dialog>,
+    datachannel_tx: &Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
+    id: u32,
+    channel_id: ChannelId,
+    finished: impl FnOnce(u64) -> String,
+) {
+    match outcome {
+        Ok(Outcome::Completed(bytes)) => {
+            spawn_message_dialog(
+                tx,
+                "Tenebra File Transfer Notification",
+                finished(bytes),
+                rfd::MessageLevel::Info,
+            )
+            .await;
+        }
+        Ok(Outcome::Declined) => {
+            datachannel_tx
+                .send((
+                    channel_id,
+                    ServerMessage::CancelTransfer { id }.to_vec(),
+                    DatachannelMessageKind::Text,
+                ))
+                .await
+                .ok();
+        }
+        Ok(Outcome::Cancelled) | Ok(Outcome::Interrupted) => {}
+        Err(e) => {
+            warn!("File transfer {} failed: {:?}", id, e);
+            datachannel_tx
+                .send((
+                    channel_id,
+                    ServerMessage::TransferFailed {
+                        id,
+                        reason: e.to_string(),
+                    }
+                    .to_vec(),
+                    DatachannelMessageKind::Text,
+                ))
+                .await
+                .ok();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn receive_file(
+    store: &Mutex<ResumeStore>,
+    roots: &Roots,
+    queue: &Queue,
+    report_progress: bool,
+    inbound_transfers: &Weak<Mutex<HashMap<u32, Inbound>>>,
+    datachannel_tx: &Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
+    tx: &Sender<Dialog>,
+    id: u32,
+    channel_id: ChannelId,
+    offer: &FileOffer,
+    sha256: &str,
+) -> Result<Outcome> {
+    let resumed = store.lock().unwrap().find_upload(sha256, offer.size);
+    let destination = match resumed.clone() {
+        Some(destination) => destination,
+        None if roots.has_drop_dir() => roots.drop_path(&offer.name).await?,
+        None => match spawn_file_dialog(tx, FileDialogKind::Save).await {
+            Some(destination) => destination,
+            None => return Ok(Outcome::Declined),
+        },
+    };
+    let Some(_permit) = queue
+        .wait_turn(datachannel_tx, id, channel_id, report_progress)
+        .await
+    else {
+        return Ok(Outcome::Cancelled);
+    };
+    let part = part_path(&destination);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&part)
+        .await
+        .context("Failed to open partial file")?;
+    let mut offset = file.metadata().await?.len();
+    // Anything already at a fresh destination belongs to some other transfer
+    if resumed.is_none() || offset > offer.size {
+        file.set_len(0).await?;
+        offset = 0;
+    }
+    file.seek(SeekFrom::Start(offset)).await?;
+    if resumed.is_some() {
+        info!("Resuming transfer {} of {} at byte {}.", id, offer.name, offset);
+    }
+    store
+        .lock()
+        .unwrap()
+        .begin_upload(sha256, offer.size, &offer.name, &destination);
+
+    let (chunk_tx, mut chunk_rx) = channel(100);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    match inbound_transfers.upgrade() {
+        Some(inbound_transfers) => {
+            inbound_transfers.lock().unwrap().insert(
+                id,
+                Inbound::V2 {
+                    tx: chunk_tx,
+                    cancelled: cancelled.clone(),
+                },
+            );
+        }
+        None => return Ok(Outcome::Interrupted),
+    }
+    if queue.take_cancelled(id, inbound_transfers) {
+        drop(file);
+        tokio::fs::remove_file(&part).await.ok();
+        store.lock().unwrap().finish_upload(sha256);
+        return Ok(Outcome::Cancelled);
+    }
+    datachannel_tx
+        .send((
+            channel_id,
+            ServerMessage::AcceptFile { id, offset }.to_vec(),
+            DatachannelMessageKind::Text,
+        ))
+        .await?;
+
+    let mut meter = Meter::new(
+        datachannel_tx.clone(),
+        channel_id,
+        id,
+        report_progress,
+        offset,
+        Some(offer.size),
+    )
+    .notify(format!("Receiving {}", offer.name));
+    let start = offset;
+    while offset < offer.size {
+        let Ok(next) = tokio::time::timeout(IDLE_TIMEOUT, chunk_rx.recv()).await else {
+            // Kept for resuming, like an interrupted transfer
+            file.flush().await?;
+            bail!("received nothing for {:?}", IDLE_TIMEOUT);
+        };
+        let Some((chunk_offset, chunk)) = next else {
+            file.flush().await?;
+            drop(file);
+            if cancelled.load(Ordering::Relaxed) {
+                tokio::fs::remove_file(&part).await.ok();
+                store.lock().unwrap().finish_upload(sha256);
+                return Ok(Outcome::Cancelled);
+            }
+            info!("Transfer {} interrupted at byte {}, keeping it for later.", id, offset);
+            return Ok(Outcome::Interrupted);
+        };
+        if chunk_offset != offset {
+            bail!("expected a chunk at offset {}, got {}", offset, chunk_offset);
+        }
+        if offset + chunk.len() as u64 > offer.size {
+            bail!("received more than the announced {} bytes", offer.size);
+        }
+        file.write_all(&chunk)
+            .await
+            .context("Failed to write file")?;
+        offset += chunk.len() as u64;
+        meter.advance(chunk.len() as u64).await;
+    }
+    file.flush().await?;
+    drop(file);
+
+    let digest = hash_file(part.clone()).await?;
+    if digest != sha256 {
+        // Resuming would only reproduce the same corrupt data
+        tokio::fs::remove_file(&part).await.ok();
+        store.lock().unwrap().finish_upload(sha256);
+        bail!(
+            "SHA-256 mismatch: expected {}, the received data hashes to {}",
+            sha256,
+            digest
+        );
+    }
+    tokio::fs::rename(&part, &destination)
+        .await
+        .context("Failed to move the finished file into place")?;
+    store.lock().unwrap().finish_upload(sha256);
+    datachannel_tx
+        .send((
+            channel_id,
+            ServerMessage::TransferComplete {
+                id,
+                sha256: digest,
+            }
+            .to_vec(),
+            DatachannelMessageKind::Text,
+        ))
+        .await?;
+    Ok(Outcome::Completed(offset - start))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_file(
+    store: &Mutex<ResumeStore>,
+    roots: &Roots,
+    queue: &Queue,
+    report_progress: bool,
+    owner: &str,
+    datachannel_tx: &Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
+    tx: &Sender<Dialog>,
+    id: u32,
+    channel_id: ChannelId,
+    path: Option<String>,
+    resume: Option<ResumeFile>,
+) -> Result<Outcome> {
+    let (path, shared, sha256, mut offset) = match (resume, path) {
+        (Some(resume), _) => {
+            let sha256 = resume.sha256.to_ascii_lowercase();
+            let served = store
+                .lock()
+                .unwrap()
+                .find_download(&sha256, owner)
+                .context("no file with that digest was sent recently, it can't be resumed")?;
+            // The shared directories may have changed since, so a shared file must still be
+            // reachable the way any other request for it would be
+            let path = match &served.shared {
+                Some(shared) => {
+                    let resolved = roots.resolve(shared).await?;
+                    if resolved != served.path {
+                        bail!("`{}` is a different file now, it can't be resumed", shared);
+                    }
+                    resolved
+                }
+                None => served.path,
+            };
+            let metadata = tokio::fs::metadata(&path).await?;
+            if metadata.len() != served.size || modified_secs(&metadata) != served.modified {
+                bail!("the file changed since it was sent, it can't be resumed");
+            }
+            (path, served.shared, sha256, resume.offset)
+        }
+        (None, Some(path)) => {
+            let resolved = roots.resolve(&path).await?;
+            if !tokio::fs::metadata(&resolved).await?.is_file() {
+                bail!("`{}` is not a file", path);
+            }
+            let sha256 = hash_file(resolved.clone()).await?;
+            (resolved, Some(path), sha256, 0)
+        }
+        (None, None) => match spawn_file_dialog(tx, FileDialogKind::Open).await {
+            Some(path) => {
+                let sha256 = hash_file(path.clone()).await?;
+                (path, None, sha256, 0)
+            }
+            None => return Ok(Outcome::Declined),
+        },
+    };
+
+    let Some(_permit) = queue
+        .wait_turn(datachannel_tx, id, channel_id, report_progress)
+        .await
+    else {
+        return Ok(Outcome::Cancelled);
+    };
+    let mut file = File::open(&path).await.context("Failed to open file")?;
+    let metadata = file.metadata().await?;
+    let size = metadata.len();
+    if offset > size {
+        bail!("offset {} is past the end of the {} byte file", offset, size);
+    }
+    let served = ServedFile {
+        sha256: sha256.clone(),
+        size,
+        path: path.clone(),
+        modified: modified_secs(&metadata),
+        updated: unix_now(),
+        owner: Some(owner.to_owned()),
+        shared,
+    };
+    store.lock().unwrap().record_download(served.clone());
+
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut meter = Meter::new(
+        datachannel_tx.clone(),
+        channel_id,
+        id,
+        report_progress,
+        offset,
+        Some(size),
+    )
+    .notify(format!("Sending {}", name));
+    datachannel_tx
+        .send((
+            channel_id,
+            ServerMessage::FileInfo {
+                id,
+                name,
+                mime: mime_guess::from_path(&path)
+                    .first_or_octet_stream()
+                    .to_string(),
+                size,
+                sha256: sha256.clone(),
+                offset,
+            }
+            .to_vec(),
+            DatachannelMessageKind::Text,
+        ))
+        .await?;
+
+    file.seek(SeekFrom::Start(offset)).await?;
+    let start = offset;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf).await.context("Failed to read file")?;
+        if n == 0 {
+            break;
+        }
+        let mut v = Vec::with_capacity(12 + n);
+        v.extend_from_slice(&id.to_be_bytes());
+        v.extend_from_slice(&offset.to_be_bytes());
+        v.extend_from_slice(&buf[..n]);
+        if datachannel_tx
+            .send((channel_id, v, DatachannelMessageKind::FileChunk(n as u64)))
+            .await
+            .is_err()
+        {
+            return Ok(Outcome::Interrupted);
+        }
+        offset += n as u64;
+        meter.advance(n as u64).await;
+    }
+    // Only an interrupted download needs to be found again
+    store.lock().unwrap().finish_download(&served);
+    datachannel_tx
+        .send((
+            channel_id,
+            ServerMessage::TransferComplete { id, sha256 }.to_vec(),
+            DatachannelMessageKind::Text,
+        ))
+        .await?;
+    Ok(Outcome::Completed(offset - start))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn receive_archive(
+    roots: &Roots,
+    queue: &Queue,
+    report_progress: bool,
+    inbound_transfers: &Weak<Mutex<HashMap<u32, Inbound>>>,
+    datachannel_tx: &Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
+    tx: &Sender<Dialog>,
+    id: u32,
+    channel_id: ChannelId,
+    name: &str,
+    compression: Compression,
+) -> Result<Outcome> {
+    // The archive is unpacked into a new folder called `name`
+    let dest = if roots.has_drop_dir() {
+        roots.drop_path(name).await?
+    } else {
+        match spawn_file_dialog(tx, FileDialogKind::Folder).await {
+            Some(parent) => unique_path(&parent, name).await?,
+            None => return Ok(Outcome::Declined),
+        }
+    };
+    let Some(_permit) = queue
+        .wait_turn(datachannel_tx, id, channel_id, report_progress)
+        .await
+    else {
+        return Ok(Outcome::Cancelled);
+    };
+
+    let (chunk_tx, chunk_rx) = channel(100);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    match inbound_transfers.upgrade() {
+        Some(inbound_transfers) => {
+            inbound_transfers.lock().unwrap().insert(
+                id,
+                Inbound::V2 {
+                    tx: chunk_tx,
+                    cancelled: cancelled.clone(),
+                },
+            );
+        }
+        None => return Ok(Outcome::Interrupted),
+    }
+    if queue.take_cancelled(id, inbound_transfers) {
+        return Ok(Outcome::Cancelled);
+    }
+    datachannel_tx
+        .send((
+            channel_id,
+            ServerMessage::AcceptFile { id, offset: 0 }.to_vec(),
+            DatachannelMessageKind::Text,
+        ))
+        .await?;
+
+    info!("Unpacking transfer {} into {}.", id, dest.display());
+    let mut progress = archive::Progress::new(datachannel_tx.clone(), channel_id, id);
+    let meter = Meter::new(
+        datachannel_tx.clone(),
+        channel_id,
+        id,
+        report_progress,
+        0,
+        None,
+    )
+    .notify(format!("Receiving {}", name));
+    let reader = archive::ChunkReader::new(chunk_rx, meter);
+    let unpacked = tokio::task::spawn_blocking(move || {
+        archive::unpack(reader, compression, &dest, &mut progress)
+    })
+    .await?;
+    let (received, sha256) = match unpacked {
+        Ok(unpacked) => unpacked,
+        // Whatever was unpacked so far stays, archives can't be resumed
+        Err(_) if cancelled.load(Ordering::Relaxed) => return Ok(Outcome::Cancelled),
+        Err(_) if inbound_transfers.upgrade().is_none() => return Ok(Outcome::Interrupted),
+        Err(e) => return Err(e),
+    };
+    datachannel_tx
+        .send((
+            channel_id,
+            ServerMessage::TransferComplete { id, sha256 }.to_vec(),
+            DatachannelMessageKind::Text,
+        ))
+        .await?;
+    Ok(Outcome::Completed(received))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_archive(
+    roots: &Roots,
+    queue: &Queue,
+    report_progress: bool,
+    datachannel_tx: &Sender<(ChannelId, Vec<u8>, DatachannelMessageKind)>,
+    tx: &Sender<Dialog>,
+    id: u32,
+    channel_id: ChannelId,
+    paths: Vec<String>,
+    compression: Compression,
+) -> Result<Outcome> {
+    let sources = if paths.is_empty() {
+        match spawn_file_dialog(tx, FileDialogKind::Folder).await {
+            Some(folder) => vec![folder],
+            None => return Ok(Outcome::Declined),
+        }
+    } else {
+        let mut sources = Vec::with_capacity(paths.len());
+        for path in &paths {
+            sources.push(roots.resolve(path).await?);
+        }
+        sources
+    };
+    let name = match sources.as_slice() {
+        [source] => source
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        _ => "files".to_string(),
+    };
+    let Some(_permit) = queue
+        .wait_turn(datachannel_tx, id, channel_id, report_progress)
+        .await
+    else {
+        return Ok(Outcome::Cancelled);
+    };
+    let meter = Meter::new(
+        datachannel_tx.clone(),
+        channel_id,
+        id,
+        report_progress,
+        0,
+        None,
+    )
+    .notify(format!("Sending {}", name));
+
+    datachannel_tx
+        .send((
+            channel_id,
+            ServerMessage::ArchiveInfo {
+                id,
+                name,
+                compression,
+            }
+            .to_vec(),
+            DatachannelMessageKind::Text,
+        ))
+        .await?;
+
+    // Aborting this task drops the guard, which stops the blocking thread at its next chunk
+    let alive = Arc::new(AtomicBool::new(true));
+    let _guard = ClearOnDrop(alive.clone());
+    let mut progress = archive::Progress::new(datachannel_tx.clone(), channel_id, id);
+    let writer = archive::ChunkWriter::new(datachannel_tx.clone(), channel_id, id, alive, meter);
+    let (sent, sha256) = tokio::task::spawn_blocking(move || {
+        archive::pack(&sources, compression, writer, &mut progress)
+    })
+    .await??;
+    datachannel_tx
+        .send((
+            channel_id,
+            ServerMessage::TransferComplete { id, sha256 }.to_vec(),
+            DatachannelMessageKind::Text,
+        ))
+        .await?;
+    Ok(Outcome::Completed(sent))
+}
+
+struct ClearOnDrop(Arc<AtomicBool>);
+
+impl Drop for ClearOnDrop {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+fn is_sha256(digest: &str) -> bool {
+    digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The lowercase hex SHA-256 digest of a file.
+async fn hash_file(path: PathBuf) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path).context("Failed to open file for hashing")?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+fn modified_secs(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Where an upload is written until its digest has been verified.
+pub(super) fn part_path(destination: &Path) -> PathBuf {
+    let mut name = destination.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    destination.with_file_name(name)
+}
+
+/// A v2 upload that has not finished yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartialUpload {
+    sha256: String,
+    size: u64,
+    name: String,
+    destination: PathBuf,
+    // Unix timestamp, in seconds
+    updated: u64,
+}
+
+/// A file sent with v2, remembered so an interrupted download can be resumed by its digest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ServedFile {
+    sha256: String,
+    size: u64,
+    path: PathBuf,
+    // Modification time when it was hashed, to notice the file changing underneath us
+    modified: u64,
+    updated: u64,
+    // The session or key it was sent to. Records without one are never resumed.
+    #[serde(default)]
+    owner: Option<String>,
+    // The virtual path of a file from a shared directory, which anyone may download
+    #[serde(default)]
+    shared: Option<String>,
+}
+
+impl ServedFile {
+    /// Whether `owner` may have this file sent again without asking the host.
+    fn resumable_by(&self, owner: &str) -> bool {
+        self.shared.is_some() || self.owner.as_deref() == Some(owner)
+    }
+
+    fn same_grant(&self, other: &ServedFile) -> bool {
+        self.sha256 == other.sha256 && self.owner == other.owner && self.shared == other.shared
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ResumeData {
+    #[serde(default)]
+    uploads: Vec<PartialUpload>,
+    #[serde(default)]
+    downloads: Vec<ServedFile>,
+}
+
+/// Interrupted v2 transfers, persisted to a JSON file so they survive the session and restarts.
+#[derive(Debug)]
+pub struct ResumeStore {
+    path: PathBuf,
+    data: ResumeData,
+    // Digests of uploads some session is currently receiving
+    active: HashSet<String>,
+}
+
+impl ResumeStore {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let data = if path.exists() {
+            serde_json::from_str(
+                &std::fs::read_to_string(&path).context("Failed to read transfer state file")?,
+            )
+            .context("Failed to parse transfer state file")?
+        } else {
+            ResumeData::default()
+        };
+        let mut store = Self {
+            path,
+            data,
+            active: HashSet::new(),
+        };
+        store.remove_stale();
+        Ok(store)
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_vec_pretty(&self.data)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| {
+                let tmp = self.path.with_extension("json.tmp");
+                std::fs::write(&tmp, data)?;
+                std::fs::rename(&tmp, &self.path)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            error!("Failed to save transfer state to {}: {:?}", self.path.display(), e);
+        }
+    }
+
+    /// Forget transfers nobody came back for, deleting their partial files.
+    fn remove_stale(&mut self) {
+        let cutoff = unix_now().saturating_sub(RESUME_FOR.as_secs());
+        let before = self.data.uploads.len() + self.data.downloads.len();
+        self.data.uploads.retain(|upload| {
+            let keep = upload.updated >= cutoff;
+            if !keep {
+                std::fs::remove_file(part_path(&upload.destination)).ok();
+            }
+            keep
+        });
+        self.data.downloads.retain(|download| download.updated >= cutoff);
+        if self.data.uploads.len() + self.data.downloads.len() != before {
+            self.save();
+        }
+    }
+
+    fn claim(&mut self, sha256: &str) -> bool {
+        self.active.insert(sha256.to_owned())
+    }
+
+    fn release(&mut self, sha256: &str) {
+        self.active.remove(sha256);
+    }
+
+    fn find_upload(&mut self, sha256: &str, size: u64) -> Option<PathBuf> {
+        self.remove_stale();
+        self.data
+            .uploads
+            .iter()
+            .find(|upload| upload.sha256 == sha256 && upload.size == size)
+            .map(|upload| upload.destination.clone())
+    }
+
+    fn begin_upload(&mut self, sha256: &str, size: u64, name: &str, destination: &Path) {
+        self.data.uploads.retain(|upload| upload.sha256 != sha256);
+        self.data.uploads.push(PartialUpload {
+            sha256: sha256.to_owned(),
+            size,
+            name: name.to_owned(),
+            destination: destination.to_owned(),
+            updated: unix_now(),
+        });
+        self.save();
+    }
+
+    fn finish_upload(&mut self, sha256: &str) {
+        self.data.uploads.retain(|upload| upload.sha256 != sha256);
+        self.save();
+    }
+
+    fn find_download(&mut self, sha256: &str, owner: &str) -> Option<ServedFile> {
+        self.remove_stale();
+        self.data
+            .downloads
+            .iter()
+            .find(|download| download.sha256 == sha256 && download.resumable_by(owner))
+            .cloned()
+    }
+
+    fn record_download(&mut self, served: ServedFile) {
+        self.data
+            .downloads
+            .retain(|download| !download.same_grant(&served));
+        self.data.downloads.push(served);
+        self.save();
+    }
+
+    fn finish_download(&mut self, served: &ServedFile) {
+        self.data
+            .downloads
+            .retain(|download| !download.same_grant(served));
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn served(owner: &str, shared: Option<&str>) -> ServedFile {
+        ServedFile {
+            sha256: "ab".repeat(32),
+            size: 3,
+            path: PathBuf::from("/srv/a.txt"),
+            modified: 0,
+            updated: unix_now(),
+            owner: Some(owner.to_string()),
+            shared: shared.map(str::to_string),
+        }
+    }
+
+    fn store() -> (tempfile::TempDir, ResumeStore) {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = ResumeStore::load(tmp.path().join("transfers.json")).unwrap();
+        (tmp, store)
+    }
+
+    #[test]
+    fn picked_files_resume_only_for_their_owner() {
+        let (_tmp, mut store) = store();
+        let sha256 = "ab".repeat(32);
+        store.record_download(served("key a", None));
+        assert!(store.find_download(&sha256, "key a").is_some());
+        assert!(store.find_download(&sha256, "key b").is_none());
+        assert!(store.find_download(&sha256, "session x").is_none());
+    }
+
+    #[test]
+    fn shared_files_resume_for_anyone() {
+        let (_tmp, mut store) = store();
+        let sha256 = "ab".repeat(32);
+        store.record_download(served("key a", Some("docs/a.txt")));
+        let found = store.find_download(&sha256, "key b").unwrap();
+        assert_eq!(found.shared.as_deref(), Some("docs/a.txt"));
+    }
+
+    #[test]
+    fn finished_downloads_are_forgotten() {
+        let (_tmp, mut store) = store();
+        let sha256 = "ab".repeat(32);
+        let a = served("key a", None);
+        store.record_download(a.clone());
+        store.record_download(served("key b", None));
+        store.finish_download(&a);
+        assert!(store.find_download(&sha256, "key a").is_none());
+        assert!(store.find_download(&sha256, "key b").is_some());
+    }
+
+    #[test]
+    fn records_without_an_owner_are_not_resumed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("transfers.json");
+        // What earlier versions saved
+        let sha256 = "ab".repeat(32);
+        std::fs::write(
+            &path,
+            serde_json::json!({
+                "downloads": [{
+                    "sha256": sha256,
+                    "size": 3,
+                    "path": "/srv/a.txt",
+                    "modified": 0,
+                    "updated": unix_now(),
+                }],
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let mut store = ResumeStore::load(path).unwrap();
+        assert!(store.find_download(&sha256, "key a").is_none());
+    }
+}
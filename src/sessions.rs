@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use tokio::sync::Notify;
+
+use crate::keys::Permissions;
+use crate::metrics::METRICS;
+use crate::rtc::codec::VideoCodec;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Streaming health, refreshed whenever str0m reports egress stats (about once a second).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionStats {
+    // Encoded video frames sent per second
+    pub fps: f64,
+    // Media bits sent per second, across all tracks
+    pub bitrate_sent: u64,
+    pub rtt_ms: Option<f64>,
+    // Fraction of video packets lost, as reported by the client
+    pub packet_loss: Option<f64>,
+    pub nacks: u64,
+    pub plis: u64,
+    pub keyframe_requests: u64,
+    pub input_events_per_second: f64,
+    pub file_bytes_received: u64,
+    pub file_bytes_sent: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub peer: SocketAddr,
+    pub permissions: Permissions,
+    // Unix timestamp, in seconds
+    pub started: u64,
+    pub codec: String,
+    // The latest bandwidth estimate, in Kbit/s
+    pub bitrate: Option<u32>,
+    // Unknown until the first packet is sent
+    pub transport: Option<Transport>,
+    pub stats: SessionStats,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: SessionInfo,
+    kick: Arc<Notify>,
+}
+
+/// Every session that is currently running, so that they can be listed and terminated.
+#[derive(Debug, Default)]
+pub struct Sessions {
+    map: Mutex<HashMap<String, Entry>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a session. It is listed for as long as the returned guard is alive.
+    pub fn register(
+        self: &Arc<Self>,
+        id: String,
+        peer: SocketAddr,
+        permissions: Permissions,
+        codec: VideoCodec,
+    ) -> SessionGuard {
+        METRICS.sessions_started.fetch_add(1, Ordering::Relaxed);
+        let kick = Arc::new(Notify::new());
+        let info = SessionInfo {
+            id: id.clone(),
+            peer,
+            permissions,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            codec: codec.to_string(),
+            bitrate: None,
+            transport: None,
+            stats: SessionStats::default(),
+        };
+        self.map.lock().unwrap().insert(
+            id.clone(),
+            Entry {
+                info,
+                kick: kick.clone(),
+            },
+        );
+        SessionGuard {
+            id,
+            sessions: self.clone(),
+            kick,
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self
+            .map
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect();
+        sessions.sort_by_key(|info| info.started);
+        sessions
+    }
+
+    pub fn get(&self, id: &str) -> Option<SessionInfo> {
+        self.map
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|entry| entry.info.clone())
+    }
+
+    /// Ask a session to shut down. Returns false if there is no such session.
+    pub fn kick(&self, id: &str) -> bool {
+        match self.map.lock().unwrap().get(id) {
+            Some(entry) => {
+                // notify_one stores a permit, so this works even if the session isn't waiting yet
+                entry.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut SessionInfo)) {
+        if let Some(entry) = self.map.lock().unwrap().get_mut(id) {
+            f(&mut entry.info);
+        }
+    }
+}
+
+/// A running session's entry in [`Sessions`], removed when dropped.
+#[derive(Debug)]
+pub struct SessionGuard {
+    id: String,
+    sessions: Arc<Sessions>,
+    kick: Arc<Notify>,
+}
+
+impl SessionGuard {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn set_bitrate(&self, bitrate: u32) {
+        self.sessions
+            .update(&self.id, |info| info.bitrate = Some(bitrate));
+    }
+
+    pub fn set_transport(&self, transport: Transport) {
+        self.sessions
+            .update(&self.id, |info| info.transport = Some(transport));
+    }
+
+    pub fn update_stats(&self, f: impl FnOnce(&mut SessionStats)) {
+        self.sessions.update(&self.id, |info| f(&mut info.stats));
+    }
+
+    /// Resolves when an administrator terminates this session.
+    pub async fn kicked(&self) {
+        self.kick.notified().await
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.map.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt::Display;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use argon2::password_hash::PasswordHash;
+
+use log::*;
+
+use crate::Config;
+
+/// Something wrong with the configuration, found before it is used.
+#[derive(Debug)]
+pub enum Diagnostic {
+    // The server would fail or misbehave with this
+    Error(String),
+    // Allowed, but probably not what was meant
+    Warning(String),
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        matches!(self, Diagnostic::Error(_))
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Diagnostic::Error(message) => write!(f, "error: {}", message),
+            Diagnostic::Warning(message) => write!(f, "warning: {}", message),
+        }
+    }
+}
+
+/// Read and parse the config file at `path`.
+pub fn load(path: &Path) -> Result<Config> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))
+}
+
+/// Log the warnings about `config`, and fail if it has errors.
+pub fn ensure_valid(config: &Config) -> Result<()> {
+    let diagnostics = check(config);
+    for warning in diagnostics.iter().filter(|d| !d.is_error()) {
+        warn!("{}", warning);
+    }
+    let errors: Vec<String> = diagnostics
+        .iter()
+        .filter(|d| d.is_error())
+        .map(|d| d.to_string())
+        .collect();
+    if !errors.is_empty() {
+        bail!("The configuration is invalid:\n{}", errors.join("\n"));
+    }
+    Ok(())
+}
+
+/// Everything that looks wrong with `config`, errors first.
+pub fn check(config: &Config) -> Vec<Diagnostic> {
+    let mut errors = vec![];
+    let mut warnings = vec![];
+
+    // 96 Kbit/s of the target are set aside for audio
+    if config.target_bitrate <= 96 {
+        errors.push(format!(
+            "`target_bitrate` is {} Kbit/s, it has to be above 96",
+            config.target_bitrate
+        ));
+    }
+    if let Some(endx) = config.endx.filter(|&endx| endx < config.startx) {
+        errors.push(format!(
+            "`endx` ({}) is left of `startx` ({})",
+            endx, config.startx
+        ));
+    }
+    if let Some(endy) = config.endy.filter(|&endy| endy < config.starty) {
+        errors.push(format!(
+            "`endy` ({}) is above `starty` ({})",
+            endy, config.starty
+        ));
+    }
+    if config.full_chroma && config.vaapi {
+        errors.push("`full_chroma` cannot be combined with `hwencode`".to_string());
+    }
+    if config.video_codecs.is_empty() {
+        errors.push("`video_codecs` is empty, no client could be served".to_string());
+    }
+    if config.transfer_parallelism == 0 {
+        errors.push("`transfer_parallelism` is 0, no file transfer would ever start".to_string());
+    }
+
+    match &config.password_hash {
+        Some(hash) => {
+            if let Err(e) = PasswordHash::new(hash) {
+                errors.push(format!("`password_hash` is not a valid PHC string: {}", e));
+            }
+            if !config.password.is_empty() {
+                warnings.push("`password` is ignored because `password_hash` is set".to_string());
+            }
+        }
+        None if config.password.is_empty() => {
+            errors.push("either `password` or `password_hash` must be set".to_string());
+        }
+        None => {}
+    }
+
+    match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => {
+            for (name, path) in [("cert", cert), ("key", key)] {
+                if !path.is_file() {
+                    errors.push(format!("`{}` file {} does not exist", name, path.display()));
+                }
+            }
+        }
+        (None, None) => {}
+        _ => errors.push(
+            "`cert` and `key` have to be set together, or left out for a generated certificate"
+                .to_string(),
+        ),
+    }
+    if let Some(dir) = config.drop_dir.as_ref().filter(|dir| !dir.is_dir()) {
+        warnings.push(format!(
+            "`drop_dir` {} is not a directory, uploads will fail",
+            dir.display()
+        ));
+    }
+    for (name, dir) in config.shared_dirs.iter().filter(|(_, dir)| !dir.is_dir()) {
+        warnings.push(format!(
+            "shared directory `{}` ({}) is not a directory",
+            name,
+            dir.display()
+        ));
+    }
+
+    if let Some(adaptation) = &config.adaptation {
+        if adaptation.reduced_fps <= 0 {
+            errors.push("`adaptation.reduced_fps` has to be above 0".to_string());
+        }
+        if !(adaptation.downscale > 0.0 && adaptation.downscale <= 1.0) {
+            warnings.push(format!(
+                "`adaptation.downscale` is {}, it is clamped between 0.1 and 1",
+                adaptation.downscale
+            ));
+        }
+        if adaptation.downscale_below > adaptation.reduce_fps_below {
+            warnings.push(
+                "`adaptation.downscale_below` is above `adaptation.reduce_fps_below`, so the \
+                 framerate drops along with the size"
+                    .to_string(),
+            );
+        }
+    }
+    if config.ask_approval && config.approval_timeout == 0 {
+        warnings.push(format!(
+            "`approval_timeout` is 0, every connection gets `approval_default` ({:?})",
+            config.approval_default
+        ));
+    }
+    if config.ask_approval && config.virtual_display.is_some() {
+        warnings.push(format!(
+            "`ask_approval` can't prompt anyone with `virtual_display`, only remote viewers see \
+             it, so every connection gets `approval_default` ({:?})",
+            config.approval_default
+        ));
+    }
+
+    if !cfg!(target_os = "linux") {
+        for (name, set) in [
+            ("virtual_display", config.virtual_display.is_some()),
+            ("dynamic_resolution", config.dynamic_resolution),
+            ("damage_capture", config.damage_capture),
+            ("vapostproc", config.vapostproc),
+        ] {
+            if set {
+                warnings.push(format!("`{}` is only supported on Linux", name));
+            }
+        }
+    }
+
+    errors
+        .into_iter()
+        .map(Diagnostic::Error)
+        .chain(warnings.into_iter().map(Diagnostic::Warning))
+        .collect()
+}
+
+/// `new`, except for the settings that only take effect on a restart, which keep their
+/// `current` values. These shape the capture pipelines that running sessions share, or are
+/// only read once at startup.
+#[cfg(target_family = "unix")]
+pub fn reloaded(current: &Config, mut new: Config) -> Config {
+    macro_rules! keep {
+        ($($field:ident),* $(,)?) => {
+            $(
+                if new.$field != current.$field {
+                    warn!("`{}` only changes after a restart.", stringify!($field));
+                    new.$field.clone_from(&current.$field);
+                }
+            )*
+        };
+    }
+    keep!(
+        startx,
+        starty,
+        endx,
+        endy,
+        windows_monitor_index,
+        windows_capture_api,
+        windows_quality_vs_speed,
+        vaapi,
+        vapostproc,
+        full_chroma,
+        vbv_buf_capacity,
+        tcp_upnp,
+        clipboard_max_size,
+        virtual_display,
+        damage_capture,
+        adaptation,
+    );
+    new
+}
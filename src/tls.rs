@@ -0,0 +1,117 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use log::*;
+
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+
+use sha2::{Digest, Sha256};
+
+use crate::Config;
+
+/// Where the certificate and its private key are. Unless the config names them, they are kept
+/// in the config directory and generated when missing.
+#[derive(Debug, Clone)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // Not named by the config, so ours to generate
+    pub managed: bool,
+}
+
+impl CertPaths {
+    pub fn new(config: &Config, dir: &Path) -> Self {
+        match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => Self {
+                cert: cert.clone(),
+                key: key.clone(),
+                managed: false,
+            },
+            // The config is checked to have both or neither
+            _ => Self {
+                cert: dir.join("cert.pem"),
+                key: dir.join("key.pem"),
+                managed: true,
+            },
+        }
+    }
+
+    /// Generate a self-signed certificate if ours is missing.
+    pub fn ensure(&self) -> Result<()> {
+        if !self.managed || (self.cert.is_file() && self.key.is_file()) {
+            return Ok(());
+        }
+        generate(default_names(), &self.cert, &self.key)?;
+        println!("Generated a certificate at {}.", self.cert.display());
+        Ok(())
+    }
+}
+
+/// Generate a self-signed certificate for `names`, and write it to `cert` and its private key
+/// to `key`.
+pub fn generate(names: Vec<String>, cert: &Path, key: &Path) -> Result<()> {
+    let rcgen::CertifiedKey {
+        cert: certificate,
+        key_pair,
+    } = rcgen::generate_simple_self_signed(names).context("Failed to generate a certificate")?;
+
+    for path in [cert, key] {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Nobody else has any business reading the private key
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(key)
+        .and_then(|mut file| file.write_all(key_pair.serialize_pem().as_bytes()))
+        .with_context(|| format!("Failed to write private key file {}", key.display()))?;
+    std::fs::write(cert, certificate.pem())
+        .with_context(|| format!("Failed to write certificate file {}", cert.display()))?;
+    Ok(())
+}
+
+/// `localhost` and the addresses of this machine, which clients are likely to connect to.
+pub fn default_names() -> Vec<String> {
+    let mut names = vec!["localhost".to_string()];
+    match NetworkInterface::show() {
+        Ok(interfaces) => names.extend(
+            interfaces
+                .into_iter()
+                .flat_map(|iface| iface.addr)
+                .map(|addr| addr.ip())
+                .filter(|ip| !crate::is_bad_ip(ip))
+                .map(|ip| ip.to_string()),
+        ),
+        Err(e) => warn!("Failed to list network interfaces: {:?}", e),
+    }
+    names.dedup();
+    names
+}
+
+/// The SHA-256 fingerprint of the certificate at `path`, as colon-separated hex, which clients
+/// can pin the certificate with.
+pub fn fingerprint(path: &Path) -> Result<String> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Failed to read certificate file {}", path.display()))?;
+    let der = rustls_pemfile::certs(&mut pem.as_slice())
+        .next()
+        .with_context(|| format!("No certificate in {}", path.display()))??;
+    Ok(fingerprint_der(&der))
+}
+
+/// The fingerprint of a DER-encoded certificate, formatted like [`fingerprint`].
+pub fn fingerprint_der(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
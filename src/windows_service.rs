@@ -168,7 +168,12 @@ pub fn run_service() -> Result<()> {
         // Relying on the output of current_exe is NOT a security risk, because an attacker
         // cannot swap this executable out for a new executable while the service is running.
         // Windows prevents users from deleting the executable of a running service.
-        let command = format!("{} --console", std::env::current_exe()?.display());
+        // Options from the service's command line, like --config, carry over
+        let args: String = std::env::args()
+            .skip(1)
+            .map(|arg| format!(" \"{}\"", arg))
+            .collect();
+        let command = format!("{} --console{}", std::env::current_exe()?.display(), args);
         let mut command_wide: Vec<u16> = OsStr::new(&command)
             .encode_wide()
             .chain(std::iter::once(0))